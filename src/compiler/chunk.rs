@@ -1,6 +1,6 @@
 use super::value::Value;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OpCode {
     Return,
     Negate,
@@ -8,7 +8,9 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
-    Constant(Value),
+    Modulo,
+    /// Index into the chunk's constant pool.
+    Constant(usize),
     True,
     False,
     Nil,
@@ -18,6 +20,9 @@ pub enum OpCode {
     Less,
     Print,
     Pop,
+    PopN(usize),
+    Dup,
+    Swap,
     DefineGlobal(String),
     GetGlobal(String),
     SetGlobal(String),
@@ -26,12 +31,30 @@ pub enum OpCode {
     JumpIfFalse(u16),
     Jump(u16),
     Loop(u16),
+    Call(u8),
+    /// Wraps the function constant at the index in a closure over the
+    /// listed variables.
+    Closure(usize, Vec<UpvalueRef>),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    /// Hands the local on top of the stack over to the closures that
+    /// captured it, then pops it.
+    CloseUpvalue,
 }
 
-#[derive(Debug, Clone)]
+/// Where a new closure finds a variable it captures: a local slot of the
+/// function creating it, or one of that function's own upvalues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueRef {
+    pub index: usize,
+    pub is_local: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Chunk {
     pub code: Vec<OpCode>,
     pub lines: Vec<usize>,
+    pub constants: Vec<Value>,
 }
 
 impl Chunk {
@@ -39,6 +62,7 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             lines: Vec::new(),
+            constants: Vec::new(),
         }
     }
 
@@ -47,9 +71,196 @@ impl Chunk {
         self.lines.push(line);
     }
 
-    pub fn add_constant(&mut self, value: Value, line: usize) -> u8 {
-        self.write_chunk(OpCode::Constant(value), line);
+    /// Pool index of `value`, reusing an existing entry for an equal value.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let existing = self.constants.iter().position(|constant| match (constant, &value) {
+            // -0 and 0 are equal but print differently, so keep both.
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (a, b) => a.lox_eq(b),
+        });
+        existing.unwrap_or_else(|| {
+            self.constants.push(value);
+            self.constants.len() - 1
+        })
+    }
+
+    /// Drops constants from the end of the pool that no instruction refers
+    /// to any more, such as the operands of a folded expression.
+    pub fn trim_constants(&mut self) {
+        while let Some(last) = self.constants.len().checked_sub(1) {
+            let used = self.code.iter().any(|op| match op {
+                OpCode::Constant(index) | OpCode::Closure(index, _) => *index == last,
+                _ => false,
+            });
+            if used {
+                return;
+            }
+            self.constants.pop();
+        }
+    }
+
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut out = format!("== {} ==\n", name);
+        for offset in 0..self.code.len() {
+            out.push_str(&self.disassemble_instruction(offset));
+            out.push('\n');
+        }
+        out
+    }
 
-        (self.code.len() - 1) as u8
+    /// Renders one instruction as `offset line mnemonic operand`. The match is
+    /// exhaustive on purpose so a new opcode can't be added without a mnemonic.
+    pub fn disassemble_instruction(&self, offset: usize) -> String {
+        let line = if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
+            "   |".to_string()
+        } else {
+            format!("{:4}", self.lines[offset])
+        };
+        // Jumps are relative to the instruction after the jump.
+        let next = offset + 1;
+        let instruction = match &self.code[offset] {
+            OpCode::Return => "OP_RETURN".to_string(),
+            OpCode::Negate => "OP_NEGATE".to_string(),
+            OpCode::Add => "OP_ADD".to_string(),
+            OpCode::Subtract => "OP_SUBTRACT".to_string(),
+            OpCode::Multiply => "OP_MULTIPLY".to_string(),
+            OpCode::Divide => "OP_DIVIDE".to_string(),
+            OpCode::Modulo => "OP_MODULO".to_string(),
+            OpCode::Constant(index) => format!(
+                "{:<16} {:4} '{}'",
+                "OP_CONSTANT", index, self.constants[*index]
+            ),
+            OpCode::True => "OP_TRUE".to_string(),
+            OpCode::False => "OP_FALSE".to_string(),
+            OpCode::Nil => "OP_NIL".to_string(),
+            OpCode::Not => "OP_NOT".to_string(),
+            OpCode::Equal => "OP_EQUAL".to_string(),
+            OpCode::Greater => "OP_GREATER".to_string(),
+            OpCode::Less => "OP_LESS".to_string(),
+            OpCode::Print => "OP_PRINT".to_string(),
+            OpCode::Pop => "OP_POP".to_string(),
+            OpCode::PopN(count) => format!("{:<16} {:4}", "OP_POPN", count),
+            OpCode::Dup => "OP_DUP".to_string(),
+            OpCode::Swap => "OP_SWAP".to_string(),
+            OpCode::DefineGlobal(name) => format!("{:<16} '{}'", "OP_DEFINE_GLOBAL", name),
+            OpCode::GetGlobal(name) => format!("{:<16} '{}'", "OP_GET_GLOBAL", name),
+            OpCode::SetGlobal(name) => format!("{:<16} '{}'", "OP_SET_GLOBAL", name),
+            OpCode::GetLocal(slot) => format!("{:<16} {:4}", "OP_GET_LOCAL", slot),
+            OpCode::SetLocal(slot) => format!("{:<16} {:4}", "OP_SET_LOCAL", slot),
+            OpCode::JumpIfFalse(jump) => format!(
+                "{:<16} {:04} -> {:04}",
+                "OP_JUMP_IF_FALSE",
+                offset,
+                next + *jump as usize
+            ),
+            OpCode::Jump(jump) => {
+                format!("{:<16} {:04} -> {:04}", "OP_JUMP", offset, next + *jump as usize)
+            }
+            OpCode::Loop(jump) => format!(
+                "{:<16} {:04} -> {:04}",
+                "OP_LOOP",
+                offset,
+                next.saturating_sub(*jump as usize)
+            ),
+            OpCode::Call(arg_count) => format!("{:<16} {:4}", "OP_CALL", arg_count),
+            OpCode::Closure(index, upvalues) => {
+                let mut out = format!(
+                    "{:<16} {:4} '{}'",
+                    "OP_CLOSURE", index, self.constants[*index]
+                );
+                for upvalue in upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    out.push_str(&format!(" {} {}", kind, upvalue.index));
+                }
+                out
+            }
+            OpCode::GetUpvalue(index) => format!("{:<16} {:4}", "OP_GET_UPVALUE", index),
+            OpCode::SetUpvalue(index) => format!("{:<16} {:4}", "OP_SET_UPVALUE", index),
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE".to_string(),
+        };
+        format!("{:04} {} {}", offset, line, instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::{Chunk, OpCode, UpvalueRef};
+    use crate::compiler::value::Value;
+
+    #[test]
+    fn disassembles_every_opcode() {
+        let code = vec![
+            OpCode::Constant(0),
+            OpCode::Constant(1),
+            OpCode::Negate,
+            OpCode::Add,
+            OpCode::Subtract,
+            OpCode::Multiply,
+            OpCode::Divide,
+            OpCode::True,
+            OpCode::False,
+            OpCode::Nil,
+            OpCode::Not,
+            OpCode::Equal,
+            OpCode::Greater,
+            OpCode::Less,
+            OpCode::Print,
+            OpCode::Pop,
+            OpCode::Dup,
+            OpCode::Swap,
+            OpCode::DefineGlobal("a".to_string()),
+            OpCode::GetGlobal("a".to_string()),
+            OpCode::SetGlobal("a".to_string()),
+            OpCode::GetLocal(1),
+            OpCode::SetLocal(2),
+            OpCode::JumpIfFalse(3),
+            OpCode::Jump(2),
+            OpCode::Loop(5),
+            OpCode::Call(2),
+            OpCode::PopN(3),
+            OpCode::Modulo,
+            OpCode::Return,
+            OpCode::Closure(
+                1,
+                vec![
+                    UpvalueRef { index: 1, is_local: true },
+                    UpvalueRef { index: 0, is_local: false },
+                ],
+            ),
+            OpCode::GetUpvalue(1),
+            OpCode::SetUpvalue(0),
+            OpCode::CloseUpvalue,
+        ];
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Number(OrderedFloat(1.5)));
+        chunk.add_constant(Value::String("hi".to_string()));
+        for (i, op) in code.into_iter().enumerate() {
+            chunk.write_chunk(op, 1 + i / 2);
+        }
+
+        let listing = chunk.disassemble("test");
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "== test ==");
+        assert_eq!(lines.len(), chunk.code.len() + 1);
+        assert_eq!(lines[1], "0000    1 OP_CONSTANT         0 '1.5'");
+        assert_eq!(lines[2], "0001    | OP_CONSTANT         1 'hi'");
+        assert_eq!(lines[19], "0018   10 OP_DEFINE_GLOBAL 'a'");
+        assert_eq!(lines[22], "0021    | OP_GET_LOCAL        1");
+        assert_eq!(lines[24], "0023    | OP_JUMP_IF_FALSE 0023 -> 0027");
+        assert_eq!(lines[25], "0024   13 OP_JUMP          0024 -> 0027");
+        assert_eq!(lines[26], "0025    | OP_LOOP          0025 -> 0021");
+        assert_eq!(lines[27], "0026   14 OP_CALL             2");
+        assert_eq!(lines[28], "0027    | OP_POPN             3");
+        assert_eq!(lines[29], "0028   15 OP_MODULO");
+        assert_eq!(lines[30], "0029    | OP_RETURN");
+        assert_eq!(lines[31], "0030   16 OP_CLOSURE          1 'hi' local 1 upvalue 0");
+        assert_eq!(lines[32], "0031    | OP_GET_UPVALUE      1");
+        assert_eq!(lines[33], "0032   17 OP_SET_UPVALUE      0");
+        assert_eq!(lines[34], "0033    | OP_CLOSE_UPVALUE");
+        for line in &lines[1..] {
+            assert!(line.contains(" OP_"), "no mnemonic in {:?}", line);
+        }
     }
 }
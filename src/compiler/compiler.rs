@@ -1,12 +1,13 @@
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use num_traits::FromPrimitive;
 use ordered_float::OrderedFloat;    
 
 use super::{
-    chunk::{Chunk, OpCode},
+    chunk::{Chunk, OpCode, UpvalueRef},
     scanner::{Scanner, Token, TokenType},
-    value::Value,
+    value::{Function, Value},
 };
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, FromPrimitive)]
@@ -44,14 +45,23 @@ pub struct ParseRule {
 pub struct Local {
     pub var: Token,
     pub depth: i32,
+    /// Whether a closure refers to it, so leaving its scope must close it.
+    pub is_captured: bool,
 }
 
 #[derive(Clone)]
 pub struct Locals {
     pub list: Vec<Local>,
     pub scope_depth: i32,
+    /// The variables of enclosing functions this function captures.
+    pub upvalues: Vec<UpvalueRef>,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum FunctionType {
+    Function,
+    Script,
+}
 
 
 pub struct Compiler {
@@ -60,10 +70,18 @@ pub struct Compiler {
     previous: Token,
     had_error: bool,
     panic_mode: bool,
-    debug_trace_execution: bool,
+    /// Every compile error reported, in order.
+    pub errors: Vec<String>,
     scanner: Scanner,
     rules: HashMap<TokenType, ParseRule>,
     locals: Locals,
+    /// Locals of the functions around the one being compiled, outermost
+    /// (the script) first.
+    enclosing: Vec<Locals>,
+    function_type: FunctionType,
+    /// Code index the latest forward jump lands on; folding must not merge
+    /// instructions across it.
+    last_jump_target: usize,
 }
 
 impl Compiler {
@@ -76,13 +94,17 @@ impl Compiler {
             previous: Token::new(),
             had_error: false,
             panic_mode: false,
-            debug_trace_execution: false,
+            errors: Vec::new(),
             scanner,
             rules: HashMap::new(),
             locals: Locals {
                 list: Vec::new(),
                 scope_depth: 0,
-            }
+                upvalues: Vec::new(),
+            },
+            enclosing: Vec::new(),
+            function_type: FunctionType::Script,
+            last_jump_target: 0,
         };
         compi.init_rules();
         compi
@@ -105,7 +127,9 @@ impl Compiler {
     }
 
     fn declaration(&mut self) {
-        if self.match_next(TokenType::Var) {
+        if self.match_next(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_next(TokenType::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -147,11 +171,27 @@ impl Compiler {
     fn end_scope(&mut self) {
         self.locals.scope_depth -= 1;
 
+        let mut count = 0;
         while !self.locals.list.is_empty()
             && self.locals.list.last().unwrap().depth > self.locals.scope_depth
         {
-            self.emit_byte(OpCode::Pop);
-            self.locals.list.pop();
+            let local = self.locals.list.pop().unwrap();
+            if local.is_captured {
+                self.emit_pops(count);
+                count = 0;
+                self.emit_byte(OpCode::CloseUpvalue);
+            } else {
+                count += 1;
+            }
+        }
+        self.emit_pops(count);
+    }
+
+    fn emit_pops(&mut self, count: usize) {
+        match count {
+            0 => {}
+            1 => self.emit_byte(OpCode::Pop),
+            _ => self.emit_byte(OpCode::PopN(count)),
         }
     }
 
@@ -170,6 +210,8 @@ impl Compiler {
             self.for_statement();
         } else if self.match_next(TokenType::If) {
             self.if_statement();
+        } else if self.match_next(TokenType::Return) {
+            self.return_statement();
         } else if self.match_next(TokenType::While) {
             self.while_statement();
         } else if self.match_next(TokenType::LeftBrace) {
@@ -235,6 +277,20 @@ impl Compiler {
         self.end_scope();
     }
 
+    fn return_statement(&mut self) {
+        if self.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_next(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return);
+        }
+    }
+
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
         self.expression();
@@ -274,7 +330,8 @@ impl Compiler {
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        let offset = self.compiling_chunk.code.len() - loop_start;
+        // The VM has already stepped past the Loop itself when it jumps back.
+        let offset = self.compiling_chunk.code.len() + 1 - loop_start;
         if offset > u16::MAX as usize {
             self.error("Loop body too large.");
         }
@@ -301,6 +358,7 @@ impl Compiler {
             }
             _ => unreachable!(),
         }
+        self.last_jump_target = self.compiling_chunk.code.len();
     }
 
     fn expression_statement(&mut self) {
@@ -309,6 +367,65 @@ impl Compiler {
         self.emit_byte(OpCode::Pop);
     }
 
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        let name = self.previous.lexeme.clone();
+        // The function may refer to itself, so its name is usable right away.
+        self.mark_initialized();
+        self.function(name);
+        self.define_variable(global);
+    }
+
+    /// Compiles a function's parameters and body into a chunk of its own and
+    /// emits a closure over the resulting function.
+    fn function(&mut self, name: String) {
+        // Slot 0 of every call frame holds the function being called.
+        let frame_locals = Locals {
+            list: vec![Local {
+                var: Token::new(),
+                depth: 0,
+                is_captured: false,
+            }],
+            scope_depth: 0,
+            upvalues: Vec::new(),
+        };
+        let enclosing_chunk = std::mem::replace(&mut self.compiling_chunk, Chunk::new());
+        let enclosing_locals = std::mem::replace(&mut self.locals, frame_locals);
+        self.enclosing.push(enclosing_locals);
+        let enclosing_type = std::mem::replace(&mut self.function_type, FunctionType::Function);
+        let enclosing_jump_target = std::mem::replace(&mut self.last_jump_target, 0);
+
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        let mut arity = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arity += 1;
+                if arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let param = self.parse_variable("Expect parameter name.");
+                self.define_variable(param);
+                if !self.match_next(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+        self.emit_return();
+
+        let chunk = std::mem::replace(&mut self.compiling_chunk, enclosing_chunk);
+        let locals = std::mem::replace(&mut self.locals, self.enclosing.pop().unwrap());
+        self.function_type = enclosing_type;
+        self.last_jump_target = enclosing_jump_target;
+
+        let function = Function { name, arity, chunk };
+        let index = self.current_chunk().add_constant(Value::Function(Rc::new(function)));
+        self.emit_byte(OpCode::Closure(index, locals.upvalues));
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
@@ -380,6 +497,7 @@ impl Compiler {
         let local = Local {
             var,
             depth: -1,
+            is_captured: false,
         };
         self.locals.list.push(local);
     }
@@ -425,14 +543,19 @@ impl Compiler {
     }
 
     fn error_at(&mut self, token: &Token, message: &str) {
+        // Only the first error of a statement is reported; the rest are
+        // usually fallout from it until `synchronize` finds the next one.
         if self.panic_mode {
             return;
         }
-        if token.token_type == TokenType::Eof {
-            println!("Error at end: {}", message);
+        self.panic_mode = true;
+        let error = if token.token_type == TokenType::Eof {
+            format!("Error at end: {}", message)
         } else {
-            println!("Error at line {}: {}", token.line, message)
-        }
+            format!("Error at line {}: {}", token.line, message)
+        };
+        println!("{}", error);
+        self.errors.push(error);
 
         self.had_error = true;
     }
@@ -447,21 +570,90 @@ impl Compiler {
     }
 
     fn emit_byte(&mut self, byte: OpCode) {
+        if self.fold_constants(&byte) {
+            return;
+        }
         self.compiling_chunk.write_chunk(byte, self.previous.line);
     }
 
+    /// Peephole constant folding: when `op` would apply to operands that were
+    /// just emitted as literals, replace them with the result instead.
+    /// Returns whether `op` was folded away.
+    fn fold_constants(&mut self, op: &OpCode) -> bool {
+        let code = &self.compiling_chunk.code;
+        let len = code.len();
+        let operands = match op {
+            OpCode::Not | OpCode::Negate => 1,
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Equal => 2,
+            _ => return false,
+        };
+        if len < operands || self.last_jump_target > len - operands {
+            return false;
+        }
+
+        let constants = &self.compiling_chunk.constants;
+        let constant = |op: &OpCode| match op {
+            OpCode::Constant(index) => Some(&constants[*index]),
+            _ => None,
+        };
+        let folded = match (op, &code[len - operands..]) {
+            (OpCode::Not, [OpCode::True]) => Value::Bool(false),
+            (OpCode::Not, [OpCode::False | OpCode::Nil]) => Value::Bool(true),
+            (OpCode::Not, _) => return false,
+            (_, operand_ops) => {
+                let Some(values) = operand_ops.iter().map(constant).collect::<Option<Vec<_>>>()
+                else {
+                    return false;
+                };
+                match (op, &values[..]) {
+                    (OpCode::Negate, [Value::Number(n)]) => Value::Number(-*n),
+                    (OpCode::Equal, [a, b]) => Value::Bool(a.lox_eq(b)),
+                    (_, [Value::Number(a), Value::Number(b)]) => {
+                        let (a, b) = (*a, *b);
+                        match op {
+                            OpCode::Add => Value::Number(a + b),
+                            OpCode::Subtract => Value::Number(a - b),
+                            OpCode::Multiply => Value::Number(a * b),
+                            OpCode::Divide => Value::Number(a / b),
+                            OpCode::Modulo => Value::Number(a % b),
+                            OpCode::Greater => Value::Bool(a > b),
+                            OpCode::Less => Value::Bool(a < b),
+                            _ => return false,
+                        }
+                    }
+                    _ => return false,
+                }
+            }
+        };
+
+        let line = self.compiling_chunk.lines[len - operands];
+        self.compiling_chunk.code.truncate(len - operands);
+        self.compiling_chunk.lines.truncate(len - operands);
+        self.compiling_chunk.trim_constants();
+        let folded = match folded {
+            Value::Bool(true) => OpCode::True,
+            Value::Bool(false) => OpCode::False,
+            value => OpCode::Constant(self.compiling_chunk.add_constant(value)),
+        };
+        self.compiling_chunk.write_chunk(folded, line);
+        true
+    }
+
     fn emit_bytes(&mut self, byte1: OpCode, byte2: OpCode) {
         self.emit_byte(byte1);
         self.emit_byte(byte2);
     }
 
     fn end_compiler(&mut self) {
-        self.emit_return();
-        if self.debug_trace_execution && !self.had_error {
-            for chunk in &self.current_chunk().code {
-                println!("{:?}", chunk);
-            }
-        }
+        // The script leaves its stack as is; only functions return a value.
+        self.emit_byte(OpCode::Return);
     }
 
     fn binary(&mut self, _can_assign: Option<bool>) {
@@ -475,6 +667,7 @@ impl Compiler {
             TokenType::Minus => self.emit_byte(OpCode::Subtract),
             TokenType::Star => self.emit_byte(OpCode::Multiply),
             TokenType::Slash => self.emit_byte(OpCode::Divide),
+            TokenType::Modulo => self.emit_byte(OpCode::Modulo),
             TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
             TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
             TokenType::Greater => self.emit_byte(OpCode::Greater),
@@ -485,6 +678,29 @@ impl Compiler {
         }
     }
 
+    fn call(&mut self, _can_assign: Option<bool>) {
+        let arg_count = self.argument_list();
+        self.emit_byte(OpCode::Call(arg_count));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_next(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count.min(255) as u8
+    }
+
     fn grouping(&mut self, _can_assign: Option<bool>) {
         self.expression();
         self.consume(
@@ -531,6 +747,9 @@ impl Compiler {
         if arg != -1 {
             get_op = OpCode::GetLocal(arg as usize);
             set_op = OpCode::SetLocal(arg as usize);
+        } else if let Some(index) = self.resolve_upvalue(self.enclosing.len(), &name) {
+            get_op = OpCode::GetUpvalue(index);
+            set_op = OpCode::SetUpvalue(index);
         } else {
             //let arg = self.identifier_constant(&self.current);
             get_op = OpCode::GetGlobal(name.clone());
@@ -549,7 +768,7 @@ impl Compiler {
 
     fn resolve_local(&mut self, name: &String) -> isize {
         for (i, local) in self.locals.list.iter().enumerate().rev() {
-            if local.var.lexeme == name.to_string() {
+            if local.var.lexeme == *name {
                 if local.depth == -1 {
                     self.error("Cannot read local variable in its own initializer.");
                 }
@@ -560,8 +779,50 @@ impl Compiler {
         return -1;
     }
 
+    /// Upvalue index of `name` in the function at `level` of the nesting,
+    /// the script being level 0 and the function being compiled the last.
+    /// Each function between the variable's and this one captures it too.
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        if level == 0 {
+            return None;
+        }
+        let enclosing = &mut self.enclosing[level - 1];
+        let local = enclosing.list.iter().rposition(|local| local.var.lexeme == name);
+        let upvalue = match local {
+            Some(slot) => {
+                enclosing.list[slot].is_captured = true;
+                UpvalueRef {
+                    index: slot,
+                    is_local: true,
+                }
+            }
+            None => UpvalueRef {
+                index: self.resolve_upvalue(level - 1, name)?,
+                is_local: false,
+            },
+        };
+        Some(self.add_upvalue(level, upvalue))
+    }
+
+    fn add_upvalue(&mut self, level: usize, upvalue: UpvalueRef) -> usize {
+        let locals = if level == self.enclosing.len() {
+            &mut self.locals
+        } else {
+            &mut self.enclosing[level]
+        };
+        if let Some(index) = locals.upvalues.iter().position(|u| *u == upvalue) {
+            return index;
+        }
+        if locals.upvalues.len() == u8::MAX as usize {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        locals.upvalues.push(upvalue);
+        locals.upvalues.len() - 1
+    }
+
     fn string(&mut self, _can_assign: Option<bool>) { 
-        let value = self.previous.lexeme.clone();
+        let value = self.previous.string_value();
         self.emit_constant(Value::String(value));
     }
 
@@ -625,13 +886,14 @@ impl Compiler {
         self.parse_precedence(Precedence::Assignment);
     }
 
+    /// Returns `nil` from the function being compiled.
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return );
+        self.emit_bytes(OpCode::Nil, OpCode::Return);
     }
 
     fn emit_constant(&mut self, value: Value) {
-        let chunk = self.current_chunk();
-        chunk.add_constant(value, 0);
+        let index = self.current_chunk().add_constant(value);
+        self.emit_byte(OpCode::Constant(index));
     }
 
     fn init_rules(&mut self) {
@@ -639,8 +901,8 @@ impl Compiler {
             TokenType::LeftParen,
             ParseRule {
                 prefix: Some(Compiler::grouping),
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Compiler::call),
+                precedence: Precedence::Call,
             },
         );
 
@@ -680,6 +942,15 @@ impl Compiler {
             },
         );
 
+        self.rules.insert(
+            TokenType::Modulo,
+            ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Factor,
+            },
+        );
+
         self.rules.insert(
             TokenType::Number,
             ParseRule {
@@ -805,3 +1076,198 @@ impl Compiler {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::Compiler;
+    use crate::compiler::{
+        chunk::{Chunk, OpCode},
+        value::Value,
+        vm::{InterpretResult, VM},
+    };
+
+    fn compile_chunk(source: &str) -> Chunk {
+        let mut compiler = Compiler::new(&source.to_string());
+        assert!(compiler.compile(&Chunk::new()));
+        compiler.compiling_chunk
+    }
+
+    fn compile(source: &str) -> Vec<OpCode> {
+        compile_chunk(source).code
+    }
+
+    fn printed(source: &str) -> Vec<String> {
+        let mut vm = VM::init_vm();
+        assert!(matches!(vm.interpret(&source.to_string()), InterpretResult::Ok));
+        vm.output
+    }
+
+    #[test]
+    fn folds_literal_not() {
+        let code = compile("print !true;");
+        assert!(matches!(code[..], [OpCode::False, OpCode::Print, OpCode::Return]));
+        assert_eq!(printed("print !true;"), vec!["false"]);
+        assert_eq!(printed("print !nil;"), vec!["true"]);
+    }
+
+    #[test]
+    fn folds_numeric_arithmetic_and_comparison() {
+        let chunk = compile_chunk("print 1 + 2 * 3;");
+        assert!(matches!(
+            chunk.code[..],
+            [OpCode::Constant(0), OpCode::Print, OpCode::Return]
+        ));
+        assert_eq!(chunk.constants, vec![Value::Number(OrderedFloat(7.0))]);
+        assert!(matches!(compile("print 1 < 2;")[0], OpCode::True));
+        assert!(matches!(compile("print 1 >= 2;")[0], OpCode::False));
+        assert_eq!(
+            compile_chunk("print -(4 - 6);").constants,
+            vec![Value::Number(OrderedFloat(2.0))]
+        );
+
+        assert_eq!(printed("print 1 + 2 * 3;"), vec!["7"]);
+        assert_eq!(printed("print 1 >= 2;"), vec!["false"]);
+        assert_eq!(printed("print -(4 - 6);"), vec!["2"]);
+    }
+
+    #[test]
+    fn folds_equality_like_the_vm() {
+        for (source, expected) in [
+            ("print \"a\" == \"a\";", "true"),
+            ("print 1 == \"1\";", "false"),
+            ("print 2 != 2;", "false"),
+            ("print 0/0 == 0/0;", "false"),
+        ] {
+            let code = compile(source);
+            assert!(matches!(code[0], OpCode::True | OpCode::False), "{}", source);
+            assert_eq!(printed(source), vec![expected]);
+        }
+    }
+
+    #[test]
+    fn does_not_fold_across_jump_target() {
+        // The `and` jumps to just before `+`, so the 2 and 3 must stay separate.
+        let code = compile("var a = false; print (a and 2) + 3;");
+        assert!(code.iter().any(|op| matches!(op, OpCode::Add)));
+        assert_eq!(printed("var a = 1; print (a and 2) + 3;"), vec!["5"]);
+    }
+
+    #[test]
+    fn leaves_non_literal_operands_alone() {
+        let code = compile("var a = 1; print a + 2;");
+        assert!(code.iter().any(|op| matches!(op, OpCode::Add)));
+        assert_eq!(printed("var a = 1; print a + 2;"), vec!["3"]);
+    }
+
+    fn compile_errors(source: &str) -> Vec<String> {
+        let mut compiler = Compiler::new(&source.to_string());
+        assert!(!compiler.compile(&Chunk::new()));
+        compiler.errors
+    }
+
+    #[test]
+    fn reports_each_statement_error_once() {
+        let errors = compile_errors(
+            "print 1 + ;
+            var a = 1;
+            print a;
+            var = 2;
+            print a;",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "Error at line 1: Expect expression.",
+                "Error at line 4: Expect variable name.",
+            ]
+        );
+    }
+
+    #[test]
+    fn error_at_end_does_not_loop() {
+        assert_eq!(compile_errors("print (1"), vec!["Error at end: Expect ')' after expression."]);
+    }
+
+    #[test]
+    fn return_outside_function_is_compile_error() {
+        assert_eq!(
+            compile_errors("return 1;"),
+            vec!["Error at line 1: Can't return from top-level code."]
+        );
+    }
+
+    #[test]
+    fn function_body_gets_its_own_chunk() {
+        let chunk = compile_chunk("fun one() { return 1; } print one();");
+        let code = chunk.code;
+        let function = match (&code[0], &chunk.constants[..]) {
+            (OpCode::Closure(0, upvalues), [Value::Function(function)]) if upvalues.is_empty() => {
+                function
+            }
+            op => panic!("expected a function constant, got {:?}", op),
+        };
+        assert_eq!(function.name, "one");
+        assert_eq!(function.arity, 0);
+        assert!(matches!(
+            function.chunk.code[..],
+            [OpCode::Constant(_), OpCode::Return, OpCode::Nil, OpCode::Return]
+        ));
+        assert!(code.contains(&OpCode::Call(0)));
+    }
+
+    #[test]
+    fn while_loop_counts_down() {
+        let source = "var n = 3; while (n > 0) { print n; n = n - 1; }";
+        let code = compile(source);
+        let jumps = code
+            .iter()
+            .filter(|op| matches!(op, OpCode::JumpIfFalse(_) | OpCode::Loop(_)))
+            .count();
+        assert_eq!(jumps, 2);
+        assert_eq!(printed(source), vec!["3", "2", "1"]);
+
+        let mut vm = VM::init_vm();
+        assert!(matches!(vm.interpret(&source.to_string()), InterpretResult::Ok));
+        assert!(vm.stack.values.is_empty());
+    }
+
+    #[test]
+    fn for_loop_runs_increment_after_body() {
+        assert_eq!(
+            printed("for (var i = 0; i < 3; i = i + 1) print i;"),
+            vec!["0", "1", "2"]
+        );
+        assert_eq!(
+            printed("var j = 0; for (; j < 2;) { print j; j = j + 1; } print j;"),
+            vec!["0", "1", "2"]
+        );
+
+        let mut vm = VM::init_vm();
+        let source = "for (var i = 0; i < 3; i = i + 1) { var twice = i * 2; print twice; }";
+        assert!(matches!(vm.interpret(&source.to_string()), InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["0", "2", "4"]);
+        assert!(vm.stack.values.is_empty());
+    }
+
+    #[test]
+    fn repeated_constants_share_a_pool_entry() {
+        let chunk = compile_chunk("print 1 + 1;");
+        assert_eq!(chunk.constants, vec![Value::Number(OrderedFloat(2.0))]);
+
+        let chunk = compile_chunk("var x = 1; print x + 1 + 1; print \"a\" + \"a\";");
+        assert_eq!(
+            chunk.constants,
+            vec![Value::Number(OrderedFloat(1.0)), Value::String("a".to_string())]
+        );
+        assert_eq!(
+            chunk.code.iter().filter(|op| **op == OpCode::Constant(0)).count(),
+            3
+        );
+
+        let chunk = compile_chunk("print 0; print -0;");
+        assert_eq!(chunk.constants.len(), 2);
+        assert_eq!(printed("print 0; print -0; print 1 + 1;"), vec!["0", "-0", "2"]);
+    }
+}
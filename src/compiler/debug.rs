@@ -0,0 +1,56 @@
+use super::chunk::Chunk;
+
+/// Prints every instruction of `chunk` under a `== name ==` header.
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
+    print!("{}", chunk.disassemble(name));
+}
+
+/// Prints the instruction at `offset` and returns the offset of the next one.
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
+    println!("{}", chunk.disassemble_instruction(offset));
+    offset + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble_chunk, disassemble_instruction};
+    use crate::compiler::{
+        chunk::Chunk,
+        compiler::Compiler,
+        vm::{InterpretResult, VM},
+    };
+
+    #[test]
+    fn disassembles_a_compiled_chunk() {
+        let mut compiler = Compiler::new(&"var x = 2;\nprint x + 1;".to_string());
+        assert!(compiler.compile(&Chunk::new()));
+        let chunk = compiler.compiling_chunk;
+
+        assert_eq!(
+            chunk.disassemble("script"),
+            "== script ==\n\
+             0000    1 OP_CONSTANT         0 '2'\n\
+             0001    | OP_DEFINE_GLOBAL 'x'\n\
+             0002    2 OP_GET_GLOBAL    'x'\n\
+             0003    | OP_CONSTANT         1 '1'\n\
+             0004    | OP_ADD\n\
+             0005    | OP_PRINT\n\
+             0006    | OP_RETURN\n"
+        );
+
+        disassemble_chunk(&chunk, "script");
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            offset = disassemble_instruction(&chunk, offset);
+        }
+        assert_eq!(offset, chunk.code.len());
+
+        let mut vm = VM::init_vm();
+        vm.debug_trace_execution = true;
+        assert!(matches!(vm.run_chunk(chunk), InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["3"]);
+        // Tracing a whole program disassembles it before running it.
+        assert!(matches!(vm.interpret(&"print 4;".to_string()), InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["3", "4"]);
+    }
+}
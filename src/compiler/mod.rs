@@ -1,5 +1,7 @@
 pub mod chunk;
 pub mod compiler;
+pub mod debug;
+pub mod natives;
 pub mod scanner;
 pub mod value;
 pub mod vm;
@@ -0,0 +1,22 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ordered_float::OrderedFloat;
+
+use super::value::{Native, Value};
+
+/// Functions implemented in Rust that every program gets as globals.
+pub fn natives() -> Vec<Native> {
+    vec![Native {
+        name: "clock".to_string(),
+        arity: 0,
+        function: clock,
+    }]
+}
+
+/// `clock()` is the number of seconds since the Unix epoch.
+fn clock(_args: &[Value]) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Value::Number(OrderedFloat(now.as_secs_f64()))
+}
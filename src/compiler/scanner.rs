@@ -8,6 +8,7 @@ pub enum TokenType {
     Comma,
     Dot,
     Minus,
+    Modulo,
     Plus,
     Semicolon,
     Slash,
@@ -70,6 +71,12 @@ impl Token {
             pos: 0,
         }
     }
+
+    /// Value of a string token: the lexeme already excludes the quotes, so
+    /// only escape sequences are left to process.
+    pub fn string_value(&self) -> String {
+        unescape(&self.lexeme)
+    }
 }
 
 pub struct Scanner {
@@ -107,7 +114,7 @@ impl Scanner {
             return self.number();
         }
 
-        let token = match c {
+        match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
@@ -119,6 +126,7 @@ impl Scanner {
             '+' => self.make_token(TokenType::Plus),
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
+            '%' => self.make_token(TokenType::Modulo),
             '!' => {
                 if self.match_next('=') {
                     self.make_token(TokenType::BangEqual)
@@ -149,11 +157,7 @@ impl Scanner {
             }
             '"' => self.string(),
             _ => self.error_token("Unexpected character."),
-        };
-
-        token
-
-        
+        }
     }
 
     fn identifier(&mut self) -> Token {
@@ -313,3 +317,109 @@ impl Scanner {
         }
     }
 }
+
+/// Expands `\n`, `\t`, `\r` and `\\` in a string literal. Unknown escapes are kept as written.
+pub fn unescape(raw: &str) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('t') => value.push('\t'),
+            Some('r') => value.push('\r'),
+            Some('\\') => value.push('\\'),
+            Some(other) => {
+                value.push('\\');
+                value.push(other);
+            }
+            None => value.push('\\'),
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scanner, Token, TokenType};
+
+    fn first_token(source: &str) -> Token {
+        Scanner::init_scanner(&source.to_string()).scan_token()
+    }
+
+    fn scan_all(source: &str) -> Vec<Token> {
+        let mut scanner = Scanner::init_scanner(&source.to_string());
+        let mut tokens = vec![];
+        loop {
+            let token = scanner.scan_token();
+            let done = token.token_type == TokenType::Eof;
+            tokens.push(token);
+            if done {
+                return tokens;
+            }
+        }
+    }
+
+    #[test]
+    fn scans_an_arithmetic_statement() {
+        let tokens = scan_all("(1 + 2) * 3;");
+        let kinds: Vec<(TokenType, &str)> = tokens
+            .iter()
+            .map(|token| (token.token_type.clone(), token.lexeme.as_str()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (TokenType::LeftParen, "("),
+                (TokenType::Number, "1"),
+                (TokenType::Plus, "+"),
+                (TokenType::Number, "2"),
+                (TokenType::RightParen, ")"),
+                (TokenType::Star, "*"),
+                (TokenType::Number, "3"),
+                (TokenType::Semicolon, ";"),
+                (TokenType::Eof, ""),
+            ]
+        );
+        assert!(tokens.iter().all(|token| token.line == 1));
+    }
+
+    #[test]
+    fn tracks_lines_across_strings_and_comments() {
+        let tokens = scan_all("// note\nprint \"one\ntwo\";\n@");
+        let lines: Vec<(TokenType, usize)> = tokens
+            .iter()
+            .map(|token| (token.token_type.clone(), token.line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (TokenType::Print, 2),
+                (TokenType::String, 3),
+                (TokenType::Semicolon, 3),
+                (TokenType::Error, 4),
+                (TokenType::Eof, 4),
+            ]
+        );
+        assert_eq!(tokens[1].lexeme, "one\ntwo");
+        assert_eq!(tokens[3].lexeme, "Unexpected character.");
+    }
+
+    #[test]
+    fn string_token_excludes_quotes() {
+        let token = first_token("\"hi\"");
+        assert_eq!(token.token_type, TokenType::String);
+        assert_eq!(token.lexeme, "hi");
+        assert_eq!(token.string_value(), "hi");
+    }
+
+    #[test]
+    fn string_token_value_processes_escapes() {
+        let token = first_token("\"a\\nb\\\\c\\q\"");
+        assert_eq!(token.lexeme, "a\\nb\\\\c\\q");
+        assert_eq!(token.string_value(), "a\nb\\c\\q");
+    }
+}
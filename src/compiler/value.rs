@@ -1,8 +1,13 @@
+use std::cell::RefCell;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap};
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 use ordered_float::OrderedFloat;
 
+use super::chunk::Chunk;
+
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
@@ -11,8 +16,75 @@ pub enum Value {
     Number(OrderedFloat<f64>),
     String(String),
     Hashmap(HashMap<Value, Value>),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Native(Native),
+}
+
+/// A compiled function: its own chunk plus what a call needs to check.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub chunk: Chunk,
+}
+
+/// A function as the program sees it at runtime, with the variables it
+/// captured from the functions around it.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// Functions and closures are equal only to themselves, matching `lox_eq`
+/// and the pointer they are hashed by.
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Function {}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Closure {}
+
+/// A captured variable. It stays `Open` on the stack slot while the function
+/// declaring it runs, and is `Closed` over, holding the value itself, once
+/// that slot goes out of scope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
 }
 
+pub type NativeFn = fn(&[Value]) -> Value;
+
+/// A function implemented in Rust. Calling it runs `function` on the
+/// arguments directly, without a call frame.
+#[derive(Debug, Clone)]
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+/// Natives are told apart by name, as they are hashed: function addresses
+/// aren't unique.
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Native {}
+
 impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
@@ -28,6 +100,34 @@ impl Hash for Value {
                 }
                 hasher.finish().hash(state);
             }
+            Value::Function(function) => Rc::as_ptr(function).hash(state),
+            Value::Closure(closure) => Rc::as_ptr(closure).hash(state),
+            Value::Native(native) => native.name.hash(state),
+        }
+    }
+}
+
+/// Formats values the way Lox prints them: `true`, `nil`, `3`, `hello`.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Hashmap(m) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Function(function) => write!(f, "<fn {}>", function.name),
+            Value::Closure(closure) => write!(f, "<fn {}>", closure.function.name),
+            Value::Native(_) => write!(f, "<native fn>"),
         }
     }
 }
@@ -94,6 +194,22 @@ impl Value {
         Value::String(s)
     }
 
+    /// Lox `==`: values of different types are never equal, and numbers
+    /// compare as IEEE floats so `NaN` is unequal to itself.
+    pub fn lox_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a.0 == b.0,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Hashmap(a), Value::Hashmap(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => a.name == b.name,
+            _ => false,
+        }
+    }
+
     pub fn is_falsey(&self) -> bool {
         match self {
             Value::Nil => true,
@@ -102,3 +218,80 @@ impl Value {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use ordered_float::OrderedFloat;
+
+    use super::{Chunk, Closure, Function, Value};
+
+    fn samples() -> Vec<Value> {
+        vec![
+            Value::Nil,
+            Value::Bool(true),
+            Value::Number(OrderedFloat(1.0)),
+            Value::String("1".to_string()),
+            Value::Hashmap(HashMap::new()),
+        ]
+    }
+
+    #[test]
+    fn lox_eq_same_type() {
+        assert!(Value::Nil.lox_eq(&Value::Nil));
+        assert!(Value::Bool(false).lox_eq(&Value::Bool(false)));
+        assert!(!Value::Bool(true).lox_eq(&Value::Bool(false)));
+        assert!(Value::Number(OrderedFloat(2.0)).lox_eq(&Value::Number(OrderedFloat(2.0))));
+        assert!(!Value::Number(OrderedFloat(2.0)).lox_eq(&Value::Number(OrderedFloat(3.0))));
+        assert!(Value::Number(OrderedFloat(0.0)).lox_eq(&Value::Number(OrderedFloat(-0.0))));
+        assert!(Value::String("a".to_string()).lox_eq(&Value::String("a".to_string())));
+        assert!(!Value::String("a".to_string()).lox_eq(&Value::String("b".to_string())));
+        assert!(Value::Hashmap(HashMap::new()).lox_eq(&Value::Hashmap(HashMap::new())));
+    }
+
+    #[test]
+    fn closures_equal_only_themselves() {
+        let function = Rc::new(Function {
+            name: "f".to_string(),
+            arity: 0,
+            chunk: Chunk::new(),
+        });
+        let closure = |function: &Rc<Function>| {
+            Value::Closure(Rc::new(Closure {
+                function: function.clone(),
+                upvalues: vec![],
+            }))
+        };
+        let (a, b) = (closure(&function), closure(&function));
+        assert_eq!(a, a.clone());
+        assert_ne!(a, b);
+        assert_ne!(
+            Value::Function(function.clone()),
+            Value::Function(Rc::new((*function).clone()))
+        );
+
+        let mut map = HashMap::new();
+        map.insert(a.clone(), 1);
+        map.insert(b.clone(), 2);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&a), Some(&1));
+    }
+
+    #[test]
+    fn lox_eq_nan_is_unequal_to_itself() {
+        let nan = Value::Number(OrderedFloat(f64::NAN));
+        assert!(!nan.lox_eq(&nan.clone()));
+    }
+
+    #[test]
+    fn lox_eq_different_types_are_unequal() {
+        let values = samples();
+        for (i, a) in values.iter().enumerate() {
+            for (j, b) in values.iter().enumerate() {
+                assert_eq!(a.lox_eq(b), i == j, "{:?} == {:?}", a, b);
+            }
+        }
+    }
+}
@@ -1,13 +1,44 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
-use super::{chunk::{Chunk, OpCode}, value::Value, compiler::Compiler};
+use super::{
+    chunk::{Chunk, OpCode},
+    compiler::Compiler,
+    debug,
+    natives::natives,
+    value::{Closure, Function, Upvalue, Value},
+};
 
-pub struct VM {
-    pub chunk: Chunk,
+/// How deeply calls may nest before the VM reports a stack overflow.
+pub const FRAMES_MAX: usize = 64;
+
+/// A function being executed: where it is in its chunk and where its
+/// locals start on the value stack. Slot 0 holds the function itself,
+/// except for the top-level script.
+pub struct CallFrame {
+    pub closure: Rc<Closure>,
     pub ip: usize,
+    pub slots: usize,
+}
+
+pub struct VM {
+    pub frames: Vec<CallFrame>,
     pub stack: Stack,
+    /// Upvalues still pointing into the stack, so closures capturing the
+    /// same variable share one.
+    pub open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
     pub globals: HashMap<String, Value>,
     pub debug_trace_execution: bool,
+    /// Keeps the globals of one `interpret` call for the next, as a REPL
+    /// needs. Otherwise every program starts with only the natives.
+    pub persist_globals: bool,
+    /// Every line written by `print`, in order.
+    pub output: Vec<String>,
+    /// Every runtime error reported, prefixed with `[line N]`.
+    pub errors: Vec<String>,
+    /// The calls that were active at the last runtime error, innermost first.
+    pub trace: Vec<String>,
 }
 
 pub struct Stack {
@@ -38,15 +69,26 @@ pub enum InterpretResult {
     RuntimeError,
 }
 
+fn native_globals() -> HashMap<String, Value> {
+    natives()
+        .into_iter()
+        .map(|native| (native.name.clone(), Value::Native(native)))
+        .collect()
+}
+
 impl VM {
     pub fn init_vm() -> VM {
         let stack = Stack { values: Vec::new() };
         VM {
-            chunk: Chunk::new(),
-            ip: 0,
+            frames: Vec::new(),
             stack,
+            open_upvalues: Vec::new(),
             debug_trace_execution: false,
-            globals: HashMap::new(),
+            persist_globals: false,
+            globals: native_globals(),
+            output: Vec::new(),
+            errors: Vec::new(),
+            trace: Vec::new(),
         }
     }
 
@@ -54,71 +96,167 @@ impl VM {
 
         let mut compi = Compiler::new(source);
 
-        if !compi.compile(&self.chunk) {
+        if !compi.compile(&Chunk::new()) {
             return InterpretResult::CompileError;
-        } else {
-            self.chunk = compi.compiling_chunk.clone();
         }
-        self.ip = 0;
-        println!("Code: {:?}", self.chunk.code);
+        if self.debug_trace_execution {
+            debug::disassemble_chunk(&compi.compiling_chunk, "script");
+        }
 
+        if !self.persist_globals {
+            self.globals = native_globals();
+        }
+
+        self.run_chunk(compi.compiling_chunk)
+    }
+
+    /// Runs an already compiled chunk from the start on an empty stack.
+    /// Globals are kept, so a chunk can be compiled once and run repeatedly.
+    pub fn run_chunk(&mut self, chunk: Chunk) -> InterpretResult {
+        self.reset_stack();
+        let script = Function {
+            name: "script".to_string(),
+            arity: 0,
+            chunk,
+        };
+        let closure = Closure {
+            function: Rc::new(script),
+            upvalues: Vec::new(),
+        };
+        self.frames.push(CallFrame {
+            closure: Rc::new(closure),
+            ip: 0,
+            slots: 0,
+        });
         self.run()
     }
 
     pub fn reset_stack(&mut self) {
         self.stack.values.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
     }
 
+    /// Reports `message` against the source line of the instruction that
+    /// was just executed, followed by a trace of the active calls.
     fn runtime_error(&mut self, message: String) {
-        println!("{}", message);
+        let frame = self.frame();
+        let line = frame.closure.function.chunk.lines[frame.ip.saturating_sub(1)];
+        let error = format!("[line {}] {}", line, message);
+        println!("{}", error);
+        self.trace = self.stack_trace();
+        for call in &self.trace {
+            println!("{}", call);
+        }
+        self.errors.push(error);
         self.reset_stack();
     }
 
+    /// One `[line N] in name()` entry per call frame, innermost first.
+    /// `ip` has already moved past the current instruction, hence the `- 1`.
+    fn stack_trace(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(depth, frame)| {
+                let line = frame.closure.function.chunk.lines[frame.ip.saturating_sub(1)];
+                match depth {
+                    0 => format!("[line {}] in script", line),
+                    _ => format!("[line {}] in {}()", line, frame.closure.function.name),
+                }
+            })
+            .collect()
+    }
+
     pub fn run(&mut self) -> InterpretResult {
         loop {
+            if self.debug_trace_execution {
+                let frame = self.frame();
+                debug::disassemble_instruction(&frame.closure.function.chunk, frame.ip);
+            }
             let instruction = self.read_byte();
-            //println!("Instruction: {:?}", instruction);
             match instruction {
                 OpCode::Return => {
-                    return InterpretResult::Ok;
+                    // The script has no caller and leaves its stack alone.
+                    if self.frames.len() == 1 {
+                        self.frames.pop();
+                        return InterpretResult::Ok;
+                    }
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.values.truncate(frame.slots);
+                    self.stack.push(result);
+                }
+                OpCode::Call(arg_count) => {
+                    if !self.call_value(arg_count as usize) {
+                        return InterpretResult::RuntimeError;
+                    }
                 }
                 OpCode::Negate => {
                     let value = self.stack.pop();
                     match value {
                         Some(Value::Number(n)) => self.stack.push(Value::Number(-n)),
-                        _ => return InterpretResult::RuntimeError,
+                        _ => {
+                            self.runtime_error("Operand must be a number.".to_string());
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                op @ (OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Modulo
+                | OpCode::Greater
+                | OpCode::Less) => {
+                    if !self.binary_op(op) {
+                        return InterpretResult::RuntimeError;
                     }
-                    print!("{:?}", value.unwrap());
                 }
-                OpCode::Add => self.binary_op(OpCode::Add),
-                OpCode::Subtract => self.binary_op(OpCode::Subtract),
-                OpCode::Multiply => self.binary_op(OpCode::Multiply),
-                OpCode::Divide => self.binary_op(OpCode::Divide),
-                OpCode::Constant(value) => self.stack.push(value),
+                OpCode::Constant(index) => {
+                    let value = self.read_constant(index);
+                    self.stack.push(value);
+                }
                 OpCode::True => self.stack.push(Value::Bool(true)),
                 OpCode::False =>  self.stack.push(Value::Bool(false)),
                 OpCode::Nil =>  self.stack.push(Value::Nil),
                 OpCode::Not => {
                     let value = self.stack.pop().unwrap().is_falsey();
-                    self.stack.push(Value::Bool(!value));
+                    self.stack.push(Value::Bool(value));
                 }
                 OpCode::Equal => {
                     let b = self.stack.pop().unwrap();
                     let a = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(a == b));
-                }
-                OpCode::Greater => {
-                    self.binary_op(OpCode::Greater);
+                    self.stack.push(Value::Bool(a.lox_eq(&b)));
                 }
-                OpCode::Less => {
-                    self.binary_op(OpCode::Less);
-                },
                 OpCode::Print => {
-                    println!("Print: {:?}", self.stack.peek().unwrap());
+                    let line = self.stack.pop().unwrap().to_string();
+                    println!("{}", line);
+                    self.output.push(line);
                 },
                 OpCode::Pop => {
                     self.stack.pop().unwrap();
                 }
+                OpCode::PopN(count) => {
+                    let len = self.stack.values.len();
+                    self.stack.values.truncate(len - count);
+                }
+                OpCode::Dup => {
+                    let value = self.stack.peek().unwrap().clone();
+                    self.stack.push(value);
+                }
+                OpCode::Swap => {
+                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop().unwrap();
+                    self.stack.push(b);
+                    self.stack.push(a);
+                }
                 OpCode::DefineGlobal(name) => {
                     self.globals.insert(name, self.stack.pop().unwrap());
                 },
@@ -132,54 +270,182 @@ impl VM {
                 },
                 OpCode::SetGlobal(name) => {
                     if self.globals.contains_key(&name) {
-                        self.globals.insert(name, self.stack.pop().unwrap());
+                        self.globals.insert(name, self.stack.peek().unwrap().clone());
                     } else {
                         self.runtime_error(format!("Undefined variable (set) '{}'.", name));
                         return InterpretResult::RuntimeError;
                     }
                 },
                 OpCode::GetLocal(index) => {
-                    let value = self.stack.peek_pos(index).unwrap().clone();
+                    let index = self.frame().slots + index;
+                    let value = match self.stack.peek_pos(index) {
+                        Some(value) => value.clone(),
+                        None => {
+                            self.runtime_error("Invalid local slot.".to_string());
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
                     self.stack.push(value);
                 },
                 OpCode::SetLocal(index) => {
+                    let index = self.frame().slots + index;
+                    if index >= self.stack.values.len() {
+                        self.runtime_error("Invalid local slot.".to_string());
+                        return InterpretResult::RuntimeError;
+                    }
                     let value = self.stack.peek().unwrap().clone();
                     self.stack.values[index] = value;
                 },
                 OpCode::JumpIfFalse(offset) => {
                     let value = self.stack.peek().unwrap().is_falsey();
                     if value {
-                        self.ip += offset as usize;
+                        self.frame().ip += offset as usize;
                     }
                 },
                 OpCode::Jump(offset) => {
-                    self.ip += offset as usize;
+                    self.frame().ip += offset as usize;
                 },
                 OpCode::Loop(offset) => {
-                    self.ip -= offset as usize;
+                    self.frame().ip -= offset as usize;
+                },
+                OpCode::Closure(index, captures) => {
+                    let Value::Function(function) = self.read_constant(index) else {
+                        unreachable!("closures are only made of function constants");
+                    };
+                    let slots = self.frame().slots;
+                    let upvalues = captures
+                        .iter()
+                        .map(|capture| match capture.is_local {
+                            true => self.capture_upvalue(slots + capture.index),
+                            false => Rc::clone(&self.frame().closure.upvalues[capture.index]),
+                        })
+                        .collect();
+                    let closure = Closure { function, upvalues };
+                    self.stack.push(Value::Closure(Rc::new(closure)));
+                },
+                OpCode::GetUpvalue(index) => {
+                    let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack.values[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                },
+                OpCode::SetUpvalue(index) => {
+                    let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                    let value = self.stack.peek().unwrap().clone();
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack.values[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                },
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.values.len() - 1);
+                    self.stack.pop();
                 },
             }
         }
     }
 
     fn read_byte(&mut self) -> OpCode {
-        let byte = &self.chunk.code[self.ip];
-        self.ip += 1;
-        byte.clone()
+        let frame = self.frame();
+        let byte = frame.closure.function.chunk.code[frame.ip].clone();
+        frame.ip += 1;
+        byte
     }
 
-    fn read_constant(&mut self) -> Value {
-        let index = self.read_byte();
-        match index {
-            OpCode::Constant(value) => value,
-            _ => unreachable!(),
+    /// The upvalue for the stack slot, shared with any closure that already
+    /// captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let open = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| *upvalue.borrow() == Upvalue::Open(slot));
+        if let Some(upvalue) = open {
+            return Rc::clone(upvalue);
         }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(Rc::clone(&upvalue));
+        upvalue
     }
 
-    fn binary_op(&mut self, op: OpCode) {
+    /// Moves the values of stack slots from `first` up into the upvalues
+    /// capturing them, as those slots are about to be popped.
+    fn close_upvalues(&mut self, first: usize) {
+        let stack = &self.stack.values;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= first => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    /// Calls the value sitting below the `arg_count` arguments on the stack.
+    fn call_value(&mut self, arg_count: usize) -> bool {
+        let callee = match self.stack.values.len().checked_sub(arg_count + 1) {
+            Some(slots) => (slots, self.stack.values[slots].clone()),
+            None => {
+                self.runtime_error("Stack underflow".to_string());
+                return false;
+            }
+        };
+        match callee {
+            (slots, Value::Closure(closure)) => {
+                let function = &closure.function;
+                if arg_count != function.arity {
+                    self.runtime_error(format!(
+                        "'{}' expected {} arguments but got {}.",
+                        function.name, function.arity, arg_count
+                    ));
+                    return false;
+                }
+                if self.frames.len() == FRAMES_MAX {
+                    self.runtime_error("Stack overflow.".to_string());
+                    return false;
+                }
+                self.frames.push(CallFrame {
+                    closure,
+                    ip: 0,
+                    slots,
+                });
+                true
+            }
+            (slots, Value::Native(native)) => {
+                if arg_count != native.arity {
+                    self.runtime_error(format!(
+                        "'{}' expected {} arguments but got {}.",
+                        native.name, native.arity, arg_count
+                    ));
+                    return false;
+                }
+                let result = (native.function)(&self.stack.values[slots + 1..]);
+                self.stack.values.truncate(slots);
+                self.stack.push(result);
+                true
+            }
+            _ => {
+                self.runtime_error("Can only call functions and classes.".to_string());
+                false
+            }
+        }
+    }
+
+    fn read_constant(&mut self, index: usize) -> Value {
+        self.frame().closure.function.chunk.constants[index].clone()
+    }
+
+    /// Applies a numeric or string operator to the top two values. Returns
+    /// false after reporting a runtime error.
+    fn binary_op(&mut self, op: OpCode) -> bool {
         let top = self.stack.values.len();
         if top < 2 {
-            return self.runtime_error("Stack underflow".to_string());
+            self.runtime_error("Stack underflow".to_string());
+            return false;
         }
         let b = self.stack.pop().unwrap();
         let a = self.stack.pop().unwrap();
@@ -190,19 +456,406 @@ impl VM {
                 OpCode::Subtract => Value::from_f64(a.as_number() - b.as_number()),
                 OpCode::Multiply => Value::from_f64(a.as_number() * b.as_number()),
                 OpCode::Divide => Value::from_f64(a.as_number() / b.as_number()),
+                OpCode::Modulo => Value::from_f64(a.as_number() % b.as_number()),
                 OpCode::Less => Value::from_bool(a.as_number() < b.as_number()),
                 OpCode::Greater => Value::from_bool(a.as_number() > b.as_number()),
                 _ => unreachable!(),
             };
             self.stack.push(res);
-        } else if a.is_string() && b.is_string() {
-            let res = match op {
-                OpCode::Add => Value::from_string(format!("{}{}", a.as_string(), b.as_string())),
-                _ => unreachable!(),
-            };
+        } else if op == OpCode::Add && a.is_string() && b.is_string() {
+            let res = Value::from_string(format!("{}{}", a.as_string(), b.as_string()));
             self.stack.push(res);
-        } else {
+        } else if op == OpCode::Add {
             self.runtime_error("Operands must be two numbers or two strings.".to_string());
+            return false;
+        } else {
+            self.runtime_error("Operands must be numbers.".to_string());
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::{InterpretResult, VM};
+    use crate::compiler::{
+        chunk::{Chunk, OpCode},
+        compiler::Compiler,
+        value::Value,
+    };
+
+    fn run_chunk(constants: Vec<Value>, code: Vec<OpCode>) -> (VM, InterpretResult) {
+        let mut chunk = Chunk::new();
+        chunk.constants = constants;
+        for op in code {
+            chunk.write_chunk(op, 1);
         }
+        let mut vm = VM::init_vm();
+        let res = vm.run_chunk(chunk);
+        (vm, res)
+    }
+
+    fn number(n: f64) -> Value {
+        Value::Number(OrderedFloat(n))
+    }
+
+    #[test]
+    fn dup_pushes_copy_of_top() {
+        let (vm, res) = run_chunk(
+            vec![number(1.0), number(2.0)],
+            vec![
+                OpCode::Constant(0),
+                OpCode::Constant(1),
+                OpCode::Dup,
+                OpCode::Return,
+            ],
+        );
+        assert!(matches!(res, InterpretResult::Ok));
+        assert_eq!(vm.stack.values, vec![number(1.0), number(2.0), number(2.0)]);
+    }
+
+    #[test]
+    fn swap_exchanges_top_two() {
+        let (vm, res) = run_chunk(
+            vec![number(1.0), number(2.0), number(3.0)],
+            vec![
+                OpCode::Constant(0),
+                OpCode::Constant(1),
+                OpCode::Constant(2),
+                OpCode::Swap,
+                OpCode::Return,
+            ],
+        );
+        assert!(matches!(res, InterpretResult::Ok));
+        assert_eq!(vm.stack.values, vec![number(1.0), number(3.0), number(2.0)]);
+    }
+
+    #[test]
+    fn get_local_out_of_range_is_runtime_error() {
+        let (vm, res) = run_chunk(
+            vec![number(1.0)],
+            vec![
+                OpCode::Constant(0),
+                OpCode::GetLocal(3),
+                OpCode::Return,
+            ],
+        );
+        assert!(matches!(res, InterpretResult::RuntimeError));
+        assert!(vm.stack.values.is_empty());
+    }
+
+    #[test]
+    fn set_local_out_of_range_is_runtime_error() {
+        let (_, res) = run_chunk(
+            vec![number(1.0)],
+            vec![
+                OpCode::Constant(0),
+                OpCode::SetLocal(1),
+                OpCode::Return,
+            ],
+        );
+        assert!(matches!(res, InterpretResult::RuntimeError));
+    }
+
+    fn printed(source: &str) -> Vec<String> {
+        let mut vm = VM::init_vm();
+        let res = vm.interpret(&source.to_string());
+        assert!(matches!(res, InterpretResult::Ok));
+        vm.output
+    }
+
+    #[test]
+    fn print_uses_canonical_lox_format() {
+        assert_eq!(printed("print true;"), vec!["true"]);
+        assert_eq!(printed("print false;"), vec!["false"]);
+        assert_eq!(printed("print nil;"), vec!["nil"]);
+        assert_eq!(printed("print 1;"), vec!["1"]);
+        assert_eq!(printed("print 2.5;"), vec!["2.5"]);
+        assert_eq!(printed("print !nil;"), vec!["true"]);
+        assert_eq!(printed("print 1 == 2;"), vec!["false"]);
+    }
+
+    #[test]
+    fn print_string_without_quotes() {
+        assert_eq!(printed("print \"hi\";"), vec!["hi"]);
+        assert_eq!(printed("print \"\";"), vec![""]);
+        assert_eq!(printed("print \"a\\tb\";"), vec!["a\tb"]);
+        assert_eq!(printed("print \"back\\\\slash\";"), vec!["back\\slash"]);
+    }
+
+    #[test]
+    fn if_else_runs_one_branch() {
+        assert_eq!(printed("if (true) print 1; else print 2;"), vec!["1"]);
+        assert_eq!(printed("if (false) print 1; else print 2;"), vec!["2"]);
+        assert_eq!(printed("if (nil) print 1; print 3;"), vec!["3"]);
+        assert_eq!(
+            printed("var a = 1; if (a == 1) { print a; } else { print 0; } print a + 1;"),
+            vec!["1", "2"]
+        );
+    }
+
+    #[test]
+    fn run_precompiled_chunk_twice() {
+        let source = "var a = 1; { var b = a + 1; print b; } print a;".to_string();
+        let mut compiler = Compiler::new(&source);
+        assert!(compiler.compile(&Chunk::new()));
+        let chunk = compiler.compiling_chunk;
+
+        let mut vm = VM::init_vm();
+        assert!(matches!(vm.run_chunk(chunk.clone()), InterpretResult::Ok));
+        let first_stack = vm.stack.values.clone();
+        assert!(matches!(vm.run_chunk(chunk), InterpretResult::Ok));
+
+        assert_eq!(vm.output, vec!["2", "1", "2", "1"]);
+        assert_eq!(vm.stack.values, first_stack);
+        assert_eq!(vm.globals["a"], number(1.0));
+    }
+
+    #[test]
+    fn interpret_starts_each_program_with_fresh_globals() {
+        let mut vm = VM::init_vm();
+        let res = vm.interpret(&"var a = 1; { var b = 2; print a + b; }".to_string());
+        assert!(matches!(res, InterpretResult::Ok));
+        let res = vm.interpret(&"print a;".to_string());
+        assert!(matches!(res, InterpretResult::RuntimeError));
+        assert_eq!(vm.errors, vec!["[line 1] Undefined variable (get) 'a'."]);
+        assert!(vm.stack.values.is_empty());
+
+        let res = vm.interpret(&"print clock() > 0;".to_string());
+        assert!(matches!(res, InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["3", "true"]);
+    }
+
+    #[test]
+    fn persisted_globals_carry_over_between_programs() {
+        let mut vm = VM::init_vm();
+        vm.persist_globals = true;
+        assert!(matches!(vm.interpret(&"var a = 1;".to_string()), InterpretResult::Ok));
+        assert!(matches!(vm.interpret(&"a = a + 1; print a;".to_string()), InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["2"]);
+    }
+
+    fn run_error(source: &str) -> Vec<String> {
+        let mut vm = VM::init_vm();
+        let res = vm.interpret(&source.to_string());
+        assert!(matches!(res, InterpretResult::RuntimeError));
+        vm.errors
+    }
+
+    #[test]
+    fn undefined_global_read_reports_line() {
+        let errors = run_error("var a = 1;\nprint a;\n\nprint b;");
+        assert_eq!(errors, vec!["[line 4] Undefined variable (get) 'b'."]);
+    }
+
+    #[test]
+    fn undefined_global_write_reports_line() {
+        let errors = run_error("var a = 1;\na = 2;\nb = 3;");
+        assert_eq!(errors, vec!["[line 3] Undefined variable (set) 'b'."]);
+    }
+
+    #[test]
+    fn print_pops_its_operand() {
+        let mut vm = VM::init_vm();
+        let res = vm.interpret(&"{ var a = 1; print a; var b = 2; print b; }".to_string());
+        assert!(matches!(res, InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["1", "2"]);
+        assert!(vm.stack.values.is_empty());
+    }
+
+    #[test]
+    fn uninitialized_local_is_popped_once() {
+        let mut vm = VM::init_vm();
+        let res = vm.interpret(&"{ var a; var b = 2; print b; print a; }".to_string());
+        assert!(matches!(res, InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["2", "nil"]);
+        assert!(vm.stack.values.is_empty());
+    }
+
+    #[test]
+    fn calls_a_function() {
+        assert_eq!(printed("fun add(a, b) { return a + b; } print add(1, 2);"), vec!["3"]);
+    }
+
+    #[test]
+    fn call_leaves_only_the_result_on_the_stack() {
+        let mut vm = VM::init_vm();
+        let source = "fun f(a) { var b = a * 2; return b; }
+            { var x = 1; var y = f(x) + f(10); print x; print y; }";
+        assert!(matches!(vm.interpret(&source.to_string()), InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["1", "22"]);
+        assert!(vm.stack.values.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn functions_return_nil_by_default_and_recurse() {
+        let source = "fun nothing() {}
+            fun early(n) { if (n > 0) return \"positive\"; return; }
+            fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+            print nothing();
+            print early(1);
+            print early(0);
+            print fib(10);
+            print fib;";
+        assert_eq!(printed(source), vec!["nil", "positive", "nil", "55", "<fn fib>"]);
+    }
+
+    #[test]
+    fn arity_mismatch_is_runtime_error() {
+        let errors = run_error("fun f(a, b) {}\nf(1);");
+        assert_eq!(errors, vec!["[line 2] 'f' expected 2 arguments but got 1."]);
+    }
+
+    #[test]
+    fn calling_a_non_function_is_runtime_error() {
+        let errors = run_error("var a = 1;\na();");
+        assert_eq!(errors, vec!["[line 2] Can only call functions and classes."]);
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows_frames() {
+        let errors = run_error("fun f() { f(); }\nf();");
+        assert_eq!(errors, vec!["[line 1] Stack overflow."]);
+    }
+
+    #[test]
+    fn clock_native_returns_a_number() {
+        let mut vm = VM::init_vm();
+        let source = "var t = clock(); { var local = clock(); print local > 0; }";
+        assert!(matches!(vm.interpret(&source.to_string()), InterpretResult::Ok));
+        assert!(matches!(vm.globals["t"], Value::Number(_)));
+        assert_eq!(vm.output, vec!["true"]);
+        assert!(vm.stack.values.is_empty());
+        assert_eq!(run_error("clock(1);"), vec!["[line 1] 'clock' expected 0 arguments but got 1."]);
+    }
+
+    #[test]
+    fn end_scope_pops_locals_at_once() {
+        let source = "var outer = 0; { var a = 1; var b = 2; var c = 3; outer = a + b + c; } print outer;";
+        let mut compiler = Compiler::new(&source.to_string());
+        assert!(compiler.compile(&Chunk::new()));
+        let code = compiler.compiling_chunk.code.clone();
+        let pops: Vec<&OpCode> = code
+            .iter()
+            .filter(|op| matches!(op, OpCode::PopN(_)))
+            .collect();
+        assert_eq!(pops, vec![&OpCode::PopN(3)]);
+
+        let mut vm = VM::init_vm();
+        assert!(matches!(vm.run_chunk(compiler.compiling_chunk), InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["6"]);
+        assert!(vm.stack.values.is_empty());
+    }
+
+    #[test]
+    fn block_local_lives_in_its_stack_slot() {
+        let source = "{ var x = 5; print x; }";
+        let mut compiler = Compiler::new(&source.to_string());
+        assert!(compiler.compile(&Chunk::new()));
+        let code = compiler.compiling_chunk.code.clone();
+        assert!(!code.iter().any(|op| matches!(op, OpCode::DefineGlobal(_))));
+        assert!(matches!(
+            code[..],
+            [
+                OpCode::Constant(_),
+                OpCode::GetLocal(0),
+                OpCode::Print,
+                OpCode::Pop,
+                OpCode::Return
+            ]
+        ));
+
+        let mut vm = VM::init_vm();
+        assert!(matches!(vm.run_chunk(compiler.compiling_chunk), InterpretResult::Ok));
+        assert_eq!(vm.output, vec!["5"]);
+        assert!(vm.stack.values.is_empty());
+        assert!(vm.globals.get("x").is_none());
+    }
+
+    #[test]
+    fn operand_errors_report_the_offending_line() {
+        let errors = run_error("var a = 1;\nvar b = \"b\";\nprint a;\nprint a +\n  b;");
+        assert_eq!(errors, vec!["[line 5] Operands must be two numbers or two strings."]);
+        let errors = run_error("print \"a\" - \"b\";");
+        assert_eq!(errors, vec!["[line 1] Operands must be numbers."]);
+        let errors = run_error("var s = \"s\";\nprint -s;");
+        assert_eq!(errors, vec!["[line 2] Operand must be a number."]);
+    }
+
+    #[test]
+    fn modulo_takes_the_remainder() {
+        assert_eq!(printed("print 10 % 4;"), vec!["2"]);
+        assert_eq!(printed("var a = 5.5; print a % 2;"), vec!["1.5"]);
+        assert_eq!(printed("print 1 + 7 % 3 * 2;"), vec!["3"]);
+        let errors = run_error("var s = \"s\";\nprint 10 % s;");
+        assert_eq!(errors, vec!["[line 2] Operands must be numbers."]);
+    }
+
+    #[test]
+    fn stack_trace_lists_calls_innermost_first() {
+        let mut vm = VM::init_vm();
+        let source = "fun inner() {\n  return 1 + nil;\n}\nfun outer() {\n  inner();\n}\nouter();";
+        let res = vm.interpret(&source.to_string());
+        assert!(matches!(res, InterpretResult::RuntimeError));
+        assert_eq!(vm.errors, vec!["[line 2] Operands must be two numbers or two strings."]);
+        assert_eq!(
+            vm.trace,
+            vec!["[line 2] in inner()", "[line 5] in outer()", "[line 7] in script"]
+        );
+    }
+
+    #[test]
+    fn closures_keep_their_captured_variables() {
+        let source = "fun makeCounter() {
+              var i = 0;
+              fun count() { i = i + 1; return i; }
+              return count;
+            }
+            var c = makeCounter();
+            print c(); print c(); print c();
+            var d = makeCounter();
+            print d();";
+        assert_eq!(printed(source), vec!["1", "2", "3", "1"]);
+    }
+
+    #[test]
+    fn closures_share_a_variable_and_see_later_writes() {
+        let source = "var get; var set;
+            fun outer() {
+              var x = \"before\";
+              fun g() { return x; }
+              fun s(v) { x = v; }
+              get = g; set = s;
+              x = \"after\";
+            }
+            outer();
+            print get();
+            set(\"set\");
+            print get();";
+        assert_eq!(printed(source), vec!["after", "set"]);
+    }
+
+    #[test]
+    fn nested_closures_capture_through_the_middle_function() {
+        let source = "fun outer() {
+              var a = \"a\";
+              fun middle() {
+                fun inner() { return a; }
+                return inner;
+              }
+              return middle;
+            }
+            print outer()()();
+            {
+              var b = 1;
+              fun show() { return b; }
+              b = 2;
+              print show();
+            }";
+        assert_eq!(printed(source), vec!["a", "2"]);
     }
 }
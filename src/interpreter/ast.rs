@@ -1,6 +1,6 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::BTreeMap;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::rc::Rc;
 
 use ordered_float::OrderedFloat;
@@ -8,7 +8,42 @@ use ordered_float::OrderedFloat;
 use crate::extract_enum_value;
 use crate::interpreter::lexer::Token;
 use crate::interpreter::operators::Operator;
-use crate::interpreter::visitors::interpreter::{Environment, Interpreter};
+use crate::interpreter::visitors::interpreter::{BlockContext, Environment, Interpreter};
+
+/// Signature of functions implemented in Rust and exposed as Lox globals.
+pub type NativeFn = fn(&mut Interpreter, Vec<Expr>) -> Result<Expr, Error>;
+
+/// The `NativeFn` a `Native` runs. It takes no part in comparisons, which
+/// go by the native's name: the same function can sit at several addresses
+/// and different ones can share an address.
+#[derive(Clone, Copy)]
+pub struct NativeFunction(pub NativeFn);
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for NativeFunction {}
+
+impl PartialOrd for NativeFunction {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NativeFunction {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NativeFunction")
+    }
+}
 
 macro_rules! define_ast {
     (pub enum $root:ident { $($n:ident: $t:ident $b:tt),* $(,)? }) => {
@@ -56,36 +91,84 @@ define_ast!(
             pub operator: Operator,
             pub right: Box<Expr>,
         },
+        Ternary: struct {
+            pub condition: Box<Expr>,
+            pub then_branch: Box<Expr>,
+            pub else_branch: Box<Expr>,
+        },
         Call: struct {
             pub callee: Box<Expr>,
             pub paren: Token,
             pub arguments: Vec<Expr>,
+            /// Set by the resolver when the call is the whole value of a `return`
+            /// outside any `try`. The interpreter then makes the call in place of
+            /// the returning function's.
+            pub tail: Cell<bool>,
         },
         Function: struct {
             pub name: String,
             pub parameters: Vec<Var>,
-            pub body: Vec<Stmt>,
+            /// Shared by every copy of the function made from one evaluation
+            /// of its declaration, which is what `==` compares.
+            pub body: Rc<Vec<Stmt>>,
             pub context: Option<Rc<RefCell<Environment>>>,
             pub is_initializer: bool,
         },
         Instance: struct {
             pub class: Box<Class>,
-            pub fields: BTreeMap<String, Expr>,
+            pub fields: Rc<RefCell<BTreeMap<String, Expr>>>,
+        },
+        List: struct {
+            pub elements: Rc<RefCell<Vec<Expr>>>,
+        },
+        // Elements still to be evaluated into a fresh `List`.
+        ListLiteral: struct {
+            pub elements: Vec<Expr>,
+        },
+        // An anonymous `fun`, holding the `FunDecl` it closes over when evaluated.
+        Lambda: struct {
+            pub keyword: Token,
+            pub declaration: Box<Stmt>,
+        },
+        Native: struct {
+            pub name: String,
+            pub arity: usize,
+            pub function: NativeFunction,
+            /// The value a built-in method was looked up on, passed to
+            /// `function` ahead of the call's arguments.
+            pub receiver: Option<Box<Expr>>,
         },
         Class: struct {
             pub name: String,
-            pub methods: BTreeMap<String, Function>,
+            /// Shared by every copy of the class, which tells classes apart.
+            pub methods: Rc<BTreeMap<String, Function>>,
             pub superclass: Option<Box<Expr>>,
         },
         Get: struct {
             pub object: Box<Expr>,
             pub name: Token,
+            pub optional: bool,
         },
         Set: struct {
             pub object: Box<Expr>,
             pub name: Token,
             pub value: Box<Expr>,
         },
+        Index: struct {
+            pub object: Box<Expr>,
+            pub bracket: Token,
+            pub index: Box<Expr>,
+        },
+        IndexSet: struct {
+            pub object: Box<Expr>,
+            pub bracket: Token,
+            pub index: Box<Expr>,
+            pub value: Box<Expr>,
+        },
+        // Stands in for a malformed argument or list element in a partial parse.
+        InvalidExpr: struct {
+            pub msg: String,
+        },
         This: struct {
             pub keyword: Token,
         },
@@ -107,7 +190,14 @@ define_ast!(
         },
         VarDecl: struct {
             pub name: String,
-            pub expr: Box<Expr>
+            pub expr: Box<Expr>,
+            /// Declared with `const`, so it can't be assigned to afterwards.
+            pub constant: bool,
+        },
+        // `var a, b = value;`, binding each name to an element of the list `value`.
+        Destructure: struct {
+            pub names: Vec<String>,
+            pub expr: Box<Expr>,
         },
         FunDecl: struct {
             pub name: String,
@@ -124,16 +214,44 @@ define_ast!(
         },
         While: struct {
             pub condition: Box<Expr>,
-            pub body: Box<Stmt>
+            pub body: Box<Stmt>,
+            // A `for` loop's increments, run in order after the body even
+            // when the body hits `continue`.
+            pub increment: Vec<Expr>,
+            // Runs once the condition turns false, but not after a `break`.
+            pub else_branch: Option<Box<Stmt>>
         },
         Return: struct {
             pub keyword: Token,
             pub value: Expr,
         },
+        Break: struct {
+            pub keyword: Token,
+        },
+        Continue: struct {
+            pub keyword: Token,
+        },
+        Assert: struct {
+            pub keyword: Token,
+            pub condition: Box<Expr>,
+            pub message: Option<Box<Expr>>,
+        },
         ClassDecl: struct {
             pub name: Token,
             pub methods: Vec<Stmt>,
             pub superclass: Option<Box<Expr>>,
+        },
+        EnumDecl: struct {
+            pub name: Token,
+            pub members: Vec<Token>,
+        },
+        Try: struct {
+            pub body: Vec<Stmt>,
+            pub name: Token,
+            pub handler: Vec<Stmt>,
+        },
+        Invalid: struct {
+            pub msg: String,
         }
     }
 );
@@ -141,15 +259,22 @@ define_ast!(
 impl Stmt {
     pub fn accept<T: IVisitorStmt<U>, U>(&self, visitor: &mut T) -> U {
         match self {
-            Stmt::Expression(_) => visitor.visit_expr(&self),
-            Stmt::Print(_) => visitor.visit_print(&self),
-            Stmt::VarDecl(_) => visitor.visit_var_decl(&self),
-            Stmt::Block(_) => visitor.visit_block(&self),
-            Stmt::If(_) => visitor.visit_if(&self),
-            Stmt::While(_) => visitor.visit_while(&self),
-            Stmt::FunDecl(_) => visitor.visit_fun_decl(&self),
-            Stmt::Return(_) => visitor.visit_return(&self),
-            Stmt::ClassDecl(_) => visitor.visit_class(&self),
+            Stmt::Expression(_) => visitor.visit_expr(self),
+            Stmt::Print(_) => visitor.visit_print(self),
+            Stmt::VarDecl(_) => visitor.visit_var_decl(self),
+            Stmt::Destructure(_) => visitor.visit_destructure(self),
+            Stmt::Block(_) => visitor.visit_block(self),
+            Stmt::If(_) => visitor.visit_if(self),
+            Stmt::While(_) => visitor.visit_while(self),
+            Stmt::FunDecl(_) => visitor.visit_fun_decl(self),
+            Stmt::Return(_) => visitor.visit_return(self),
+            Stmt::Break(_) => visitor.visit_break(self),
+            Stmt::Continue(_) => visitor.visit_continue(self),
+            Stmt::Assert(_) => visitor.visit_assert(self),
+            Stmt::ClassDecl(_) => visitor.visit_class(self),
+            Stmt::EnumDecl(_) => visitor.visit_enum(self),
+            Stmt::Try(_) => visitor.visit_try(self),
+            Stmt::Invalid(_) => visitor.visit_invalid(self),
         }
     }
 }
@@ -157,21 +282,50 @@ impl Stmt {
 impl Expr {
     pub fn accept<T: IVisitorExpr<U>, U>(&self, visitor: &mut T) -> U {
         match self {
-            Expr::Var(_) => visitor.visit_var(&self),
-            Expr::Literal(_) => visitor.visit_literal(&self),
-            Expr::Unary(_) => visitor.visit_unary(&self),
-            Expr::Binary(_) => visitor.visit_binary(&self),
-            Expr::Grouping(_) => visitor.visit_grouping(&self),
-            Expr::Assign(_) => visitor.visit_assign(&self),
-            Expr::Logical(_) => visitor.visit_logical(&self),
-            Expr::Call(_) => visitor.visit_call(&self),
-            Expr::Get(_) => visitor.visit_get(&self),
-            Expr::Set(_) => visitor.visit_set(&self),
-            Expr::This(_) => visitor.visit_this(&self),
-            Expr::Super(_) => visitor.visit_super(&self),
+            Expr::Var(_) => visitor.visit_var(self),
+            Expr::Literal(_) => visitor.visit_literal(self),
+            Expr::Unary(_) => visitor.visit_unary(self),
+            Expr::Binary(_) => visitor.visit_binary(self),
+            Expr::Grouping(_) => visitor.visit_grouping(self),
+            Expr::Assign(_) => visitor.visit_assign(self),
+            Expr::Logical(_) => visitor.visit_logical(self),
+            Expr::Ternary(_) => visitor.visit_ternary(self),
+            Expr::Call(_) => visitor.visit_call(self),
+            Expr::Get(_) => visitor.visit_get(self),
+            Expr::Set(_) => visitor.visit_set(self),
+            Expr::Index(_) => visitor.visit_index(self),
+            Expr::IndexSet(_) => visitor.visit_index_set(self),
+            Expr::This(_) => visitor.visit_this(self),
+            Expr::Super(_) => visitor.visit_super(self),
+            Expr::ListLiteral(_) => visitor.visit_list_literal(self),
+            Expr::Lambda(_) => visitor.visit_lambda(self),
+            Expr::InvalidExpr(_) => visitor.visit_invalid_expr(self),
             _ => panic!("Invalid expression"),
         }
     }
+
+    /// Identity of an expression the resolver can bind, assigned by the parser
+    /// so identical-looking occurrences stay distinct.
+    pub fn id(&self) -> Option<usize> {
+        match self {
+            Expr::Var(Var::Token(token)) => Some(token.id),
+            Expr::Assign(Assign {
+                var: Var::Token(token),
+                ..
+            }) => Some(token.id),
+            Expr::This(This { keyword }) => Some(keyword.id),
+            Expr::Super(Super { keyword, .. }) => Some(keyword.id),
+            _ => None,
+        }
+    }
+
+    /// Lox truthiness: `nil` and `false` are falsey, everything else is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(
+            self,
+            Expr::Literal(Literal::Nil) | Expr::Literal(Literal::Bool(false))
+        )
+    }
 }
 
 impl From<crate::interpreter::ast::Stmt> for crate::interpreter::ast::Function {
@@ -184,7 +338,7 @@ impl From<crate::interpreter::ast::Stmt> for crate::interpreter::ast::Function {
                     .into_iter()
                     .map(|x| crate::interpreter::ast::Var::Token(x))
                     .collect(),
-                body: fun_decl.body,
+                body: Rc::new(fun_decl.body),
                 context: None,
                 is_initializer: false,
             },
@@ -205,40 +359,52 @@ impl Function {
         interpreter: &mut Interpreter,
         args: Vec<Expr>,
     ) -> Result<Expr, Error> {
-        let Function {
-            name: _,
-            parameters,
-            body,
-            context,
-            is_initializer,
-        } = self;
-
-        let context = context.unwrap();
-        let mut env = interpreter.create_environment(Some(Rc::clone(&context)));
-
-        if args.len() != parameters.len() {
-            return Err(Error::new(format!(
-                "Invalid number of arguments (got {}, expected {}).",
-                args.len(),
-                parameters.len()
-            )));
-        }
+        let mut function = self;
+        let mut args = args;
+        // Each pass runs one function; a call in tail position leaves the
+        // next one to run in `tail_call` instead of nesting another call.
+        loop {
+            let Function {
+                name,
+                parameters,
+                body,
+                context,
+                is_initializer,
+            } = function;
 
-        for (i, arg) in args.into_iter().enumerate() {
-            let Var::Token(token) = parameters.get(i).unwrap();
-            env.define(&token.lexeme, arg)
-        }
-        //TODO globals here
-        let res: Option<Stmt> =
-            interpreter.execute_block(&body, Some(Rc::new(RefCell::new(env))))?;
+            let context = context.unwrap();
+            let mut env = interpreter.create_environment(Some(Rc::clone(&context)));
 
-        if is_initializer {
-            return Ok(context.as_ref().borrow().retrieve("this").unwrap());
-        }
+            if args.len() != parameters.len() {
+                return Err(Error::arity(&name, parameters.len(), args.len()));
+            }
+
+            for (i, arg) in args.into_iter().enumerate() {
+                let Var::Token(token) = parameters.get(i).unwrap();
+                env.define(&token.lexeme, arg)
+            }
+            //TODO globals here
+            let res: Option<Stmt> = interpreter.execute_block(
+                &body,
+                Some(Rc::new(RefCell::new(env))),
+                BlockContext::Statement,
+            )?;
+
+            if is_initializer {
+                let this = context.as_ref().borrow().retrieve("this");
+                return Ok(this.unwrap());
+            }
+
+            if let Some((next, next_args)) = interpreter.take_tail_call() {
+                function = next;
+                args = next_args;
+                continue;
+            }
 
-        match res {
-            Some(Stmt::Return(Return { keyword: _, value })) => Ok(value),
-            _ => Ok(Expr::Literal(Literal::Nil)),
+            return match res {
+                Some(Stmt::Return(Return { keyword: _, value })) => Ok(value),
+                _ => Ok(Expr::Literal(Literal::Nil)),
+            };
         }
     }
 
@@ -255,7 +421,7 @@ impl Function {
                     .into_iter()
                     .map(|x| crate::interpreter::ast::Var::Token(x))
                     .collect(),
-                body: fun_decl.body,
+                body: Rc::new(fun_decl.body),
                 context: env,
                 is_initializer,
             },
@@ -282,10 +448,7 @@ impl Class {
         interpreter: &mut Interpreter,
         args: Vec<Expr>,
     ) -> Result<Expr, Error> {
-        let instance = Instance {
-            class: Box::new(self.clone()),
-            fields: BTreeMap::new(),
-        };
+        let instance = Instance::new(self.clone());
         let init = self.find_method("init");
         match init {
             Ok(Some(init)) => {
@@ -294,11 +457,7 @@ impl Class {
             }
             _ => {
                 if args.len() != 0 {
-                    return Err(Error::new(format!(
-                        "Invalid number of arguments (got {}, expected {}).",
-                        args.len(),
-                        0
-                    )));
+                    return Err(Error::arity(&self.name, 0, args.len()));
                 }
                 Ok(Expr::Instance(instance))
             }
@@ -320,24 +479,43 @@ impl Class {
     }
 }
 
+impl List {
+    pub fn new(elements: Vec<Expr>) -> Self {
+        List {
+            elements: Rc::new(RefCell::new(elements)),
+        }
+    }
+}
+
 impl Instance {
+    pub fn new(class: Class) -> Self {
+        Instance {
+            class: Box::new(class),
+            fields: Rc::new(RefCell::new(BTreeMap::new())),
+        }
+    }
+
     pub fn get_field(&self, name: &str) -> Result<Expr, Error> {
-        match self.fields.get(name) {
-            Some(expr) => Ok(expr.clone()),
+        let field = self.fields.borrow().get(name).cloned();
+        match field {
+            Some(expr) => Ok(expr),
             None => {
                 let method = self.class.find_method(name);
                 match method {
                     Ok(Some(method)) => Ok(Expr::Function(method.bind(self))),
-                    _ => Err(Error {
-                        msg: format!("Undefined property '{:}'.", name),
-                    }),
+                    _ => Err(Error::new(
+                        ErrorKind::UndefinedProperty,
+                        format!("Undefined property '{:}'.", name),
+                    )),
                 }
             }
         }
     }
 
-    pub fn set_field(&mut self, name: &str, value: Expr) {
-        self.fields.insert(name.to_string(), value);
+    /// Fields are shared between every copy of an instance, so setting one
+    /// is visible through all the variables holding it.
+    pub fn set_field(&self, name: &str, value: Expr) {
+        self.fields.borrow_mut().insert(name.to_string(), value);
     }
 }
 
@@ -349,32 +527,105 @@ pub trait IVisitorExpr<T> {
     fn visit_grouping(&mut self, expr: &Expr) -> T;
     fn visit_assign(&mut self, expr: &Expr) -> T;
     fn visit_logical(&mut self, expr: &Expr) -> T;
+    fn visit_ternary(&mut self, expr: &Expr) -> T;
     fn visit_call(&mut self, expr: &Expr) -> T;
     fn visit_get(&mut self, expr: &Expr) -> T;
     fn visit_set(&mut self, expr: &Expr) -> T;
+    fn visit_index(&mut self, expr: &Expr) -> T;
+    fn visit_index_set(&mut self, expr: &Expr) -> T;
     fn visit_this(&mut self, expr: &Expr) -> T;
     fn visit_super(&mut self, expr: &Expr) -> T;
+    fn visit_list_literal(&mut self, expr: &Expr) -> T;
+    fn visit_lambda(&mut self, expr: &Expr) -> T;
+    fn visit_invalid_expr(&mut self, expr: &Expr) -> T;
 }
 
 pub trait IVisitorStmt<T> {
     fn visit_expr(&mut self, stmt: &Stmt) -> T;
     fn visit_print(&mut self, stmt: &Stmt) -> T;
     fn visit_var_decl(&mut self, stmt: &Stmt) -> T;
+    fn visit_destructure(&mut self, stmt: &Stmt) -> T;
     fn visit_block(&mut self, stmt: &Stmt) -> T;
     fn visit_if(&mut self, stmt: &Stmt) -> T;
     fn visit_while(&mut self, stmt: &Stmt) -> T;
     fn visit_fun_decl(&mut self, stmt: &Stmt) -> T;
     fn visit_return(&mut self, stmt: &Stmt) -> T;
+    fn visit_break(&mut self, stmt: &Stmt) -> T;
+    fn visit_continue(&mut self, stmt: &Stmt) -> T;
     fn visit_class(&mut self, stmt: &Stmt) -> T;
+    fn visit_enum(&mut self, stmt: &Stmt) -> T;
+    fn visit_try(&mut self, stmt: &Stmt) -> T;
+    fn visit_assert(&mut self, stmt: &Stmt) -> T;
+    fn visit_invalid(&mut self, stmt: &Stmt) -> T;
+}
+
+/// Shows the message, prefixed with `[line N]` when the line is known.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "[line {}] {}", location.line, self.msg),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+/// The stage or failure class an [`Error`] came from, so callers can tell
+/// a syntax error from a runtime one without matching on the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Lex,
+    Parse,
+    Resolve,
+    Runtime,
+    Type,
+    Arity,
+    UndefinedVariable,
+    UndefinedProperty,
+}
+
+/// Where in the source an error was found. Both are counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub col: usize,
 }
 
 #[derive(Debug)]
 pub struct Error {
+    pub kind: ErrorKind,
     pub msg: String,
+    /// Boxed to keep `Error` small: the parser's recursion passes a
+    /// `Result<_, Error>` through every level of nesting.
+    pub location: Option<Box<Location>>,
 }
 
 impl Error {
-    pub fn new(msg: String) -> Self {
-        Error { msg }
+    pub fn new(kind: ErrorKind, msg: String) -> Self {
+        Error {
+            kind,
+            msg,
+            location: None,
+        }
+    }
+
+    /// Locates the error at `token`.
+    pub fn at(self, token: &Token) -> Self {
+        let location = Location {
+            line: token.line,
+            col: token.col,
+        };
+        Error {
+            location: Some(Box::new(location)),
+            ..self
+        }
+    }
+
+    /// Calling `name` with `got` arguments when it takes `expected`. The
+    /// bytecode VM words its arity errors the same way.
+    pub fn arity(name: &str, expected: usize, got: usize) -> Self {
+        Error::new(
+            ErrorKind::Arity,
+            format!("'{}' expected {} arguments but got {}.", name, expected, got),
+        )
     }
 }
@@ -1,4 +1,10 @@
-use crate::interpreter::ast::Error;
+use crate::compiler::scanner::unescape;
+use crate::interpreter::ast::{Error, ErrorKind, Location};
+
+/// Default upper bound on identifier length, in bytes.
+pub const MAX_IDENTIFIER_LEN: usize = 1024;
+/// Default upper bound on string literal length, in bytes, quotes excluded.
+pub const MAX_STRING_LEN: usize = 64 * 1024;
 
 pub struct Lexer {
     source: String,
@@ -6,6 +12,12 @@ pub struct Lexer {
     start: usize,
     current: usize,
     line: usize,
+    /// Offset of the first character on the current line.
+    line_start: usize,
+    /// Column the token being scanned starts on, counted from 1.
+    col: usize,
+    max_identifier_len: usize,
+    max_string_len: usize,
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, PartialOrd, Ord, Eq)]
@@ -15,10 +27,18 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
+    DotDot,
     Minus,
+    Percent,
     Plus,
+    Question,
+    QuestionDot,
+    QuestionQuestion,
     Semicolon,
     Slash,
     Star,
@@ -40,8 +60,14 @@ pub enum TokenType {
 
     // Keywords.
     And,
+    Assert,
+    Break,
+    Catch,
     Class,
+    Const,
+    Continue,
     Else,
+    Enum,
     False,
     Fun,
     For,
@@ -53,6 +79,7 @@ pub enum TokenType {
     Super,
     This,
     True,
+    Try,
     Var,
     While,
 
@@ -66,7 +93,12 @@ pub struct Token {
     pub lexeme: String,
     pub literal: Option<TokenLiteral>,
     pub line: usize,
+    /// Column of the token's first character on its line, counted from 1.
+    pub col: usize,
     pub pos: usize,
+    /// Identity of the expression this token names, assigned by the parser;
+    /// 0 for tokens that don't name one.
+    pub id: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Ord, PartialOrd, Eq, Hash)]
@@ -77,18 +109,27 @@ pub enum TokenLiteral {
 
 impl Lexer {
     pub fn new(source: &str) -> Lexer {
+        Lexer::new_with_limits(source, MAX_IDENTIFIER_LEN, MAX_STRING_LEN)
+    }
+
+    pub fn new_with_limits(source: &str, max_identifier_len: usize, max_string_len: usize) -> Lexer {
         Lexer {
             source: source.to_owned(),
             tokens: Vec::new(),
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            col: 1,
+            max_identifier_len,
+            max_string_len,
         }
     }
 
     pub fn scan_tokens(&mut self) -> Result<(), Error> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.col = self.start - self.line_start + 1;
             self.scan_token()?;
         }
 
@@ -97,7 +138,9 @@ impl Lexer {
             lexeme: "".to_owned(),
             literal: None,
             line: self.line,
+            col: self.current - self.line_start + 1,
             pos: self.current,
+            id: 0,
         });
 
         Ok(())
@@ -111,9 +154,22 @@ impl Lexer {
             ')' => self.add_token(TokenType::RightParen, None),
             '{' => self.add_token(TokenType::LeftBrace, None),
             '}' => self.add_token(TokenType::RightBrace, None),
+            '[' => self.add_token(TokenType::LeftBracket, None),
+            ']' => self.add_token(TokenType::RightBracket, None),
             ',' => self.add_token(TokenType::Comma, None),
-            '.' => self.add_token(TokenType::Dot, None),
+            ':' => self.add_token(TokenType::Colon, None),
+            '.' => {
+                if self.match_next('.') {
+                    self.add_token(TokenType::DotDot, None)
+                } else {
+                    self.add_token(TokenType::Dot, None)
+                }
+            }
+            '?' if self.match_next('.') => self.add_token(TokenType::QuestionDot, None),
+            '?' if self.match_next('?') => self.add_token(TokenType::QuestionQuestion, None),
+            '?' => self.add_token(TokenType::Question, None),
             '-' => self.add_token(TokenType::Minus, None),
+            '%' => self.add_token(TokenType::Percent, None),
             '+' => self.add_token(TokenType::Plus, None),
             ';' => self.add_token(TokenType::Semicolon, None),
             '*' => self.add_token(TokenType::Star, None),
@@ -157,28 +213,35 @@ impl Lexer {
             }
             '"' => self.string()?,
             '0'..='9' => self.number(),
-            'a'..='z' | 'A'..='Z' => self.identifier(),
+            'a'..='z' | 'A'..='Z' | '_' => self.identifier()?,
             ' ' | '\r' | '\t' => {}
-            '\n' => self.line += 1,
+            '\n' => self.new_line(),
             _ => {
-                return Err(Error {
-                    msg: format!("[line {}] Error: Unexpected character.", self.line),
-                });
+                return Err(self.error("Error: Unexpected character."));
             }
         }
         Ok(())
     }
 
-    fn identifier(&mut self) {
-        while self.peek().is_alphanumeric() {
+    fn identifier(&mut self) -> Result<(), Error> {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
             self.advance();
+            if self.current - self.start > self.max_identifier_len {
+                return Err(self.error("Error: Identifier too long."));
+            }
         }
 
         let text = &self.source[self.start..self.current];
         let token_type = match text {
             "and" => TokenType::And,
+            "assert" => TokenType::Assert,
+            "break" => TokenType::Break,
+            "catch" => TokenType::Catch,
             "class" => TokenType::Class,
+            "const" => TokenType::Const,
+            "continue" => TokenType::Continue,
             "else" => TokenType::Else,
+            "enum" => TokenType::Enum,
             "false" => TokenType::False,
             "for" => TokenType::For,
             "fun" => TokenType::Fun,
@@ -190,34 +253,38 @@ impl Lexer {
             "super" => TokenType::Super,
             "this" => TokenType::This,
             "true" => TokenType::True,
+            "try" => TokenType::Try,
             "var" => TokenType::Var,
             "while" => TokenType::While,
             _ => TokenType::Identifier,
         };
 
         self.add_token(token_type, None);
+        Ok(())
     }
 
     fn string(&mut self) -> Result<(), Error> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            let c = self.advance();
+            if c == '\n' {
+                self.new_line();
+            }
+            // The opening quote is not part of the string's length.
+            if self.current - self.start - 1 > self.max_string_len {
+                return Err(self.error("Error: String too long."));
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            return Err(Error {
-                msg: format!("[line {}] Unterminated string.", self.line),
-            });
+            return Err(self.error("Unterminated string."));
         }
 
         // The closing quote.
         self.advance();
 
         // Trim the surrounding quotes.
-        let value = &self.source[self.start + 1..self.current - 1];
-        self.add_token(TokenType::String, Some(TokenLiteral::Str(value.to_owned())));
+        let value = unescape(&self.source[self.start + 1..self.current - 1]);
+        self.add_token(TokenType::String, Some(TokenLiteral::Str(value)));
         Ok(())
     }
 
@@ -252,10 +319,30 @@ impl Lexer {
             lexeme: text.to_owned(),
             literal: literal,
             line: self.line,
+            col: self.col,
             pos: self.current,
+            id: 0,
         });
     }
 
+    /// A lexing error located at the current line and token.
+    fn error(&self, msg: &str) -> Error {
+        Error {
+            kind: ErrorKind::Lex,
+            msg: msg.to_string(),
+            location: Some(Box::new(Location {
+                line: self.line,
+                col: self.col,
+            })),
+        }
+    }
+
+    /// Called after consuming a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -295,3 +382,36 @@ impl Lexer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Lexer, TokenLiteral, TokenType};
+
+    #[test]
+    fn identifier_length_limit() {
+        let mut lexer = Lexer::new_with_limits("abcd", 4, 16);
+        lexer.scan_tokens().unwrap();
+        assert_eq!(lexer.tokens[0].token_type, TokenType::Identifier);
+        assert_eq!(lexer.tokens[0].lexeme, "abcd");
+
+        let mut lexer = Lexer::new_with_limits("var abcde;", 4, 16);
+        let error = lexer.scan_tokens().unwrap_err();
+        assert_eq!(error.to_string(), "[line 1] Error: Identifier too long.");
+        assert_eq!(error.location.unwrap().col, 5);
+    }
+
+    #[test]
+    fn string_length_limit() {
+        let mut lexer = Lexer::new_with_limits("\"abcd\"", 16, 4);
+        lexer.scan_tokens().unwrap();
+        assert_eq!(
+            lexer.tokens[0].literal,
+            Some(TokenLiteral::Str("abcd".to_string()))
+        );
+
+        let mut lexer = Lexer::new_with_limits("\n\"abcde\"", 16, 4);
+        let error = lexer.scan_tokens().unwrap_err();
+        assert_eq!(error.to_string(), "[line 2] Error: String too long.");
+        assert_eq!(error.location.unwrap().col, 1);
+    }
+}
@@ -1,5 +1,6 @@
 pub mod ast;
 pub mod lexer;
+pub mod natives;
 pub mod operators;
 pub mod parser;
 pub mod visitors;
@@ -0,0 +1,744 @@
+use std::rc::Rc;
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::interpreter::ast::{
+    Error, ErrorKind, Expr, Instance, List, Literal, Native, NativeFn, NativeFunction,
+};
+use crate::interpreter::operators::Operator;
+use crate::interpreter::visitors::interpreter::Interpreter;
+
+/// Arity of a native that takes any number of arguments and checks them itself.
+pub const VARIADIC: usize = usize::MAX;
+
+/// Functions implemented in Rust that every program gets as globals.
+pub fn natives() -> Vec<Native> {
+    vec![
+        Native {
+            name: "get_field".to_string(),
+            arity: 2,
+            function: NativeFunction(get_field),
+            receiver: None,
+        },
+        Native {
+            name: "set_field".to_string(),
+            arity: 3,
+            function: NativeFunction(set_field),
+            receiver: None,
+        },
+        Native {
+            name: "to_json".to_string(),
+            arity: 1,
+            function: NativeFunction(to_json),
+            receiver: None,
+        },
+        Native {
+            name: "globals".to_string(),
+            arity: 0,
+            function: NativeFunction(globals),
+            receiver: None,
+        },
+        Native {
+            name: "contains".to_string(),
+            arity: 2,
+            function: NativeFunction(contains),
+            receiver: None,
+        },
+        Native {
+            name: "starts_with".to_string(),
+            arity: 2,
+            function: NativeFunction(starts_with),
+            receiver: None,
+        },
+        Native {
+            name: "ends_with".to_string(),
+            arity: 2,
+            function: NativeFunction(ends_with),
+            receiver: None,
+        },
+        Native {
+            name: "index_of".to_string(),
+            arity: 2,
+            function: NativeFunction(index_of),
+            receiver: None,
+        },
+        Native {
+            name: "replace".to_string(),
+            arity: 3,
+            function: NativeFunction(replace),
+            receiver: None,
+        },
+        Native {
+            name: "split".to_string(),
+            arity: 2,
+            function: NativeFunction(split),
+            receiver: None,
+        },
+        Native {
+            name: "join".to_string(),
+            arity: 2,
+            function: NativeFunction(join),
+            receiver: None,
+        },
+        Native {
+            name: "keys".to_string(),
+            arity: 1,
+            function: NativeFunction(keys),
+            receiver: None,
+        },
+        Native {
+            name: "values".to_string(),
+            arity: 1,
+            function: NativeFunction(values),
+            receiver: None,
+        },
+        Native {
+            name: "has".to_string(),
+            arity: 2,
+            function: NativeFunction(has),
+            receiver: None,
+        },
+        Native {
+            name: "len".to_string(),
+            arity: 1,
+            function: NativeFunction(len),
+            receiver: None,
+        },
+        Native {
+            name: "type".to_string(),
+            arity: 1,
+            function: NativeFunction(type_of),
+            receiver: None,
+        },
+        Native {
+            name: "is".to_string(),
+            arity: 2,
+            function: NativeFunction(is),
+            receiver: None,
+        },
+        Native {
+            name: "deep_eq".to_string(),
+            arity: 2,
+            function: NativeFunction(deep_eq),
+            receiver: None,
+        },
+        Native {
+            name: "to_hex".to_string(),
+            arity: 1,
+            function: NativeFunction(to_hex),
+            receiver: None,
+        },
+        Native {
+            name: "to_bin".to_string(),
+            arity: 1,
+            function: NativeFunction(to_bin),
+            receiver: None,
+        },
+        Native {
+            name: "format".to_string(),
+            arity: VARIADIC,
+            function: NativeFunction(format),
+            receiver: None,
+        },
+        Native {
+            name: "print_err".to_string(),
+            arity: 1,
+            function: NativeFunction(print_err),
+            receiver: None,
+        },
+        Native {
+            name: "panic".to_string(),
+            arity: 1,
+            function: NativeFunction(panic),
+            receiver: None,
+        },
+        #[cfg(feature = "std")]
+        Native {
+            name: "clock".to_string(),
+            arity: 0,
+            function: NativeFunction(clock),
+            receiver: None,
+        },
+        #[cfg(feature = "std")]
+        Native {
+            name: "env".to_string(),
+            arity: 1,
+            function: NativeFunction(env),
+            receiver: None,
+        },
+    ]
+}
+
+/// Built-in methods on numbers and strings, e.g. `(3.7).floor()` or
+/// `"abc".upper()`, bound to `receiver`. `None` when there is no such method.
+pub fn literal_method(receiver: &Literal, name: &str) -> Option<Native> {
+    let function: NativeFn = match (receiver, name) {
+        (Literal::Number(_), "floor") => floor,
+        (Literal::Number(_), "abs") => abs,
+        (Literal::Str(_), "length") => length,
+        (Literal::Str(_), "upper") => upper,
+        (Literal::Str(_), "lower") => lower,
+        _ => return None,
+    };
+    Some(Native {
+        name: name.to_string(),
+        arity: 0,
+        function: NativeFunction(function),
+        receiver: Some(Box::new(Expr::Literal(receiver.clone()))),
+    })
+}
+
+fn number_receiver(args: &[Expr]) -> f64 {
+    match &args[0] {
+        Expr::Literal(Literal::Number(n)) => n.into_inner(),
+        _ => unreachable!("number methods are only bound to numbers"),
+    }
+}
+
+fn string_receiver(args: &[Expr]) -> &str {
+    match &args[0] {
+        Expr::Literal(Literal::Str(s)) => s.as_str(),
+        _ => unreachable!("string methods are only bound to strings"),
+    }
+}
+
+fn floor(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let n = number_receiver(&args).floor();
+    Ok(Expr::Literal(Literal::Number(n.into())))
+}
+
+fn abs(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let n = number_receiver(&args).abs();
+    Ok(Expr::Literal(Literal::Number(n.into())))
+}
+
+/// `s.length()` counts characters, not bytes.
+fn length(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let n = string_receiver(&args).chars().count() as f64;
+    Ok(Expr::Literal(Literal::Number(n.into())))
+}
+
+fn upper(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let s = string_receiver(&args).to_uppercase();
+    Ok(Expr::Literal(Literal::Str(s)))
+}
+
+fn lower(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let s = string_receiver(&args).to_lowercase();
+    Ok(Expr::Literal(Literal::Str(s)))
+}
+
+fn instance_and_name(args: &[Expr]) -> Result<(&Instance, &str), Error> {
+    let instance = match &args[0] {
+        Expr::Instance(instance) => instance,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                "Only instances have fields.".to_string(),
+            ))
+        }
+    };
+    match &args[1] {
+        Expr::Literal(Literal::Str(name)) => Ok((instance, name.as_str())),
+        _ => Err(Error::new(
+            ErrorKind::Type,
+            "Field name must be a string.".to_string(),
+        )),
+    }
+}
+
+/// `get_field(instance, name)` reads a property whose name is only known at runtime.
+fn get_field(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let (instance, name) = instance_and_name(&args)?;
+    instance.get_field(name)
+}
+
+/// `set_field(instance, name, value)` writes a property and returns the value.
+fn set_field(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let (instance, name) = instance_and_name(&args)?;
+    instance.set_field(name, args[2].clone());
+    Ok(args[2].clone())
+}
+
+fn instance_arg(args: &[Expr]) -> Result<&Instance, Error> {
+    match &args[0] {
+        Expr::Instance(instance) => Ok(instance),
+        _ => Err(Error::new(
+            ErrorKind::Type,
+            "Only instances have fields.".to_string(),
+        )),
+    }
+}
+
+/// `keys(instance)` lists the names of an instance's fields, sorted.
+fn keys(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let names = instance_arg(&args)?
+        .fields
+        .borrow()
+        .keys()
+        .map(|name| Expr::Literal(Literal::Str(name.clone())))
+        .collect();
+    Ok(Expr::List(List::new(names)))
+}
+
+/// `values(instance)` lists an instance's field values, in the order `keys`
+/// names them.
+fn values(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let values = instance_arg(&args)?.fields.borrow().values().cloned().collect();
+    Ok(Expr::List(List::new(values)))
+}
+
+/// `has(instance, name)` tells whether the instance has a field `name`.
+/// Methods don't count.
+fn has(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let (instance, name) = instance_and_name(&args)?;
+    let found = instance.fields.borrow().contains_key(name);
+    Ok(Expr::Literal(Literal::Bool(found)))
+}
+
+/// `globals()` lists the names of the global variables the program defined,
+/// in sorted order. The names natives are registered under are left out,
+/// whatever they hold now.
+fn globals(interpreter: &mut Interpreter, _args: Vec<Expr>) -> Result<Expr, Error> {
+    let natives: Vec<String> = natives().into_iter().map(|native| native.name).collect();
+    let globals = interpreter.globals();
+    let names = globals
+        .borrow()
+        .symbols()
+        .filter(|(name, _)| !natives.contains(name))
+        .map(|(name, _)| Expr::Literal(Literal::Str(name.clone())))
+        .collect();
+    Ok(Expr::List(List::new(names)))
+}
+
+fn string_arg(args: &[Expr], index: usize) -> Result<&str, Error> {
+    match &args[index] {
+        Expr::Literal(Literal::Str(s)) => Ok(s.as_str()),
+        _ => Err(Error::new(
+            ErrorKind::Type,
+            "Arguments must be strings.".to_string(),
+        )),
+    }
+}
+
+/// The argument at `index` as an integer. Numbers with a fractional part, or
+/// too large to be held exactly, are rejected.
+fn integer_arg(args: &[Expr], index: usize) -> Result<i64, Error> {
+    match &args[index] {
+        Expr::Literal(Literal::Number(n))
+            if n.fract() == 0.0 && n.abs() <= 9_007_199_254_740_992.0 =>
+        {
+            Ok(n.into_inner() as i64)
+        }
+        _ => Err(Error::new(
+            ErrorKind::Type,
+            "Argument must be an integer.".to_string(),
+        )),
+    }
+}
+
+/// `to_hex(n)` writes an integer in base 16, lowercase, e.g. `"ff"` or `"-ff"`.
+fn to_hex(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let n = integer_arg(&args, 0)?;
+    let sign = if n < 0 { "-" } else { "" };
+    let digits = format!("{}{:x}", sign, n.unsigned_abs());
+    Ok(Expr::Literal(Literal::Str(digits)))
+}
+
+/// `to_bin(n)` writes an integer in base 2, e.g. `"1010"`.
+fn to_bin(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let n = integer_arg(&args, 0)?;
+    let sign = if n < 0 { "-" } else { "" };
+    let digits = format!("{}{:b}", sign, n.unsigned_abs());
+    Ok(Expr::Literal(Literal::Str(digits)))
+}
+
+/// `contains(haystack, needle)` tells whether `needle` occurs in `haystack`.
+fn contains(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let found = string_arg(&args, 0)?.contains(string_arg(&args, 1)?);
+    Ok(Expr::Literal(Literal::Bool(found)))
+}
+
+/// `print_err(value)` prints like `print`, but to the interpreter's stderr sink.
+fn print_err(interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let value = args.into_iter().next().unwrap();
+    interpreter.print_err(value)?;
+    Ok(Expr::Literal(Literal::Nil))
+}
+
+/// `panic(message)` aborts with a runtime error carrying `message`, which a
+/// surrounding `try` can catch. Other values become the message the way
+/// they read inside a string.
+fn panic(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    Err(Error::new(ErrorKind::Runtime, stringify(&args[0])))
+}
+
+/// `clock()` is the number of seconds since the Unix epoch.
+#[cfg(feature = "std")]
+fn clock(_interpreter: &mut Interpreter, _args: Vec<Expr>) -> Result<Expr, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(Expr::Literal(Literal::Number(now.as_secs_f64().into())))
+}
+
+/// `env(name)` is the environment variable `name`, or nil when it is unset.
+#[cfg(feature = "std")]
+fn env(interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    Ok(match interpreter.env_var(string_arg(&args, 0)?) {
+        Some(value) => Expr::Literal(Literal::Str(value)),
+        None => Expr::Literal(Literal::Nil),
+    })
+}
+
+/// `starts_with(s, prefix)` tells whether `s` begins with `prefix`.
+fn starts_with(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let found = string_arg(&args, 0)?.starts_with(string_arg(&args, 1)?);
+    Ok(Expr::Literal(Literal::Bool(found)))
+}
+
+/// `ends_with(s, suffix)` tells whether `s` finishes with `suffix`.
+fn ends_with(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let found = string_arg(&args, 0)?.ends_with(string_arg(&args, 1)?);
+    Ok(Expr::Literal(Literal::Bool(found)))
+}
+
+/// `index_of(haystack, needle)` is the character index of the first
+/// occurrence of `needle`, or -1 when there is none.
+fn index_of(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let haystack = string_arg(&args, 0)?;
+    let index = match haystack.find(string_arg(&args, 1)?) {
+        Some(byte) => haystack[..byte].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Expr::Literal(Literal::Number(index.into())))
+}
+
+/// `replace(s, from, to)` replaces every occurrence of `from`. An empty
+/// `from` matches nothing, so `s` comes back unchanged.
+fn replace(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let s = string_arg(&args, 0)?;
+    let from = string_arg(&args, 1)?;
+    let to = string_arg(&args, 2)?;
+    let replaced = if from.is_empty() {
+        s.to_string()
+    } else {
+        s.replace(from, to)
+    };
+    Ok(Expr::Literal(Literal::Str(replaced)))
+}
+
+/// `split(s, sep)` cuts `s` at every `sep`. An empty `sep` splits `s` into
+/// its characters, and an empty `s` gives an empty list.
+fn split(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let s = string_arg(&args, 0)?;
+    let sep = string_arg(&args, 1)?;
+    let parts: Vec<String> = if s.is_empty() {
+        Vec::new()
+    } else if sep.is_empty() {
+        s.chars().map(String::from).collect()
+    } else {
+        s.split(sep).map(String::from).collect()
+    };
+    let elements = parts
+        .into_iter()
+        .map(|part| Expr::Literal(Literal::Str(part)))
+        .collect();
+    Ok(Expr::List(List::new(elements)))
+}
+
+/// `join(list, sep)` concatenates a list of strings with `sep` between them.
+fn join(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let elements = match &args[0] {
+        Expr::List(List { elements }) => elements.clone(),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                "Can only join a list.".to_string(),
+            ))
+        }
+    };
+    let sep = string_arg(&args, 1)?;
+    let parts = elements
+        .borrow()
+        .iter()
+        .map(|element| match element {
+            Expr::Literal(Literal::Str(s)) => Ok(s.clone()),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "List elements must be strings.".to_string(),
+            )),
+        })
+        .collect::<Result<Vec<String>, Error>>()?;
+    Ok(Expr::Literal(Literal::Str(parts.join(sep))))
+}
+
+/// `len(value)` is the number of characters in a string or of elements in
+/// a list.
+fn len(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let n = match &args[0] {
+        Expr::Literal(Literal::Str(s)) => s.chars().count(),
+        Expr::List(List { elements }) => elements.borrow().len(),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                "Can only take the length of a string or a list.".to_string(),
+            ))
+        }
+    };
+    Ok(Expr::Literal(Literal::Number((n as f64).into())))
+}
+
+/// `type(value)` names the kind of value it is given, e.g. `"number"`.
+fn type_of(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let name = match &args[0] {
+        Expr::Literal(Literal::Number(_)) => "number",
+        Expr::Literal(Literal::Str(_)) => "string",
+        Expr::Literal(Literal::Bool(_)) => "bool",
+        Expr::Literal(Literal::Nil) => "nil",
+        Expr::Function(_) | Expr::Native(_) => "function",
+        Expr::Class(_) => "class",
+        Expr::Instance(_) => "instance",
+        Expr::List(_) => "list",
+        _ => return Err(Error::new(ErrorKind::Runtime, "Invalid value".to_string())),
+    };
+    Ok(Expr::Literal(Literal::Str(name.to_string())))
+}
+
+/// `is(value, class)` tells whether `value` is an instance of `class` or of
+/// one of its subclasses. Classes are told apart by identity, as `==` does.
+fn is(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let target = match &args[1] {
+        Expr::Class(class) => class,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                "Second argument must be a class.".to_string(),
+            ))
+        }
+    };
+    let mut class = match &args[0] {
+        Expr::Instance(Instance { class, .. }) => Some(class.as_ref()),
+        _ => None,
+    };
+    while let Some(current) = class {
+        if Rc::ptr_eq(&current.methods, &target.methods) {
+            return Ok(Expr::Literal(Literal::Bool(true)));
+        }
+        class = match current.superclass.as_deref() {
+            Some(Expr::Class(superclass)) => Some(superclass),
+            _ => None,
+        };
+    }
+    Ok(Expr::Literal(Literal::Bool(false)))
+}
+
+/// `deep_eq(a, b)` compares lists element by element and instances of the
+/// same class field by field, where `==` only compares their identity.
+/// Everything else is compared as `==` does, except that values of
+/// different kinds are simply unequal.
+fn deep_eq(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let equal = deep_equal(&args[0], &args[1], &mut Vec::new());
+    Ok(Expr::Literal(Literal::Bool(equal)))
+}
+
+/// `comparing` holds the pairs of lists and instances being compared further
+/// up, so a structure that contains itself is taken as equal when the
+/// comparison comes back around to the same pair.
+fn deep_equal(left: &Expr, right: &Expr, comparing: &mut Vec<(usize, usize)>) -> bool {
+    match (left, right) {
+        (Expr::List(List { elements: l }), Expr::List(List { elements: r })) => {
+            let pair = (l.as_ptr() as usize, r.as_ptr() as usize);
+            if comparing.contains(&pair) {
+                return true;
+            }
+            comparing.push(pair);
+            let (l, r) = (l.borrow(), r.borrow());
+            let equal = l.len() == r.len()
+                && l.iter().zip(r.iter()).all(|(l, r)| deep_equal(l, r, comparing));
+            comparing.pop();
+            equal
+        }
+        (Expr::Instance(l), Expr::Instance(r)) => {
+            let pair = (l.fields.as_ptr() as usize, r.fields.as_ptr() as usize);
+            if comparing.contains(&pair) {
+                return true;
+            }
+            comparing.push(pair);
+            let (fields_l, fields_r) = (l.fields.borrow(), r.fields.borrow());
+            let equal = Rc::ptr_eq(&l.class.methods, &r.class.methods)
+                && fields_l.len() == fields_r.len()
+                && fields_l.iter().zip(fields_r.iter()).all(|((name_l, l), (name_r, r))| {
+                    name_l == name_r && deep_equal(l, r, comparing)
+                });
+            comparing.pop();
+            equal
+        }
+        _ => matches!(
+            Operator::EqualEqual.binary(left.clone(), right.clone()),
+            Ok(Some(Expr::Literal(Literal::Bool(true))))
+        ),
+    }
+}
+
+/// `format(fmt, ...)` fills each `{}` in `fmt` with the next argument, in
+/// order. `{{` and `}}` stand for literal braces.
+fn format(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    if args.is_empty() {
+        return Err(Error::arity("format", 1, 0));
+    }
+    let fmt = string_arg(&args, 0)?;
+    let mut values = args[1..].iter();
+    let mut placeholders = 0;
+    let mut out = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                out.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                placeholders += 1;
+                if let Some(value) = values.next() {
+                    out.push_str(&stringify(value));
+                }
+            }
+            ('{', _) | ('}', _) => {
+                return Err(Error::new(
+                    ErrorKind::Runtime,
+                    format!("Unmatched '{}' in format string.", c),
+                ))
+            }
+            _ => out.push(c),
+        }
+    }
+    if placeholders != args.len() - 1 {
+        return Err(Error::new(
+            ErrorKind::Arity,
+            format!(
+                "Format string has {} placeholders but got {} arguments.",
+                placeholders,
+                args.len() - 1
+            ),
+        ));
+    }
+    Ok(Expr::Literal(Literal::Str(out)))
+}
+
+/// How a value reads inside a string: numbers without a trailing `.0` when
+/// integral, strings without quotes, functions and classes by name.
+pub fn stringify(value: &Expr) -> String {
+    let mut out = String::new();
+    write_value(value, &mut out, &mut Vec::new());
+    out
+}
+
+/// `visiting` holds the lists being written further up, so a list that
+/// contains itself is cut short as `[...]`.
+fn write_value(value: &Expr, out: &mut String, visiting: &mut Vec<usize>) {
+    match value {
+        Expr::Literal(Literal::Nil) => out.push_str("nil"),
+        Expr::Literal(Literal::Bool(b)) => out.push_str(&b.to_string()),
+        Expr::Literal(Literal::Number(n)) => out.push_str(&n.to_string()),
+        Expr::Literal(Literal::Str(s)) => out.push_str(s),
+        Expr::List(List { elements }) => {
+            let id = elements.as_ptr() as usize;
+            if visiting.contains(&id) {
+                out.push_str("[...]");
+                return;
+            }
+            visiting.push(id);
+            out.push('[');
+            for (i, element) in elements.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(element, out, visiting);
+            }
+            out.push(']');
+            visiting.pop();
+        }
+        Expr::Function(function) => out.push_str(&format!("<fn {}>", function.name)),
+        Expr::Native(_) => out.push_str("<native fn>"),
+        Expr::Class(class) => out.push_str(&class.name),
+        Expr::Instance(Instance { class, .. }) => {
+            out.push_str(&format!("{} instance", class.name))
+        }
+        _ => out.push_str(&format!("{:?}", value)),
+    }
+}
+
+/// `to_json(value)` serializes a value to a JSON string. Instances become
+/// objects of their fields; functions and classes have no data so they become
+/// `null`. Values that contain themselves are rejected.
+fn to_json(_interpreter: &mut Interpreter, args: Vec<Expr>) -> Result<Expr, Error> {
+    let mut out = String::new();
+    write_json(&args[0], &mut out, &mut Vec::new())?;
+    Ok(Expr::Literal(Literal::Str(out)))
+}
+
+fn write_json(value: &Expr, out: &mut String, visiting: &mut Vec<usize>) -> Result<(), Error> {
+    match value {
+        Expr::Literal(Literal::Nil) => out.push_str("null"),
+        Expr::Literal(Literal::Bool(b)) => out.push_str(&b.to_string()),
+        Expr::Literal(Literal::Number(n)) if n.is_finite() => out.push_str(&n.to_string()),
+        Expr::Literal(Literal::Str(s)) => write_json_string(s, out),
+        Expr::List(List { elements }) => {
+            enter(visiting, elements.as_ptr() as usize)?;
+            out.push('[');
+            for (i, element) in elements.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(element, out, visiting)?;
+            }
+            out.push(']');
+            visiting.pop();
+        }
+        Expr::Instance(Instance { fields, .. }) => {
+            enter(visiting, fields.as_ptr() as usize)?;
+            out.push('{');
+            for (i, (name, field)) in fields.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(name, out);
+                out.push(':');
+                write_json(field, out, visiting)?;
+            }
+            out.push('}');
+            visiting.pop();
+        }
+        _ => out.push_str("null"),
+    }
+    Ok(())
+}
+
+fn enter(visiting: &mut Vec<usize>, id: usize) -> Result<(), Error> {
+    if visiting.contains(&id) {
+        return Err(Error::new(
+            ErrorKind::Runtime,
+            "Cannot serialize a value that contains itself.".to_string(),
+        ));
+    }
+    visiting.push(id);
+    Ok(())
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
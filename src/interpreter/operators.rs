@@ -1,8 +1,15 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
 use crate::interpreter::{
-    ast::{Class, Error, Expr, Function, Literal},
+    ast::{Error, ErrorKind, Expr, Function, List, Literal},
     lexer::{Token, TokenType},
 };
 
+/// Upper bound on the number of elements a range may produce, as ranges
+/// are built eagerly.
+pub const MAX_RANGE_LEN: i64 = 1 << 20;
+
 #[derive(Debug, Clone, PartialEq, Ord, PartialOrd, Eq, Hash)]
 pub enum Operator {
     Bang,
@@ -14,11 +21,47 @@ pub enum Operator {
     Less,
     LessEqual,
     Minus,
+    Percent,
     Plus,
     Slash,
     Star,
+    Range,
     Or,
     And,
+    Coalesce,
+}
+
+/// Whether both functions close over the same environment. Methods bound
+/// to different instances differ here.
+fn same_context(left: &Function, right: &Function) -> bool {
+    match (&left.context, &right.context) {
+        (Some(l), Some(r)) => Rc::ptr_eq(l, r),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Orders two lists by their first differing element, a list that is a
+/// prefix of the other coming first.
+fn compare_lists(left: &List, right: &List) -> Result<Ordering, Error> {
+    let (left, right) = (left.elements.borrow(), right.elements.borrow());
+    for pair in left.iter().zip(right.iter()) {
+        let ordering = match pair {
+            (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => l.cmp(r),
+            (Expr::Literal(Literal::Str(l)), Expr::Literal(Literal::Str(r))) => l.cmp(r),
+            (Expr::List(l), Expr::List(r)) => compare_lists(l, r)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Type,
+                    "Can only compare lists of numbers, strings or lists.".to_string(),
+                ))
+            }
+        };
+        if ordering != Ordering::Equal {
+            return Ok(ordering);
+        }
+    }
+    Ok(left.len().cmp(&right.len()))
 }
 
 impl Operator {
@@ -28,6 +71,7 @@ impl Operator {
             TokenType::Plus => Operator::Plus,
             TokenType::Slash => Operator::Slash,
             TokenType::Star => Operator::Star,
+            TokenType::Percent => Operator::Percent,
             TokenType::Bang => Operator::Bang,
             TokenType::BangEqual => Operator::BangEqual,
             TokenType::Equal => Operator::Equal,
@@ -36,31 +80,52 @@ impl Operator {
             TokenType::GreaterEqual => Operator::GreaterEqual,
             TokenType::Less => Operator::Less,
             TokenType::LessEqual => Operator::LessEqual,
+            TokenType::DotDot => Operator::Range,
             TokenType::Or => Operator::Or,
             TokenType::And => Operator::And,
+            TokenType::QuestionQuestion => Operator::Coalesce,
             _ => panic!("Unknown binary operation"),
         }
     }
 
+    /// Name of the method a class can define to overload this operator.
+    pub fn method_name(&self) -> Option<&'static str> {
+        match self {
+            Operator::Plus => Some("add"),
+            Operator::EqualEqual | Operator::BangEqual => Some("equals"),
+            Operator::Less => Some("less"),
+            _ => None,
+        }
+    }
+
     pub fn unary(self, right: Expr) -> Result<Option<Expr>, Error> {
         match self {
             Operator::Minus => self.minus(right),
             Operator::Bang => self.negation(right),
-            _ => Err(Error::new("Unknown unary operation".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Runtime,
+                "Unknown unary operation".to_string(),
+            )),
         }
     }
 
     fn minus(self, expr: Expr) -> Result<Option<Expr>, Error> {
         match expr {
             Expr::Literal(Literal::Number(n)) => Ok(Some(Expr::Literal(Literal::Number(-n)))),
-            _ => Err(Error::new("Operand must be a number.".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operand must be a number.".to_string(),
+            )),
         }
     }
 
     fn negation(self, expr: Expr) -> Result<Option<Expr>, Error> {
         match expr {
             Expr::Literal(Literal::Bool(b)) => Ok(Some(Expr::Literal(Literal::Bool(!b)))),
-            _ => Err(Error::new("Operand must be a bool".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operand must be a bool".to_string(),
+            )),
         }
     }
 
@@ -70,12 +135,14 @@ impl Operator {
             Operator::Minus => self.subtraction(left, right),
             Operator::Star => self.multiplication(left, right),
             Operator::Slash => self.division(left, right),
+            Operator::Percent => self.modulo(left, right),
             Operator::EqualEqual => self.equal_equal(left, right),
             Operator::BangEqual => self.bang_equal(left, right),
             Operator::Greater => self.greater_than(left, right),
             Operator::GreaterEqual => self.greater_than_or_equal(left, right),
             Operator::Less => self.less_than(left, right),
             Operator::LessEqual => self.less_than_or_equal(left, right),
+            Operator::Range => self.range(left, right),
             _ => panic!("Unknown binary operator"),
         }
     }
@@ -84,7 +151,7 @@ impl Operator {
         match self {
             Operator::Or => self.logical_or(left, right),
             Operator::And => self.logical_and(left, right),
-            _ => Err(Error::new("Unknown logical operator".to_string())),
+            _ => Err(Error::new(ErrorKind::Runtime, "Unknown logical operator".to_string())),
         }
     }
 
@@ -105,6 +172,7 @@ impl Operator {
                 Ok(Some(Expr::Literal(Literal::Str(l + &r))))
             }
             _ => Err(Error::new(
+                ErrorKind::Type,
                 "Operands must be two numbers or two strings.".to_string(),
             )),
         }
@@ -115,7 +183,10 @@ impl Operator {
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Number(l - r))))
             }
-            _ => Err(Error::new("Operands must be numbers.".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be numbers.".to_string(),
+            )),
         }
     }
 
@@ -124,7 +195,10 @@ impl Operator {
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Number(l * r))))
             }
-            _ => Err(Error::new("Operands must be numbers.".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be numbers.".to_string(),
+            )),
         }
     }
 
@@ -133,69 +207,92 @@ impl Operator {
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Number(l / r))))
             }
-            _ => Err(Error::new("Operands must be numbers.".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be numbers.".to_string(),
+            )),
+        }
+    }
+
+    fn modulo(self, left: Expr, right: Expr) -> Result<Option<Expr>, Error> {
+        match (left, right) {
+            (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
+                Ok(Some(Expr::Literal(Literal::Number(l % r))))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be numbers.".to_string(),
+            )),
         }
     }
 
     fn equal_equal(self, left: Expr, right: Expr) -> Result<Option<Expr>, Error> {
         match (left, right) {
-            (
-                Expr::Function(Function {
-                    name: name1,
-                    parameters: parameters1,
-                    ..
-                }),
-                Expr::Function(Function {
-                    name: name2,
-                    parameters: parameters2,
-                    ..
-                }),
-            ) => Ok(Some(Expr::Literal(Literal::Bool(
-                name1 == name2 && parameters1 == parameters2,
+            // A function is only equal to copies of itself: the same
+            // evaluation of its declaration, closing over the same scope.
+            (Expr::Function(l), Expr::Function(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                Rc::ptr_eq(&l.body, &r.body) && same_context(&l, &r),
+            )))),
+            (Expr::Class(l), Expr::Class(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                Rc::ptr_eq(&l.methods, &r.methods),
             )))),
-            (Expr::Class(Class { name: name1, .. }), Expr::Class(Class { name: name2, .. })) => {
-                Ok(Some(Expr::Literal(Literal::Bool(name1 == name2))))
-            }
             (Expr::Literal(Literal::Bool(l)), Expr::Literal(Literal::Bool(r))) => {
                 Ok(Some(Expr::Literal(Literal::Bool(l == r))))
             }
+            // `OrderedFloat`'s `Eq`, `Ord` and `Hash` all treat `-0` and `0` as
+            // the same number, so `-0 == 0` both here and for internal map keys.
+            // Unlike IEEE floats it also makes `NaN == NaN`.
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Bool(l == r))))
             }
             (Expr::Literal(Literal::Str(l)), Expr::Literal(Literal::Str(r))) => {
                 Ok(Some(Expr::Literal(Literal::Bool(l == r))))
             }
+            // Lists and instances, including enum members, are only equal to
+            // themselves; `deep_eq` compares their contents.
+            (Expr::List(l), Expr::List(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                Rc::ptr_eq(&l.elements, &r.elements),
+            )))),
+            (Expr::Instance(l), Expr::Instance(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                Rc::ptr_eq(&l.fields, &r.fields),
+            )))),
             (Expr::Literal(Literal::Nil), Expr::Literal(Literal::Nil)) => {
                 Ok(Some(Expr::Literal(Literal::Bool(true))))
             }
             (Expr::Literal(Literal::Nil), _) => Ok(Some(Expr::Literal(Literal::Bool(false)))),
             (_, Expr::Literal(Literal::Nil)) => Ok(Some(Expr::Literal(Literal::Bool(false)))),
 
-            _ => Err(Error::new("Operands must be of the same type".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be of the same type".to_string(),
+            )),
         }
     }
 
     fn bang_equal(self, left: Expr, right: Expr) -> Result<Option<Expr>, Error> {
-        match (left, right) {
-            (Expr::Literal(Literal::Bool(l)), Expr::Literal(Literal::Bool(r))) => {
-                Ok(Some(Expr::Literal(Literal::Bool(l != r))))
-            }
-            (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
-                Ok(Some(Expr::Literal(Literal::Bool(l != r))))
-            }
-            (Expr::Literal(Literal::Str(l)), Expr::Literal(Literal::Str(r))) => {
-                Ok(Some(Expr::Literal(Literal::Bool(l != r))))
-            }
-            _ => Err(Error::new("Operands must be of the same type".to_string())),
+        match Operator::EqualEqual.equal_equal(left, right)? {
+            Some(Expr::Literal(Literal::Bool(b))) => Ok(Some(Expr::Literal(Literal::Bool(!b)))),
+            other => Ok(other),
         }
     }
 
+    /// The comparisons order numbers numerically, strings lexicographically
+    /// by bytes and lists element by element; mixing kinds is an error.
     fn greater_than(self, left: Expr, right: Expr) -> Result<Option<Expr>, Error> {
         match (left, right) {
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Bool(l > r))))
             }
-            _ => Err(Error::new("Operands must be numbers.".to_string())),
+            (Expr::Literal(Literal::Str(l)), Expr::Literal(Literal::Str(r))) => {
+                Ok(Some(Expr::Literal(Literal::Bool(l > r))))
+            }
+            (Expr::List(l), Expr::List(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                compare_lists(&l, &r)? == Ordering::Greater,
+            )))),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be two numbers or two strings.".to_string(),
+            )),
         }
     }
 
@@ -204,7 +301,16 @@ impl Operator {
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Bool(l >= r))))
             }
-            _ => Err(Error::new("Operands must be numbers.".to_string())),
+            (Expr::Literal(Literal::Str(l)), Expr::Literal(Literal::Str(r))) => {
+                Ok(Some(Expr::Literal(Literal::Bool(l >= r))))
+            }
+            (Expr::List(l), Expr::List(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                compare_lists(&l, &r)? != Ordering::Less,
+            )))),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be two numbers or two strings.".to_string(),
+            )),
         }
     }
 
@@ -213,7 +319,16 @@ impl Operator {
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Bool(l < r))))
             }
-            _ => Err(Error::new("Operands must be numbers.".to_string())),
+            (Expr::Literal(Literal::Str(l)), Expr::Literal(Literal::Str(r))) => {
+                Ok(Some(Expr::Literal(Literal::Bool(l < r))))
+            }
+            (Expr::List(l), Expr::List(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                compare_lists(&l, &r)? == Ordering::Less,
+            )))),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be two numbers or two strings.".to_string(),
+            )),
         }
     }
 
@@ -222,7 +337,41 @@ impl Operator {
             (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r))) => {
                 Ok(Some(Expr::Literal(Literal::Bool(l <= r))))
             }
-            _ => Err(Error::new("Operands must be numbers.".to_string())),
+            (Expr::Literal(Literal::Str(l)), Expr::Literal(Literal::Str(r))) => {
+                Ok(Some(Expr::Literal(Literal::Bool(l <= r))))
+            }
+            (Expr::List(l), Expr::List(r)) => Ok(Some(Expr::Literal(Literal::Bool(
+                compare_lists(&l, &r)? != Ordering::Greater,
+            )))),
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Operands must be two numbers or two strings.".to_string(),
+            )),
+        }
+    }
+
+    /// Half-open range `a..b`, evaluated eagerly into a list of numbers.
+    fn range(self, left: Expr, right: Expr) -> Result<Option<Expr>, Error> {
+        match (left, right) {
+            (Expr::Literal(Literal::Number(l)), Expr::Literal(Literal::Number(r)))
+                if l.fract() == 0.0 && r.fract() == 0.0 =>
+            {
+                let (start, end) = (l.0 as i64, r.0 as i64);
+                if end.saturating_sub(start) > MAX_RANGE_LEN {
+                    return Err(Error::new(
+                        ErrorKind::Runtime,
+                        format!("Range is longer than {} elements.", MAX_RANGE_LEN),
+                    ));
+                }
+                let elements = (start..end)
+                    .map(|n| Expr::Literal(Literal::Number((n as f64).into())))
+                    .collect();
+                Ok(Some(Expr::List(List::new(elements))))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Range endpoints must be integers.".to_string(),
+            )),
         }
     }
 }
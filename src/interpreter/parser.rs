@@ -1,33 +1,88 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use crate::interpreter::ast::{
-    Assign, Binary, Block, Call, ClassDecl, Error, Expr, Expression, FunDecl, Get, Grouping, If,
-    Literal, Logical, Print, Return, Set, Stmt, Super, This, Unary, Var, VarDecl, While,
+    Assert, Assign, Binary, Block, Break, Call, ClassDecl, Continue, Destructure, EnumDecl, Error,
+    ErrorKind, Expr, Expression, FunDecl, Get, Grouping, If, Index, IndexSet, Invalid, InvalidExpr,
+    Lambda, ListLiteral, Literal, Logical, Print, Return, Set, Stmt, Super, Ternary, This, Try,
+    Unary, Var, VarDecl, While,
 };
 use crate::interpreter::lexer::{Token, TokenLiteral, TokenType};
 use crate::interpreter::operators::Operator;
 
-#[derive(Debug, Clone)]
+/// Default upper bound on how deeply expressions may nest, so an adversarial
+/// program fails to parse instead of overflowing the stack here or later
+/// while the tree is walked.
+pub const MAX_NESTING_DEPTH: usize = 64;
+
+/// Source of expression ids, shared by every parser so trees parsed
+/// separately, such as REPL lines, never reuse one.
+static NEXT_EXPR_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug)]
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    depth: usize,
+    max_depth: usize,
+    /// Set by `parse_partial`, which recovers from malformed elements.
+    partial: bool,
+    /// Errors recovered from while parsing partially.
+    errors: Vec<Error>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+        Parser::new_with_max_depth(tokens, MAX_NESTING_DEPTH)
+    }
+
+    pub fn new_with_max_depth(tokens: Vec<Token>, max_depth: usize) -> Parser {
+        Parser {
+            tokens,
+            current: 0,
+            depth: 0,
+            max_depth,
+            partial: false,
+            errors: vec![],
+        }
     }
 
     pub fn parse(&mut self) -> Vec<Result<Stmt, Error>> {
         self.program()
     }
 
+    /// Best-effort parse for tooling: a declaration that fails to parse is
+    /// replaced by a `Stmt::Invalid` placeholder and parsing resumes at the
+    /// next statement boundary, so the rest of the program is still available.
+    /// A malformed call argument or list element becomes an `Expr::InvalidExpr`
+    /// instead, keeping the statement around it.
+    pub fn parse_partial(&mut self) -> (Vec<Stmt>, Vec<Error>) {
+        self.partial = true;
+        let mut program = vec![];
+        while !self.is_at_end() {
+            match self.declaration() {
+                Ok(stmt) => program.push(stmt),
+                Err(error) => {
+                    program.push(Stmt::Invalid(Invalid {
+                        msg: error.msg.clone(),
+                    }));
+                    self.errors.push(error);
+                    self.synchronize();
+                }
+            }
+        }
+
+        (program, std::mem::take(&mut self.errors))
+    }
+
     fn program(&mut self) -> Vec<Result<Stmt, Error>> {
         let mut program = vec![];
         while !self.is_at_end() {
             let stmt = self.declaration();
-            match stmt {
-                Ok(stmt) => program.push(Ok(stmt)),
-                Err(Error { msg: message }) => return vec![Err(Error { msg: message })],
+            if stmt.is_err() {
+                self.synchronize();
             }
+            program.push(stmt);
         }
 
         program
@@ -36,34 +91,113 @@ impl Parser {
     fn declaration(&mut self) -> Result<Stmt, Error> {
         if self.ismatch(&[TokenType::Var])? {
             self.var_decl()
+        } else if self.ismatch(&[TokenType::Const])? {
+            self.const_decl()
         } else if self.ismatch(&[TokenType::Fun])? {
             self.fun_decl("function")
         } else if self.ismatch(&[TokenType::Class])? {
             self.class_decl()
+        } else if self.ismatch(&[TokenType::Enum])? {
+            self.enum_decl()
         } else {
             self.statement()
         }
-        //TODO catch error & synchronize
+    }
+
+    fn synchronize(&mut self) {
+        let _ = self.advance();
+
+        while !self.is_at_end() {
+            if let Ok(Token {
+                token_type: TokenType::Semicolon,
+                ..
+            }) = self.previous()
+            {
+                return;
+            }
+
+            match self.peek().token_type {
+                TokenType::Class
+                | TokenType::Enum
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::Const
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Try
+                | TokenType::Assert => return,
+                _ => {}
+            }
+
+            let _ = self.advance();
+        }
     }
 
     fn var_decl(&mut self) -> Result<Stmt, Error> {
+        let decl = self.var_binding()?;
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(decl)
+    }
+
+    /// `name`, `name = value` or `a, b = value`, without the `;` that ends a
+    /// declaration.
+    fn var_binding(&mut self) -> Result<Stmt, Error> {
         let name = self
             .consume(TokenType::Identifier, "Expect variable name.")?
             .lexeme
             .to_owned();
 
+        if self.check(&TokenType::Comma) {
+            let mut names = vec![name];
+            while self.ismatch(&[TokenType::Comma])? {
+                let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
+                names.push(name.lexeme.to_owned());
+            }
+            self.consume(TokenType::Equal, "Expect '=' after variable names.")?;
+            let value = self.expression()?;
+            return Ok(Stmt::Destructure(Destructure {
+                names,
+                expr: Box::new(value),
+            }));
+        }
+
         let mut value = Expr::Literal(Literal::Nil);
         if self.ismatch(&[TokenType::Equal])? {
             value = self.expression()?;
         }
 
+        Ok(Stmt::VarDecl(VarDecl {
+            name,
+            expr: Box::new(value),
+            constant: false,
+        }))
+    }
+
+    fn const_decl(&mut self) -> Result<Stmt, Error> {
+        let name = self
+            .consume(TokenType::Identifier, "Expect constant name.")?
+            .lexeme
+            .to_owned();
+
+        self.consume(TokenType::Equal, "Expect '=' after constant name.")?;
+        let value = self.expression()?;
+
         self.consume(
             TokenType::Semicolon,
-            "Expect ';' after variable declaration.",
+            "Expect ';' after constant declaration.",
         )?;
         Ok(Stmt::VarDecl(VarDecl {
             name,
             expr: Box::new(value),
+            constant: true,
         }))
     }
 
@@ -72,7 +206,11 @@ impl Parser {
             .consume(TokenType::Identifier, &format!("Expect {} name.", kind))?
             .lexeme
             .clone();
+        self.function(name, kind)
+    }
 
+    /// Parameters and body of a function whose name, if any, was already read.
+    fn function(&mut self, name: String, kind: &str) -> Result<Stmt, Error> {
         self.consume(
             TokenType::LeftParen,
             &format!("Expect '(' after {} name.", kind),
@@ -82,12 +220,7 @@ impl Parser {
         if !self.check(&TokenType::RightParen) {
             loop {
                 if parameters.len() >= 255 {
-                    return Err(Error {
-                        msg: format!(
-                            "Error at '{}': Can't have more than 255 parameters.",
-                            self.peek().lexeme
-                        ),
-                    });
+                    return Err(self.error_at_peek("Can't have more than 255 parameters."));
                 }
                 let token = self.consume(TokenType::Identifier, "Expect parameter name.")?;
                 parameters.push(token.to_owned());
@@ -120,13 +253,13 @@ impl Parser {
         let mut superclass: Option<Box<Expr>> = None;
         if self.ismatch(&[TokenType::Less])? {
             self.consume(TokenType::Identifier, "Expect superclass name.")?;
-            superclass = Some(Box::new(Expr::Var(Var::Token(self.previous()?))));
+            superclass = Some(Box::new(Expr::Var(Var::Token(self.reference()?))));
         }
         self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
 
         let mut methods: Vec<Stmt> = vec![];
         while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
-            methods.push(self.fun_decl("method")?.into());
+            methods.push(self.fun_decl("method")?);
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
@@ -137,6 +270,22 @@ impl Parser {
         }))
     }
 
+    fn enum_decl(&mut self) -> Result<Stmt, Error> {
+        let name = self.consume(TokenType::Identifier, "Expect enum name.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before enum body.")?;
+
+        let mut members = vec![];
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            members.push(self.consume(TokenType::Identifier, "Expect member name.")?);
+            if !self.ismatch(&[TokenType::Comma])? {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after enum body.")?;
+        Ok(Stmt::EnumDecl(EnumDecl { name, members }))
+    }
+
     fn statement(&mut self) -> Result<Stmt, Error> {
         if self.ismatch(&[TokenType::Print])? {
             self.print_stmt()
@@ -152,6 +301,14 @@ impl Parser {
             self.for_stmt()
         } else if self.ismatch(&[TokenType::Return])? {
             self.return_stmt()
+        } else if self.ismatch(&[TokenType::Break])? {
+            self.break_stmt()
+        } else if self.ismatch(&[TokenType::Continue])? {
+            self.continue_stmt()
+        } else if self.ismatch(&[TokenType::Assert])? {
+            self.assert_stmt()
+        } else if self.ismatch(&[TokenType::Try])? {
+            self.try_stmt()
         } else {
             self.expr_stmt()
         }
@@ -176,11 +333,37 @@ impl Parser {
         }))
     }
 
+    fn try_stmt(&mut self) -> Result<Stmt, Error> {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.")?;
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+        let name = self.consume(TokenType::Identifier, "Expect error variable name.")?;
+        self.consume(TokenType::RightParen, "Expect ')' after error variable.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch block.")?;
+        let handler = self.block()?;
+        Ok(Stmt::Try(Try {
+            body,
+            name,
+            handler,
+        }))
+    }
+
     fn return_stmt(&mut self) -> Result<Stmt, Error> {
         let keyword: Token = self.previous()?;
         let mut value: Option<Expr> = None;
         if !self.check(&TokenType::Semicolon) {
-            value = Some(self.expression()?);
+            let first = self.expression()?;
+            // `return a, b;` hands back both values as one list.
+            if self.check(&TokenType::Comma) {
+                let mut elements = vec![first];
+                while self.ismatch(&[TokenType::Comma])? {
+                    elements.push(self.expression()?);
+                }
+                value = Some(Expr::ListLiteral(ListLiteral { elements }));
+            } else {
+                value = Some(first);
+            }
         }
 
         self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
@@ -191,16 +374,61 @@ impl Parser {
         }))
     }
 
-    fn for_stmt(&mut self) -> Result<Stmt, Error> {
-        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
-        let initializer: Option<Stmt> = if self.ismatch(&[TokenType::Semicolon])? {
-            None
-        } else if self.ismatch(&[TokenType::Var])? {
-            Some(self.var_decl()?)
+    fn break_stmt(&mut self) -> Result<Stmt, Error> {
+        let keyword: Token = self.previous()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.")?;
+        Ok(Stmt::Break(Break { keyword }))
+    }
+
+    fn continue_stmt(&mut self) -> Result<Stmt, Error> {
+        let keyword: Token = self.previous()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.")?;
+        Ok(Stmt::Continue(Continue { keyword }))
+    }
+
+    fn assert_stmt(&mut self) -> Result<Stmt, Error> {
+        let keyword: Token = self.previous()?;
+        let condition: Expr = self.expression()?;
+        let message = if self.ismatch(&[TokenType::Colon])? {
+            Some(Box::new(self.expression()?))
         } else {
-            Some(self.expr_stmt()?)
+            None
         };
 
+        self.consume(TokenType::Semicolon, "Expect ';' after assertion.")?;
+        Ok(Stmt::Assert(Assert {
+            keyword,
+            condition: Box::new(condition),
+            message,
+        }))
+    }
+
+    fn for_stmt(&mut self) -> Result<Stmt, Error> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        // Both clauses take a comma-separated list, e.g. `var i = 0, j = 10`.
+        let mut initializer: Vec<Stmt> = vec![];
+        if !self.ismatch(&[TokenType::Semicolon])? {
+            let declares = self.ismatch(&[TokenType::Var])?;
+            loop {
+                initializer.push(if declares {
+                    self.var_binding()?
+                } else {
+                    Stmt::Expression(Expression {
+                        expr: Box::new(self.expression()?),
+                    })
+                });
+                if !self.ismatch(&[TokenType::Comma])? {
+                    break;
+                }
+            }
+            let msg = if declares {
+                "Expect ';' after variable declaration."
+            } else {
+                "Expect ';' after value."
+            };
+            self.consume(TokenType::Semicolon, msg)?;
+        }
+
         let condition: Option<Expr> = if !self.check(&TokenType::Semicolon) {
             Some(self.expression()?)
         } else {
@@ -208,45 +436,36 @@ impl Parser {
         };
         self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
 
-        let increment: Option<Expr> = if !self.check(&TokenType::RightParen) {
-            Some(self.expression()?)
-        } else {
-            None
-        };
+        let mut increment: Vec<Expr> = vec![];
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                increment.push(self.expression()?);
+                if !self.ismatch(&[TokenType::Comma])? {
+                    break;
+                }
+            }
+        }
         self.consume(TokenType::RightParen, "Expect ';' after loop condition.")?;
 
         let mut body: Stmt = self.statement()?;
 
-        body = if let Some(inc) = increment {
-            Stmt::Block(Block {
-                stmts: vec![
-                    body,
-                    Stmt::Expression(Expression {
-                        expr: Box::new(inc),
-                    }),
-                ],
-            })
-        } else {
-            body
-        };
-
         let cond = if let Some(cond) = condition {
             cond
         } else {
             Expr::Literal(Literal::Bool(true))
         };
+        // The increment stays out of the body so `continue` doesn't skip it.
         body = Stmt::While(While {
             condition: Box::new(cond),
             body: Box::new(body),
+            increment,
+            else_branch: None,
         });
 
-        body = if let Some(init) = initializer {
-            Stmt::Block(Block {
-                stmts: vec![init, body],
-            })
-        } else {
-            body
-        };
+        if !initializer.is_empty() {
+            initializer.push(body);
+            body = Stmt::Block(Block { stmts: initializer });
+        }
 
         Ok(body)
     }
@@ -256,10 +475,20 @@ impl Parser {
         let condition: Expr = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after if condition")?;
         let body = self.statement()?;
+        // Only a block body takes an `else`, so in `if (c) while (d) s; else e;`
+        // the `else` still belongs to the `if`.
+        let else_branch =
+            if matches!(body, Stmt::Block(_)) && self.ismatch(&[TokenType::Else])? {
+                Some(Box::new(self.statement()?))
+            } else {
+                None
+            };
 
         Ok(Stmt::While(While {
             condition: Box::new(condition),
             body: Box::new(body),
+            increment: vec![],
+            else_branch,
         }))
     }
 
@@ -291,158 +520,250 @@ impl Parser {
     }
 
     fn expression(&mut self) -> Result<Expr, Error> {
-        self.assigment()
+        self.nested(Parser::assigment)
     }
 
-    fn assigment(&mut self) -> Result<Expr, Error> {
-        let expr: Expr = self.or()?;
+    /// Runs `parse` one nesting level deeper.
+    fn nested<T>(&mut self, parse: fn(&mut Parser) -> Result<T, Error>) -> Result<T, Error> {
+        self.check_depth(1)?;
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
 
-        if self.ismatch(&[TokenType::Equal])? {
-            let value: Expr = self.assigment()?;
-
-            match expr {
-                Expr::Var(var) => Ok(Expr::Assign(Assign {
-                    var,
-                    expr: Box::new(value),
-                })),
-                Expr::Get(Get { object, name }) => Ok(Expr::Set(Set {
-                    object,
-                    name,
-                    value: Box::new(value),
-                })),
-                _ => Err(Error {
-                    msg: format!("Error at '=': Invalid assignment target.",),
-                }),
-            }
-        } else {
-            Ok(expr)
+    /// Fails if `extra` more levels would nest deeper than allowed. Call and
+    /// property chains pass their length, as each link wraps the one before.
+    fn check_depth(&mut self, extra: usize) -> Result<(), Error> {
+        if self.depth + extra > self.max_depth {
+            return Err(self.error_at_peek("Expression nests too deeply."));
         }
+        Ok(())
     }
 
-    fn or(&mut self) -> Result<Expr, Error> {
-        let mut expr: Expr = self.and()?;
-        while self.ismatch(&[TokenType::Or])? {
-            let operator: Operator = Operator::from_token(&self.previous()?);
-            let right: Expr = self.and()?;
-            expr = Expr::Logical(Logical {
-                left: Box::new(expr),
-                operator,
-                right: Box::new(right),
-            });
+    // Every level below keeps only the descent into its first operand and
+    // hands whatever follows to a separate function. In debug builds a frame
+    // holds all of its temporaries at once, and a level's frame stays on the
+    // stack while everything nested inside that operand is parsed, so this
+    // keeps the cost of each nesting level down.
+
+    fn assigment(&mut self) -> Result<Expr, Error> {
+        let expr: Expr = self.ternary()?;
+        if self.check(&TokenType::Equal) {
+            return self.assignment_to(expr);
         }
         Ok(expr)
     }
 
-    fn and(&mut self) -> Result<Expr, Error> {
-        let mut expr: Expr = self.equality()?;
-        while self.ismatch(&[TokenType::And])? {
-            let operator: Operator = Operator::from_token(&self.previous()?);
-            let right: Expr = self.and()?;
-            expr = Expr::Logical(Logical {
-                left: Box::new(expr),
-                operator,
-                right: Box::new(right),
-            });
+    fn assignment_to(&mut self, target: Expr) -> Result<Expr, Error> {
+        self.advance()?;
+        // Only the position, a whole token would grow every nested frame.
+        let equals = self.current - 1;
+        let value: Expr = self.nested(Parser::assigment)?;
+
+        match target {
+            Expr::Var(var) => Ok(Expr::Assign(Assign {
+                var,
+                expr: Box::new(value),
+            })),
+            Expr::Get(Get {
+                object,
+                name,
+                optional: false,
+            }) => Ok(Expr::Set(Set {
+                object,
+                name,
+                value: Box::new(value),
+            })),
+            Expr::Index(Index {
+                object,
+                bracket,
+                index,
+            }) => Ok(Expr::IndexSet(IndexSet {
+                object,
+                bracket,
+                index,
+                value: Box::new(value),
+            })),
+            _ => Err(Error::new(
+                ErrorKind::Parse,
+                "Error at '=': Invalid assignment target.".to_string(),
+            )
+            .at(&self.tokens[equals])),
         }
-        Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr, Error> {
-        let mut expr: Expr = self.comparison()?;
-
-        while self.ismatch(&[TokenType::BangEqual, TokenType::EqualEqual])? {
-            let operator: Operator = Operator::from_token(&self.previous()?);
-            let right: Expr = self.comparison()?;
-            expr = Expr::Binary(Binary {
-                left: Box::new(expr),
-                operator,
-                right: Box::new(right),
-            });
+    /// `condition ? then : else`, right-associative, so `a ? b : c ? d : e`
+    /// reads as `a ? b : (c ? d : e)`.
+    fn ternary(&mut self) -> Result<Expr, Error> {
+        let condition: Expr = self.coalesce()?;
+        if self.check(&TokenType::Question) {
+            return self.conditional(condition);
         }
+        Ok(condition)
+    }
 
-        Ok(expr)
+    fn conditional(&mut self, condition: Expr) -> Result<Expr, Error> {
+        self.advance()?;
+        let then_branch: Expr = self.nested(Parser::assigment)?;
+        self.consume(TokenType::Colon, "Expect ':' after then branch of conditional.")?;
+        let else_branch: Expr = self.nested(Parser::ternary)?;
+        Ok(Expr::Ternary(Ternary {
+            condition: Box::new(condition),
+            then_branch: Box::new(then_branch),
+            else_branch: Box::new(else_branch),
+        }))
+    }
+
+    fn coalesce(&mut self) -> Result<Expr, Error> {
+        let expr: Expr = self.or()?;
+        self.logical_chain(expr, &[TokenType::QuestionQuestion], Parser::or)
+    }
+
+    fn or(&mut self) -> Result<Expr, Error> {
+        let expr: Expr = self.and()?;
+        self.logical_chain(expr, &[TokenType::Or], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        let expr: Expr = self.equality()?;
+        self.logical_chain(expr, &[TokenType::And], Parser::equality)
+    }
+
+    fn equality(&mut self) -> Result<Expr, Error> {
+        let expr: Expr = self.comparison()?;
+        self.binary_chain(
+            expr,
+            &[TokenType::BangEqual, TokenType::EqualEqual],
+            Parser::comparison,
+        )
     }
 
     fn comparison(&mut self) -> Result<Expr, Error> {
-        let mut expr: Expr = self.term()?;
-
-        while self.ismatch(&[
-            TokenType::Greater,
-            TokenType::GreaterEqual,
-            TokenType::Less,
-            TokenType::LessEqual,
-        ])? {
-            let operator: Operator = Operator::from_token(&self.previous()?);
-            let right: Expr = self.term()?;
-            expr = Expr::Binary(Binary {
-                left: Box::new(expr),
-                operator,
-                right: Box::new(right),
-            });
-        }
+        let expr: Expr = self.range()?;
+        self.binary_chain(
+            expr,
+            &[
+                TokenType::Greater,
+                TokenType::GreaterEqual,
+                TokenType::Less,
+                TokenType::LessEqual,
+            ],
+            Parser::range,
+        )
+    }
 
-        Ok(expr)
+    fn range(&mut self) -> Result<Expr, Error> {
+        let expr: Expr = self.term()?;
+        self.binary_chain(expr, &[TokenType::DotDot], Parser::term)
     }
 
     fn term(&mut self) -> Result<Expr, Error> {
-        let mut expr: Expr = self.factor()?;
+        let expr: Expr = self.factor()?;
+        self.binary_chain(expr, &[TokenType::Minus, TokenType::Plus], Parser::factor)
+    }
+
+    fn factor(&mut self) -> Result<Expr, Error> {
+        let expr: Expr = self.unary()?;
+        self.binary_chain(
+            expr,
+            &[TokenType::Slash, TokenType::Star, TokenType::Percent],
+            Parser::unary,
+        )
+    }
 
-        while self.ismatch(&[TokenType::Minus, TokenType::Plus])? {
+    /// Folds `expr` and any following `operators` with their right operands,
+    /// parsed by `operand`, into a left-leaning chain of binary expressions.
+    /// The interpreter walks these chains iteratively, so their length is not
+    /// limited by the nesting depth.
+    fn binary_chain(
+        &mut self,
+        mut expr: Expr,
+        operators: &[TokenType],
+        operand: fn(&mut Parser) -> Result<Expr, Error>,
+    ) -> Result<Expr, Error> {
+        while self.ismatch(operators)? {
             let operator: Operator = Operator::from_token(&self.previous()?);
-            let right: Expr = self.factor()?;
+            let right: Expr = operand(self)?;
             expr = Expr::Binary(Binary {
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
             });
         }
-
         Ok(expr)
     }
 
-    fn factor(&mut self) -> Result<Expr, Error> {
-        let mut expr: Expr = self.unary()?;
-
-        while self.ismatch(&[TokenType::Slash, TokenType::Star])? {
+    /// Same as `binary_chain`, for the short-circuiting operators.
+    fn logical_chain(
+        &mut self,
+        mut expr: Expr,
+        operators: &[TokenType],
+        operand: fn(&mut Parser) -> Result<Expr, Error>,
+    ) -> Result<Expr, Error> {
+        while self.ismatch(operators)? {
             let operator: Operator = Operator::from_token(&self.previous()?);
-            let right: Expr = self.unary()?;
-            expr = Expr::Binary(Binary {
+            let right: Expr = operand(self)?;
+            expr = Expr::Logical(Logical {
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
             });
         }
-
         Ok(expr)
     }
 
     fn unary(&mut self) -> Result<Expr, Error> {
-        if self.ismatch(&[TokenType::Bang, TokenType::Minus])? {
-            let operator: Operator = Operator::from_token(&self.previous()?);
-            let right: Expr = self.factor()?;
-            let expr = Expr::Unary(Unary {
-                operator,
-                right: Box::new(right),
-            });
-
-            return Ok(expr);
+        if self.check(&TokenType::Bang) || self.check(&TokenType::Minus) {
+            return self.prefix();
         }
-
         self.call()
     }
 
+    fn prefix(&mut self) -> Result<Expr, Error> {
+        let operator: Operator = Operator::from_token(&self.advance()?);
+        let right: Expr = self.nested(Parser::factor)?;
+        Ok(Expr::Unary(Unary {
+            operator,
+            right: Box::new(right),
+        }))
+    }
+
     fn call(&mut self) -> Result<Expr, Error> {
-        let mut expr: Expr = self.primary()?;
+        let expr: Expr = self.primary()?;
+        self.postfix(expr)
+    }
 
+    /// Wraps `expr` in the calls, index and property accesses following it.
+    fn postfix(&mut self, mut expr: Expr) -> Result<Expr, Error> {
+        let mut chain = 0;
         loop {
+            if self.check(&TokenType::LeftParen)
+                || self.check(&TokenType::LeftBracket)
+                || self.check(&TokenType::Dot)
+                || self.check(&TokenType::QuestionDot)
+            {
+                chain += 1;
+                self.check_depth(chain)?;
+            }
             if self.ismatch(&[TokenType::LeftParen])? {
                 expr = self.finish_call(expr)?;
-            } else if self.ismatch(&[TokenType::Dot])? {
+            } else if self.ismatch(&[TokenType::LeftBracket])? {
+                let index = self.expression()?;
+                let bracket = self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                expr = Expr::Index(Index {
+                    object: Box::new(expr),
+                    bracket: bracket.clone(),
+                    index: Box::new(index),
+                });
+            } else if self.ismatch(&[TokenType::Dot, TokenType::QuestionDot])? {
+                let optional = self.previous()?.token_type == TokenType::QuestionDot;
                 let name =
                     self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
                 expr = Expr::Get(Get {
                     object: Box::new(expr),
                     name: name.clone(),
+                    optional,
                 });
             } else {
                 break;
@@ -457,14 +778,9 @@ impl Parser {
         if !self.check(&TokenType::RightParen) {
             loop {
                 if arguments.len() >= 255 {
-                    Err(Error {
-                        msg: format!(
-                            "Error at '{}': Can't have more than 255 arguments.",
-                            self.peek().lexeme
-                        ),
-                    })?;
+                    return Err(self.error_at_peek("Can't have more than 255 arguments."));
                 }
-                arguments.push(self.expression()?);
+                arguments.push(self.element(&TokenType::RightParen)?);
                 if !self.ismatch(&[TokenType::Comma])? {
                     break;
                 }
@@ -476,10 +792,100 @@ impl Parser {
             callee: Box::new(callee),
             paren: paren.clone(),
             arguments,
+            tail: Cell::new(false),
         }))
     }
 
     fn primary(&mut self) -> Result<Expr, Error> {
+        match self.peek().token_type {
+            TokenType::LeftParen => self.grouping(),
+            TokenType::LeftBracket => self.list_literal(),
+            TokenType::Fun => self.lambda(),
+            _ => self.atom(),
+        }
+    }
+
+    fn grouping(&mut self) -> Result<Expr, Error> {
+        self.advance()?;
+        let expr: Expr = self.expression()?;
+        self.consume(TokenType::RightParen, "Expected ')' after expression.")?;
+        Ok(Expr::Grouping(Grouping {
+            group: Box::new(expr),
+        }))
+    }
+
+    fn list_literal(&mut self) -> Result<Expr, Error> {
+        self.advance()?;
+        let mut elements = vec![];
+        if !self.check(&TokenType::RightBracket) {
+            loop {
+                elements.push(self.element(&TokenType::RightBracket)?);
+                if !self.ismatch(&[TokenType::Comma])? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+        Ok(Expr::ListLiteral(ListLiteral { elements }))
+    }
+
+    /// An argument or list element ended by `,` or `close`. When parsing
+    /// partially, a malformed one is recorded and replaced by a placeholder.
+    fn element(&mut self, close: &TokenType) -> Result<Expr, Error> {
+        let start = self.current;
+        match self.expression() {
+            Err(error) if self.partial => {
+                let msg = error.msg.clone();
+                self.errors.push(error);
+                self.current = start;
+                self.skip_element(close);
+                Ok(Expr::InvalidExpr(InvalidExpr { msg }))
+            }
+            result => result,
+        }
+    }
+
+    /// Skips from the start of an element to the `,` or `close` ending it,
+    /// stepping over anything bracketed. Stops early at the end of the statement, leaving
+    /// the caller to report the missing `close`.
+    fn skip_element(&mut self, close: &TokenType) {
+        let mut nesting = 0;
+        while !self.is_at_end() {
+            let token_type = &self.peek().token_type;
+            if nesting == 0
+                && (token_type == close
+                    || *token_type == TokenType::Comma
+                    || *token_type == TokenType::Semicolon)
+            {
+                return;
+            }
+            match token_type {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => {
+                    nesting += 1
+                }
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                    if nesting == 0 {
+                        return;
+                    }
+                    nesting -= 1
+                }
+                _ => {}
+            }
+            let _ = self.advance();
+        }
+    }
+
+    fn lambda(&mut self) -> Result<Expr, Error> {
+        let keyword = self.advance()?;
+        let declaration = self.function("lambda".to_string(), "function")?;
+        Ok(Expr::Lambda(Lambda {
+            keyword,
+            declaration: Box::new(declaration),
+        }))
+    }
+
+    /// The primary expressions that don't contain others.
+    fn atom(&mut self) -> Result<Expr, Error> {
         if self.ismatch(&[TokenType::False])? {
             Ok(Expr::Literal(Literal::Bool(false)))
         } else if self.ismatch(&[TokenType::True])? {
@@ -492,18 +898,12 @@ impl Parser {
                 TokenLiteral::Str(s) => Expr::Literal(Literal::Str(s.to_owned())),
             };
             Ok(expr)
-        } else if self.ismatch(&[TokenType::LeftParen])? {
-            let expr: Expr = self.expression()?;
-            self.consume(TokenType::RightParen, "Expected ')' after expression.")?;
-            Ok(Expr::Grouping(Grouping {
-                group: Box::new(expr),
-            }))
         } else if self.ismatch(&[TokenType::This])? {
             Ok(Expr::This(This {
-                keyword: self.previous()?,
+                keyword: self.reference()?,
             }))
         } else if self.ismatch(&[TokenType::Super])? {
-            let keyword = self.previous()?;
+            let keyword = self.reference()?;
             self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
             let method = self.consume(TokenType::Identifier, "Expect superclass method name.")?;
             Ok(Expr::Super(Super {
@@ -511,15 +911,9 @@ impl Parser {
                 method: method.clone(),
             }))
         } else if self.ismatch(&[TokenType::Identifier])? {
-            Ok(Expr::Var(Var::Token(self.previous()?)))
+            Ok(Expr::Var(Var::Token(self.reference()?)))
         } else {
-            let peek = self.peek();
-            Err(Error {
-                msg: format!(
-                    "[line {:}] Error at '{:}': Expect expression.",
-                    peek.line, peek.lexeme
-                ),
-            })
+            Err(self.error_at_peek("Expect expression."))
         }
     }
 
@@ -559,24 +953,253 @@ impl Parser {
     fn previous(&mut self) -> Result<Token, Error> {
         match self.tokens.get(self.current - 1) {
             Some(token) => Ok(token.clone()),
-            None => Err(Error {
-                msg: format!("No previous token"),
-            }),
+            None => Err(Error::new(
+                ErrorKind::Parse,
+                "No previous token".to_string(),
+            )),
         }
     }
 
+    /// The previous token, tagged with a fresh id for the expression it names.
+    fn reference(&mut self) -> Result<Token, Error> {
+        let mut token = self.previous()?;
+        token.id = NEXT_EXPR_ID.fetch_add(1, Ordering::Relaxed);
+        Ok(token)
+    }
+
     fn consume(&mut self, token_type: TokenType, message: &str) -> Result<Token, Error> {
         if self.check(&token_type) {
             self.advance()
         } else {
-            let token = self.peek();
-            let name = match token.token_type {
-                TokenType::Eof => "end".to_string(),
-                _ => "'".to_owned() + &token.lexeme + "'",
-            };
-            Err(Error {
-                msg: format!("[line {}] Error at {}: {}", token.line, name, message),
-            })
+            Err(self.error_at_peek(message))
+        }
+    }
+
+    fn error_at_peek(&mut self, message: &str) -> Error {
+        let token = self.peek();
+        let name = match token.token_type {
+            TokenType::Eof => "end".to_string(),
+            _ => "'".to_owned() + &token.lexeme + "'",
+        };
+        Error::new(
+            ErrorKind::Parse,
+            format!("Error at {}: {}", name, message),
+        )
+        .at(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::ast::{
+        Call, Expr, Invalid, InvalidExpr, ListLiteral, Location, Print, Stmt, VarDecl,
+    };
+    use crate::interpreter::lexer::Lexer;
+
+    use super::{Parser, MAX_NESTING_DEPTH};
+
+    fn parser(source: &str) -> Parser {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        Parser::new(lexer.tokens)
+    }
+
+    #[test]
+    fn partial_parse_keeps_statements_around_error() {
+        let (stmts, errors) = parser(
+            "var a = 1;
+            var b = ;
+            print a;",
+        )
+        .parse_partial();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(stmts.len(), 3);
+        assert!(matches!(&stmts[0], Stmt::VarDecl(VarDecl { name, .. }) if name == "a"));
+        assert!(matches!(&stmts[1], Stmt::Invalid(Invalid { msg }) if *msg == errors[0].msg));
+        assert!(matches!(&stmts[2], Stmt::Print(Print { .. })));
+    }
+
+    #[test]
+    fn partial_parse_replaces_malformed_elements() {
+        let (stmts, errors) = parser(
+            "print f(1, +, g(2));
+            var l = [1, (2 + ), 3];",
+        )
+        .parse_partial();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(stmts.len(), 2);
+        let Stmt::Print(Print { expr }) = &stmts[0] else {
+            panic!("expected a print statement, got {:?}", stmts[0]);
+        };
+        let Expr::Call(Call { arguments, .. }) = expr.as_ref() else {
+            panic!("expected a call, got {:?}", expr);
+        };
+        assert_eq!(arguments.len(), 3);
+        assert!(matches!(&arguments[1], Expr::InvalidExpr(InvalidExpr { msg }) if *msg == errors[0].msg));
+        assert!(matches!(&arguments[2], Expr::Call(_)));
+
+        let Stmt::VarDecl(VarDecl { expr, .. }) = &stmts[1] else {
+            panic!("expected a declaration, got {:?}", stmts[1]);
+        };
+        let Expr::ListLiteral(ListLiteral { elements }) = expr.as_ref() else {
+            panic!("expected a list literal, got {:?}", expr);
+        };
+        assert_eq!(elements.len(), 3);
+        assert!(matches!(&elements[1], Expr::InvalidExpr(_)));
+    }
+
+    #[test]
+    fn bare_super_expects_dot() {
+        let results = parser(
+            "class A {}
+            class B < A {
+                method() {
+                    super;
+                }
+            }",
+        )
+        .parse();
+        let error = results.into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!(error.to_string(), "[line 4] Error at ';': Expect '.' after 'super'.");
+    }
+
+    #[test]
+    fn assert_requires_semicolon() {
+        let error = parser("assert true : \"msg\"")
+            .parse()
+            .into_iter()
+            .find_map(|r| r.err())
+            .unwrap();
+        assert_eq!(error.to_string(), "[line 1] Error at end: Expect ';' after assertion.");
+    }
+
+    #[test]
+    fn class_body_only_holds_methods() {
+        // There are no field initializers: anything but a method in a class
+        // body, `return` included, is rejected before the resolver runs.
+        let error = parser(
+            "class A {
+                return 1;
+            }",
+        )
+        .parse()
+        .into_iter()
+        .find_map(|r| r.err())
+        .unwrap();
+        assert_eq!(error.to_string(), "[line 2] Error at 'return': Expect method name.");
+    }
+
+    fn first_error(mut parser: Parser) -> String {
+        parser.parse().into_iter().find_map(|r| r.err()).unwrap().to_string()
+    }
+
+    fn grouping(depth: usize) -> String {
+        format!("print {}1{};", "(".repeat(depth), ")".repeat(depth))
+    }
+
+    #[test]
+    fn missing_semicolon_reports_its_location() {
+        let error = parser("var a = 1;\nprint a\nprint 2;")
+            .parse()
+            .into_iter()
+            .find_map(|r| r.err())
+            .unwrap();
+        assert_eq!(error.msg, "Error at 'print': Expect ';' after value.");
+        assert_eq!(error.location.as_deref(), Some(&Location { line: 3, col: 1 }));
+        assert_eq!(error.to_string(), "[line 3] Error at 'print': Expect ';' after value.");
+
+        let error = parser("var a = 1 print a;").parse().into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!(error.location.as_deref(), Some(&Location { line: 1, col: 11 }));
+        let error = parser("print (1;").parse().into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!(error.location.as_deref(), Some(&Location { line: 1, col: 9 }));
+        let error = parser("f(1,);").parse().into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!(error.to_string(), "[line 1] Error at ')': Expect expression.");
+        assert_eq!(error.location.map(|location| location.col), Some(5));
+    }
+
+    #[test]
+    fn parsing_resumes_after_an_error() {
+        let results = parser("print 1;\nvar = 2;\nprint 3;\nprint (4;\nprint 5;").parse();
+        let errors: Vec<String> = results
+            .iter()
+            .filter_map(|r| r.as_ref().err().map(|e| e.to_string()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "[line 2] Error at '=': Expect variable name.",
+                "[line 4] Error at ';': Expected ')' after expression.",
+            ]
+        );
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 3);
+    }
+
+    #[test]
+    fn constants_need_an_initializer() {
+        assert_eq!(
+            first_error(parser("const PI;")),
+            "[line 1] Error at ';': Expect '=' after constant name."
+        );
+    }
+
+    #[test]
+    fn enum_members_are_identifiers() {
+        assert_eq!(
+            first_error(parser("enum Color { Red, 1 }")),
+            "[line 1] Error at '1': Expect member name."
+        );
+        assert_eq!(
+            first_error(parser("enum Color { Red Green }")),
+            "[line 1] Error at 'Green': Expect '}' after enum body."
+        );
+    }
+
+    #[test]
+    fn try_needs_a_catch_clause() {
+        assert_eq!(
+            first_error(parser("try { print 1; } print 2;")),
+            "[line 1] Error at 'print': Expect 'catch' after try block."
+        );
+        assert_eq!(
+            first_error(parser("try { } catch e { }")),
+            "[line 1] Error at 'e': Expect '(' after 'catch'."
+        );
+    }
+
+    #[test]
+    fn deep_grouping_is_rejected() {
+        let mut lexer = Lexer::new(&grouping(20));
+        lexer.scan_tokens().unwrap();
+        let error = first_error(Parser::new_with_max_depth(lexer.tokens, 16));
+        assert_eq!(error, "[line 1] Error at '(': Expression nests too deeply.");
+
+        let source = grouping(MAX_NESTING_DEPTH + 1);
+        assert_eq!(
+            first_error(parser(&source)),
+            "[line 1] Error at '(': Expression nests too deeply."
+        );
+        assert!(parser(&grouping(MAX_NESTING_DEPTH - 1)).parse().iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn long_chains_are_rejected() {
+        for source in ["a.b.c.d.e.f;", "f()()()()();", "!!!!!!true;"] {
+            let mut lexer = Lexer::new(source);
+            lexer.scan_tokens().unwrap();
+            let error = first_error(Parser::new_with_max_depth(lexer.tokens, 3));
+            assert!(error.ends_with("Expression nests too deeply."), "{}: {}", source, error);
+        }
+    }
+
+    #[test]
+    fn operator_chains_do_not_count_toward_the_depth() {
+        for source in ["1 + 2 + 3 + 4 + 5 + 6;", "a or b or c or d or e;"] {
+            let mut lexer = Lexer::new(source);
+            lexer.scan_tokens().unwrap();
+            let mut parser = Parser::new_with_max_depth(lexer.tokens, 3);
+            assert!(parser.parse().iter().all(|stmt| stmt.is_ok()), "{}", source);
         }
     }
 }
@@ -1,12 +1,16 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
 use std::rc::Rc;
 
 use crate::interpreter::ast::{
-    Assign, Binary, Block, Class, ClassDecl, Error, Expr, Expression, FunDecl, Function, Get,
-    Grouping, IVisitorExpr, IVisitorStmt, If, Instance, Literal, Logical, Print, Return, Set, Stmt,
-    Super, This, Unary, Var, VarDecl, While,
+    Assert, Assign, Binary, Block, Call, Class, ClassDecl, Destructure, EnumDecl, Error, ErrorKind,
+    Expr, Expression, FunDecl, Function, Get, Grouping, IVisitorExpr, IVisitorStmt, If, Index,
+    IndexSet, Instance, Lambda, List, ListLiteral, Literal, Logical, Native, NativeFunction, Print,
+    Return, Set, Stmt, Super, Ternary, This, Try, Unary, Var, VarDecl, While,
 };
+use crate::interpreter::natives;
 use crate::interpreter::operators::Operator;
 
 #[macro_export]
@@ -22,7 +26,11 @@ macro_rules! extract_enum_value {
 #[derive(Debug, PartialEq, Clone, PartialOrd, Ord, Eq)]
 
 pub struct Environment {
-    symbol_table: BTreeMap<String, Expr>,
+    /// Slot of every name defined here. Slots are handed out in definition
+    /// order, the order the resolver numbers declarations in.
+    symbol_table: BTreeMap<String, usize>,
+    /// Each slot's name and value, indexed by slot.
+    values: Vec<(String, Expr)>,
     enclosing: Option<Rc<RefCell<Environment>>>,
 }
 
@@ -30,6 +38,7 @@ impl Environment {
     pub fn new() -> Self {
         Environment {
             symbol_table: BTreeMap::new(),
+            values: Vec::new(),
             enclosing: None,
         }
     }
@@ -37,28 +46,97 @@ impl Environment {
     pub fn new_with_enclosing(enclosing: Option<Rc<RefCell<Environment>>>) -> Self {
         Environment {
             symbol_table: BTreeMap::new(),
+            values: Vec::new(),
             enclosing,
         }
     }
 
     pub fn define(&mut self, name: &str, value: Expr) {
-        self.symbol_table.insert(name.to_string(), value);
+        match self.symbol_table.get(name) {
+            Some(&slot) => self.values[slot].1 = value,
+            None => {
+                self.symbol_table.insert(name.to_string(), self.values.len());
+                self.values.push((name.to_string(), value));
+            }
+        }
     }
 
     pub fn retrieve(&self, name: &str) -> Option<Expr> {
-        self.symbol_table.get(name).cloned()
+        self.symbol_table
+            .get(name)
+            .map(|&slot| self.values[slot].1.clone())
+    }
+
+    /// The value in `slot`, provided the slot holds `name`.
+    pub fn retrieve_slot(&self, slot: usize, name: &str) -> Option<Expr> {
+        match self.values.get(slot) {
+            Some((defined, value)) if defined == name => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Overwrites `slot` if it holds `name`, returning whether it did.
+    pub fn assign_slot(&mut self, slot: usize, name: &str, value: Expr) -> bool {
+        match self.values.get_mut(slot) {
+            Some((defined, old)) if defined == name => {
+                *old = value;
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn contains_key(&self, name: &str) -> bool {
         self.symbol_table.contains_key(name)
     }
+
+    /// Names and values defined directly in this environment, sorted by name.
+    pub fn symbols(&self) -> impl Iterator<Item = (&String, &Expr)> {
+        self.symbol_table
+            .iter()
+            .map(|(name, &slot)| (name, &self.values[slot].1))
+    }
+}
+
+/// Whether a block runs only for its effects or also yields the value of a
+/// trailing expression statement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockContext {
+    Statement,
+    Expression,
 }
 
-#[derive(Debug, PartialEq)]
 pub struct Interpreter {
     environments: Option<Rc<RefCell<Environment>>>,
-    locals: BTreeMap<Expr, usize>,
+    locals: HashMap<usize, usize>,
+    /// Slot of each resolved local within the environment `locals` points at.
+    slots: HashMap<usize, usize>,
+    /// Whether resolved locals are read and written through `slots`.
+    slot_lookup: bool,
+    /// Whether `+` turns its other operand into a string when one is a string.
+    concat_strings: bool,
     counter: usize,
+    /// Stands in for the process environment when set, see `set_env`.
+    env: Option<HashMap<String, String>>,
+    /// Where `print` writes, standard output unless replaced by `set_stdout`.
+    stdout: Box<dyn Write>,
+    /// Where `print_err` writes, standard error unless replaced by `set_stderr`.
+    stderr: Box<dyn Write>,
+    /// A call in tail position, left by `return` for the running function to
+    /// make in place of nesting it.
+    tail_call: Option<(Function, Vec<Expr>)>,
+}
+
+// The output sinks are opaque, so they are left out.
+impl fmt::Debug for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interpreter")
+            .field("environments", &self.environments)
+            .field("locals", &self.locals)
+            .field("counter", &self.counter)
+            .field("env", &self.env)
+            .finish_non_exhaustive()
+    }
 }
 
 pub struct EnvironmentIterator<'a> {
@@ -101,12 +179,106 @@ impl<'a> Iterator for EnvironmentIterator<'a> {
     }
 }
 
+/// How `print` shows a value: `5` rather than `5.0`, strings without quotes,
+/// `nil` for nil, see `natives::stringify`.
+fn display(value: Option<Expr>) -> String {
+    match value {
+        Some(value) => natives::stringify(&value),
+        None => "nil".to_string(),
+    }
+}
+
+/// Checks that `object` is a list and `index` a position inside it, returning
+/// both for the caller to read or write.
+fn list_slot(object: Option<Expr>, index: Option<Expr>) -> Result<(List, usize), Error> {
+    let list = match object {
+        Some(Expr::List(list)) => list,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                "Only lists can be indexed.".to_string(),
+            ))
+        }
+    };
+    let index = match index {
+        Some(Expr::Literal(Literal::Number(n))) if n.0 >= 0.0 && n.0.fract() == 0.0 => n.0,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Type,
+                "List index must be a non-negative integer.".to_string(),
+            ))
+        }
+    };
+    let length = list.elements.borrow().len();
+    if index >= length as f64 {
+        return Err(Error::new(
+            ErrorKind::Runtime,
+            format!("List index {} out of bounds for length {}.", index, length),
+        ));
+    }
+    Ok((list, index as usize))
+}
+
 impl Interpreter {
     pub fn new() -> Self {
+        let mut globals = Environment::new();
+        for native in natives::natives() {
+            globals.define(&native.name.clone(), Expr::Native(native));
+        }
         Interpreter {
-            environments: Some(Rc::new(RefCell::new(Environment::new()))),
-            locals: BTreeMap::new(),
+            environments: Some(Rc::new(RefCell::new(globals))),
+            locals: HashMap::new(),
+            slots: HashMap::new(),
+            slot_lookup: true,
+            concat_strings: false,
             counter: 1,
+            env: None,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            tail_call: None,
+        }
+    }
+
+    /// Turns slot lookups for resolved locals on or off; when off every
+    /// variable is found by name.
+    pub fn set_slot_lookup(&mut self, enabled: bool) {
+        self.slot_lookup = enabled;
+    }
+
+    /// Lets `"n=" + 5` give `"n=5"` instead of failing, with the non-string
+    /// side written as `format` would.
+    pub fn set_concat_strings(&mut self, enabled: bool) {
+        self.concat_strings = enabled;
+    }
+
+    pub fn set_stdout(&mut self, sink: Box<dyn Write>) {
+        self.stdout = sink;
+    }
+
+    pub fn set_stderr(&mut self, sink: Box<dyn Write>) {
+        self.stderr = sink;
+    }
+
+    /// Writes `value` to the stderr sink the way `print` would show it.
+    pub fn print_err(&mut self, value: Expr) -> Result<(), Error> {
+        let line = display(Some(value));
+        writeln!(self.stderr, "{}", line).map_err(|e| Error::new(ErrorKind::Runtime, e.to_string()))
+    }
+
+    /// Makes the `env` native read from `vars` instead of the process
+    /// environment.
+    pub fn set_env(&mut self, vars: HashMap<String, String>) {
+        self.env = Some(vars);
+    }
+
+    pub fn take_tail_call(&mut self) -> Option<(Function, Vec<Expr>)> {
+        self.tail_call.take()
+    }
+
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        match &self.env {
+            Some(vars) => vars.get(name).cloned(),
+            None => std::env::var(name).ok(),
         }
     }
 
@@ -168,7 +340,10 @@ impl Interpreter {
 
     pub fn get_symbol_at(&self, mut pos: isize, name: &str) -> Result<Option<Expr>, Error> {
         if pos < 0 {
-            return Err(Error::new("Invalid position".to_string()));
+            return Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid position".to_string(),
+            ));
         }
         for env in self.iterator() {
             if pos == 0 {
@@ -184,7 +359,10 @@ impl Interpreter {
             }
         }
 
-        Err(Error::new(format!("Undefined variable {}", name)))
+        Err(Error::new(
+            ErrorKind::UndefinedVariable,
+            format!("Undefined variable '{}'.", name),
+        ))
     }
 
     pub fn assign_symbol_at(
@@ -214,35 +392,210 @@ impl Interpreter {
         false
     }
 
+    fn distance(&self, expr: &Expr) -> Option<&usize> {
+        expr.id().and_then(|id| self.locals.get(&id))
+    }
+
+    fn slot(&self, expr: &Expr) -> Option<usize> {
+        if !self.slot_lookup {
+            return None;
+        }
+        expr.id().and_then(|id| self.slots.get(&id)).copied()
+    }
+
+    /// The environment `distance` hops up from the current one.
+    fn ancestor(&self, distance: usize) -> Option<Rc<RefCell<Environment>>> {
+        let mut env = self.get_actual_env();
+        for _ in 0..distance {
+            env = env?.borrow().enclosing.clone();
+        }
+        env
+    }
+
     fn lookup_symbol(&mut self, name: &str, expr: &Expr) -> Result<Option<Expr>, Error> {
-        let distance = self.locals.get(&expr);
+        let distance = self.distance(expr).copied();
+
+        if let (Some(distance), Some(slot)) = (distance, self.slot(expr)) {
+            let symbol = self
+                .ancestor(distance)
+                .and_then(|env| env.borrow().retrieve_slot(slot, name));
+            if symbol.is_some() {
+                return Ok(symbol);
+            }
+        }
 
         match distance {
-            Some(distance) => self.get_symbol_at(*distance as isize, name),
+            Some(distance) => self.get_symbol_at(distance as isize, name),
             None => {
-                if self.globals().as_ref().borrow().contains_key(name) {
-                    return Ok(self.globals().as_ref().borrow().retrieve(name));
-                } else {
-                    return Err(Error::new(format!("Undefined variable '{}'.", name)));
+                // Take the value out of the globals before returning so no
+                // borrow outlives this lookup (callers may recurse into accept).
+                let symbol = self.globals().borrow().retrieve(name);
+                match symbol {
+                    Some(symbol) => Ok(Some(symbol)),
+                    None => Err(Error::new(
+                        ErrorKind::UndefinedVariable,
+                        format!("Undefined variable '{}'.", name),
+                    )),
                 }
             }
         }
     }
 
     pub fn resolve(&mut self, expr: &Expr, depth: usize) {
-        self.locals.insert(expr.clone(), depth);
+        if let Some(id) = expr.id() {
+            self.locals.insert(id, depth);
+        }
+    }
+
+    /// Records which slot of its environment a resolved local lives in.
+    pub fn resolve_slot(&mut self, expr: &Expr, slot: usize) {
+        if let Some(id) = expr.id() {
+            self.slots.insert(id, slot);
+        }
     }
 
+    /// Evaluates the callee and arguments of `call`, checking the argument
+    /// count against what the callee takes.
+    fn callee_and_args(&mut self, call: &Call) -> Result<(Expr, Vec<Expr>), Error> {
+        let callee = match call.callee.accept(self)? {
+            Some(callee) => callee,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Type,
+                    "Can only call functions and classes.".to_string(),
+                ))
+            }
+        };
+
+        let args = call
+            .arguments
+            .iter()
+            .map(|arg| Ok(arg.accept(self)?.unwrap_or(Expr::Literal(Literal::Nil))))
+            .collect::<Result<Vec<Expr>, Error>>()?;
+
+        let arity = match &callee {
+            Expr::Function(fun) => Some((fun.name.as_str(), fun.parameters.len())),
+            Expr::Class(class) => {
+                let init = class.find_method("init")?;
+                Some((class.name.as_str(), init.map_or(0, |init| init.parameters.len())))
+            }
+            Expr::Native(native) if native.arity != natives::VARIADIC => {
+                Some((native.name.as_str(), native.arity))
+            }
+            _ => None,
+        };
+        if let Some((name, arity)) = arity {
+            if args.len() != arity {
+                return Err(Error::arity(name, arity, args.len()).at(&call.paren));
+            }
+        }
+        Ok((callee, args))
+    }
+
+    fn call_value(&mut self, callee: Expr, args: Vec<Expr>) -> Result<Option<Expr>, Error> {
+        match callee {
+            Expr::Function(fun) => Ok(Some(fun.execute_call(self, args)?)),
+            Expr::Class(class) => Ok(Some(class.execute_call(self, args)?)),
+            Expr::Native(Native {
+                name: _,
+                arity: _,
+                function: NativeFunction(function),
+                receiver,
+            }) => {
+                let args = receiver.map(|receiver| *receiver).into_iter().chain(args);
+                Ok(Some(function(self, args.collect())?))
+            }
+            _ => Err(Error::new(
+                ErrorKind::Type,
+                "Can only call functions and classes.".to_string(),
+            )),
+        }
+    }
+
+    /// Applies `operator`, letting an instance on the left overload it.
+    fn binary(
+        &mut self,
+        operator: &Operator,
+        left: Expr,
+        right: Expr,
+    ) -> Result<Option<Expr>, Error> {
+        if let Expr::Instance(instance) = &left {
+            let overloaded = self.call_operator_method(instance, operator, &right)?;
+            if overloaded.is_some() {
+                return Ok(overloaded);
+            }
+        }
+        if self.concat_strings && *operator == Operator::Plus {
+            if let (Expr::Literal(Literal::Str(_)), _) | (_, Expr::Literal(Literal::Str(_))) =
+                (&left, &right)
+            {
+                let joined = natives::stringify(&left) + &natives::stringify(&right);
+                return Ok(Some(Expr::Literal(Literal::Str(joined))));
+            }
+        }
+        operator.clone().binary(left, right)
+    }
+
+    fn call_operator_method(
+        &mut self,
+        instance: &Instance,
+        operator: &Operator,
+        right: &Expr,
+    ) -> Result<Option<Expr>, Error> {
+        let method = match operator.method_name() {
+            Some(name) => instance.class.find_method(name)?,
+            None => None,
+        };
+
+        match method {
+            Some(method) => {
+                let result = method.bind(instance).execute_call(self, vec![right.clone()])?;
+                if *operator == Operator::BangEqual {
+                    Ok(Some(Expr::Literal(Literal::Bool(!result.is_truthy()))))
+                } else {
+                    Ok(Some(result))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Runs `stmts` in `env`. A `return` comes back as `Some(Stmt::Return)`;
+    /// in expression context a trailing expression statement comes back as
+    /// `Some(Stmt::Expression)` holding its value.
     pub fn execute_block(
         &mut self,
         stmts: &[Stmt],
         env: Option<Rc<RefCell<Environment>>>,
+        context: BlockContext,
     ) -> Result<Option<Stmt>, Error> {
         let actual_env = self.get_actual_env();
         self.set_environment(env);
+        // Restored on errors too, or the caller would carry on in this scope.
+        let result = self.run_block(stmts, context);
+        self.set_environment(actual_env);
+        result
+    }
+
+    fn run_block(&mut self, stmts: &[Stmt], context: BlockContext) -> Result<Option<Stmt>, Error> {
+        // Functions are defined before anything else runs so they can call
+        // each other regardless of declaration order.
+        for stmt in stmts.iter().filter(|s| matches!(s, Stmt::FunDecl(_))) {
+            stmt.accept(self)?;
+        }
 
         let mut result = None;
-        for stmt in stmts {
+        let mut rest = stmts.iter().filter(|s| !matches!(s, Stmt::FunDecl(_))).peekable();
+        while let Some(stmt) = rest.next() {
+            if let (BlockContext::Expression, None, Stmt::Expression(Expression { expr })) =
+                (context, rest.peek(), stmt)
+            {
+                let value = expr.accept(self)?.unwrap_or(Expr::Literal(Literal::Nil));
+                result = Some(Stmt::Expression(Expression {
+                    expr: Box::new(value),
+                }));
+                break;
+            }
             let accepted_stmt = stmt.accept(self)?;
             match accepted_stmt {
                 Some(s) => {
@@ -253,8 +606,6 @@ impl Interpreter {
             }
         }
 
-        self.set_environment(actual_env);
-
         Ok(result)
     }
 }
@@ -267,47 +618,72 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
                 Err(e) => Err(e),
             }
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
     fn visit_print(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
         match stmt {
             Stmt::Print(Print { expr }) => {
-                let opv = expr.accept(self)?;
-                match opv {
-                    Some(Expr::Class(Class {
-                        name,
-                        methods: _,
-                        superclass: _,
-                    })) => println!("{:?}", Expr::Literal(Literal::Str(name))),
-                    Some(Expr::Instance(Instance { class, fields: _ })) => {
-                        println!("{:?}", Expr::Literal(Literal::Str(class.name)))
-                    }
-                    Some(Expr::Function(Function {
-                        name,
-                        parameters: _,
-                        body: _,
-                        context: _,
-                        is_initializer: _,
-                    })) => println!("{:?}", Expr::Literal(Literal::Str(name))),
-                    Some(pv) => println!("{:?}", pv),
-                    None => println!("None"),
-                }
+                let line = display(expr.accept(self)?);
+                writeln!(self.stdout, "{}", line)
+                    .map_err(|e| Error::new(ErrorKind::Runtime, e.to_string()))?;
                 Ok(None)
             }
-            _ => Err(Error::new("Invalid statement".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            )),
         }
     }
 
     fn visit_var_decl(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
         match stmt {
-            Stmt::VarDecl(VarDecl { name, expr }) => {
+            Stmt::VarDecl(VarDecl { name, expr, .. }) => {
                 let accepted_expr = expr.accept(self)?;
                 self.define_symbol(name.as_str(), accepted_expr.unwrap());
                 Ok(None)
             }
-            _ => Err(Error::new("Invalid statement".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            )),
+        }
+    }
+
+    fn visit_destructure(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        if let Stmt::Destructure(Destructure { names, expr }) = stmt {
+            let values = match expr.accept(self)? {
+                Some(Expr::List(List { elements })) => elements.borrow().clone(),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Type,
+                        "Can only destructure a list.".to_string(),
+                    ))
+                }
+            };
+            if values.len() != names.len() {
+                return Err(Error::new(
+                    ErrorKind::Runtime,
+                    format!(
+                        "Expected {} values to destructure but got {}.",
+                        names.len(),
+                        values.len()
+                    ),
+                ));
+            }
+            for (name, value) in names.iter().zip(values) {
+                self.define_symbol(name.as_str(), value);
+            }
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
@@ -319,14 +695,14 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
             branch_false,
         }) = stmt
         {
-            let eval_condition = condition.accept(self).unwrap();
-            res = if let Some(Expr::Literal(Literal::Bool(b))) = eval_condition {
-                match b {
-                    true => branch_true.accept(self),
-                    false => branch_false.accept(self),
-                }
-            } else {
-                Err(Error::new("Invalid condition".to_string()))
+            let eval_condition = condition.accept(self)?;
+            res = match eval_condition {
+                Some(c) if c.is_truthy() => branch_true.accept(self),
+                Some(_) => branch_false.accept(self),
+                None => Err(Error::new(
+                    ErrorKind::Runtime,
+                    "Invalid condition".to_string(),
+                )),
             };
         }
         res
@@ -334,18 +710,35 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
 
     fn visit_while(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
         let mut res = Ok(None);
-        if let Stmt::While(While { condition, body }) = stmt {
-            let mut accepted_cond = condition.accept(self).unwrap();
-            while let Some(Expr::Literal(Literal::Bool(true))) = accepted_cond {
+        if let Stmt::While(While {
+            condition,
+            body,
+            increment,
+            else_branch,
+        }) = stmt
+        {
+            let mut accepted_cond = condition.accept(self)?;
+            while accepted_cond.as_ref().is_some_and(Expr::is_truthy) {
                 res = body.accept(self);
                 match res {
                     Ok(Some(Stmt::Return(_))) => {
                         accepted_cond = Some(Expr::Literal(Literal::Bool(false)))
                     }
-                    Ok(_) => accepted_cond = condition.accept(self).unwrap(),
+                    Ok(Some(Stmt::Break(_))) => return Ok(None),
+                    Ok(_) => {
+                        // A `continue` ends the body like falling off its end.
+                        res = Ok(None);
+                        for increment in increment {
+                            increment.accept(self)?;
+                        }
+                        accepted_cond = condition.accept(self)?
+                    }
                     Err(e) => return Err(e),
                 }
             }
+            if let (Ok(None), Some(else_branch)) = (&res, else_branch) {
+                res = else_branch.accept(self);
+            }
         }
         res
     }
@@ -353,8 +746,9 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
     fn visit_block(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
         if let Stmt::Block(Block { stmts }) = stmt {
             self.new_environment(None);
-            let res = match self.execute_block(stmts, self.get_actual_env()) {
-                Ok(Some(Stmt::Return(r))) => Ok(Some(Stmt::Return(r))),
+            let env = self.get_actual_env();
+            let res = match self.execute_block(stmts, env, BlockContext::Statement) {
+                Ok(Some(s @ (Stmt::Return(_) | Stmt::Break(_) | Stmt::Continue(_)))) => Ok(Some(s)),
                 Ok(_) => Ok(None),
                 Err(e) => Err(e),
             };
@@ -362,7 +756,10 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
             self.drop_environment();
             res
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
@@ -383,6 +780,16 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
         if let Stmt::Return(Return { keyword, value }) = stmt {
             let val = match value {
                 Expr::Literal(Literal::Nil) => Expr::Literal(Literal::Nil),
+                Expr::Call(call) if call.tail.get() => {
+                    let (callee, args) = self.callee_and_args(call)?;
+                    match callee {
+                        Expr::Function(function) => {
+                            self.tail_call = Some((function, args));
+                            Expr::Literal(Literal::Nil)
+                        }
+                        callee => self.call_value(callee, args)?.unwrap(),
+                    }
+                }
                 _ => value.accept(self)?.unwrap(),
             };
 
@@ -391,7 +798,32 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
                 value: val,
             })))
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    /// Hands the statement itself back up to the enclosing loop, the way
+    /// `visit_return` hands back its value.
+    fn visit_break(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        match stmt {
+            Stmt::Break(_) => Ok(Some(stmt.clone())),
+            _ => Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            )),
+        }
+    }
+
+    fn visit_continue(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        match stmt {
+            Stmt::Continue(_) => Ok(Some(stmt.clone())),
+            _ => Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            )),
         }
     }
 
@@ -404,14 +836,19 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
         {
             let mut env = self.get_actual_env();
             let asc = if superclass.is_some() {
-                let accepted_superclass = superclass.as_ref().unwrap().accept(self).unwrap();
+                let accepted_superclass = superclass.as_ref().unwrap().accept(self)?;
                 match accepted_superclass {
                     Some(Expr::Class(Class {
                         name: _,
                         methods: _,
                         superclass: _,
                     })) => {}
-                    _ => return Err(Error::new("Superclass must be a class.".to_string())),
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Type,
+                            "Superclass must be a class.".to_string(),
+                        ))
+                    }
                 }
 
                 //self.new_environment(self.get_actual_env());
@@ -443,16 +880,133 @@ impl IVisitorStmt<Result<Option<Stmt>, Error>> for Interpreter {
 
             let class: Class = Class {
                 name: name.lexeme.clone(),
-                methods: meths,
+                methods: Rc::new(meths),
                 superclass: asc,
             };
             self.define_symbol(&name.lexeme.as_str(), Expr::Class(class));
 
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    /// An enum is an instance holding one singleton instance per member, all
+    /// of a class named after the enum, so members compare by identity.
+    fn visit_enum(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        if let Stmt::EnumDecl(EnumDecl { name, members }) = stmt {
+            let class = Class {
+                name: name.lexeme.clone(),
+                methods: Rc::new(BTreeMap::new()),
+                superclass: None,
+            };
+            let enumeration = Instance::new(class.clone());
+            for member in members {
+                let value = Expr::Instance(Instance::new(class.clone()));
+                enumeration.set_field(&member.lexeme, value);
+            }
+            self.define_symbol(&name.lexeme, Expr::Instance(enumeration));
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    /// Runs the body, and on an error runs the handler with the error
+    /// message bound to the catch variable. The error may leave the
+    /// environment chain wherever it was raised, so it is reset first.
+    fn visit_try(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        if let Stmt::Try(Try {
+            body,
+            name,
+            handler,
+        }) = stmt
+        {
+            let environments = self.get_actual_env();
+            let counter = self.counter;
+
+            self.new_environment(None);
+            let env = self.get_actual_env();
+            let error = match self.execute_block(body, env, BlockContext::Statement) {
+                Ok(Some(s @ (Stmt::Return(_) | Stmt::Break(_) | Stmt::Continue(_)))) => {
+                    self.drop_environment();
+                    return Ok(Some(s));
+                }
+                Ok(_) => {
+                    self.drop_environment();
+                    return Ok(None);
+                }
+                Err(e) => e,
+            };
+            self.set_environment(environments);
+            self.counter = counter;
+
+            self.new_environment(None);
+            self.define_symbol(&name.lexeme, Expr::Literal(Literal::Str(error.msg)));
+            let env = self.get_actual_env();
+            let res = match self.execute_block(handler, env, BlockContext::Statement) {
+                Ok(Some(s @ (Stmt::Return(_) | Stmt::Break(_) | Stmt::Continue(_)))) => Ok(Some(s)),
+                Ok(_) => Ok(None),
+                Err(e) => Err(e),
+            };
+
+            self.drop_environment();
+            res
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    fn visit_assert(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        if let Stmt::Assert(Assert {
+            keyword: _,
+            condition,
+            message,
+        }) = stmt
+        {
+            match condition.accept(self)? {
+                Some(c) if c.is_truthy() => Ok(None),
+                Some(_) => {
+                    let msg = match message {
+                        Some(message) => match message.accept(self)? {
+                            Some(Expr::Literal(Literal::Str(s))) => s,
+                            Some(other) => format!("{:?}", other),
+                            None => {
+                                return Err(Error::new(
+                                    ErrorKind::Runtime,
+                                    "Invalid message".to_string(),
+                                ))
+                            }
+                        },
+                        None => "Assertion failed.".to_string(),
+                    };
+                    Err(Error::new(ErrorKind::Runtime, msg))
+                }
+                None => Err(Error::new(
+                    ErrorKind::Runtime,
+                    "Invalid condition".to_string(),
+                )),
+            }
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
+
+    fn visit_invalid(&mut self, _: &Stmt) -> Result<Option<Stmt>, Error> {
+        Ok(None)
+    }
 }
 
 impl IVisitorExpr<Result<Option<Expr>, Error>> for Interpreter {
@@ -465,38 +1019,94 @@ impl IVisitorExpr<Result<Option<Expr>, Error>> for Interpreter {
             let accepted_right = right.accept(self)?.unwrap();
             operator.clone().unary(accepted_right)
         } else {
-            Err(Error::new("Invalid expression".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
     fn visit_binary(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
-        if let Expr::Binary(Binary {
-            left,
+        // `a + b + c` nests to the left, so walk that spine in a loop rather
+        // than recursing once per operator.
+        let mut spine: Vec<&Binary> = vec![];
+        let mut leftmost = expr;
+        while let Expr::Binary(binary) = leftmost {
+            spine.push(binary);
+            leftmost = &binary.left;
+        }
+        if spine.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ));
+        }
+
+        let mut accepted = leftmost.accept(self)?;
+        for Binary {
+            left: _,
             operator,
             right,
-        }) = expr
+        } in spine.into_iter().rev()
         {
-            let accepted_left = left.accept(self)?.unwrap();
+            let accepted_left = accepted.unwrap();
             let accepted_right = right.accept(self)?.unwrap();
-            operator.clone().binary(accepted_left, accepted_right)
-        } else {
-            Err(Error::new("Invalid expression".to_string()))
+            accepted = self.binary(operator, accepted_left, accepted_right)?;
         }
+        Ok(accepted)
     }
 
     fn visit_grouping(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Grouping(Grouping { group }) = expr {
             group.accept(self)
         } else {
-            Err(Error::new("Invalid expression".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
+        }
+    }
+
+    fn visit_list_literal(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::ListLiteral(ListLiteral { elements }) = expr {
+            let mut values = Vec::with_capacity(elements.len());
+            for element in elements {
+                values.push(element.accept(self)?.unwrap_or(Expr::Literal(Literal::Nil)));
+            }
+            Ok(Some(Expr::List(List::new(values))))
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
+        }
+    }
+
+    fn visit_lambda(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::Lambda(Lambda { declaration, .. }) = expr {
+            let function =
+                Function::from_stmt(declaration.as_ref().clone(), self.get_actual_env(), false);
+            Ok(Some(Expr::Function(function)))
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
+    fn visit_invalid_expr(&mut self, _: &Expr) -> Result<Option<Expr>, Error> {
+        Ok(Some(Expr::Literal(Literal::Nil)))
+    }
+
     fn visit_var(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Var(Var::Token(name)) = expr {
             self.lookup_symbol(name.lexeme.as_str(), expr)
         } else {
-            Err(Error::new("Invalid expression".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
@@ -507,121 +1117,144 @@ impl IVisitorExpr<Result<Option<Expr>, Error>> for Interpreter {
 
             if self.check_symbol(&var_name) {
                 let accepted_expr = value.accept(self)?.unwrap();
-                let distance = self.locals.get(expr);
-
-                match distance {
-                    Some(distance) => {
-                        self.assign_symbol_at(*distance, var_name.as_str(), accepted_expr.clone())?;
-                    }
-                    None => {
-                        self.globals()
-                            .as_ref()
-                            .borrow_mut()
-                            .define(&var_name, accepted_expr.clone());
+                let distance = self.distance(expr).copied();
+                let slot = self.slot(expr);
+                let assigned = match (distance, slot) {
+                    (Some(distance), Some(slot)) => self.ancestor(distance).is_some_and(|env| {
+                        env.borrow_mut().assign_slot(slot, &var_name, accepted_expr.clone())
+                    }),
+                    _ => false,
+                };
+                if !assigned {
+                    match distance {
+                        Some(distance) => {
+                            self.assign_symbol_at(distance, var_name.as_str(), accepted_expr.clone())?;
+                        }
+                        None => {
+                            self.globals()
+                                .as_ref()
+                                .borrow_mut()
+                                .define(&var_name, accepted_expr.clone());
+                        }
                     }
                 }
 
                 Ok(Some(accepted_expr))
             } else {
-                Err(Error::new(format!("Undefined variable '{}'.", var_name)))
+                Err(Error::new(
+                    ErrorKind::UndefinedVariable,
+                    format!("Undefined variable '{}'.", var_name),
+                ))
             }
         } else {
-            Err(Error::new("Invalid expression".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
     fn visit_logical(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
-        if let Expr::Logical(Logical {
-            left,
+        // Same as `visit_binary`: chains of `or`/`and`/`??` are walked in a loop.
+        let mut spine: Vec<&Logical> = vec![];
+        let mut leftmost = expr;
+        while let Expr::Logical(logical) = leftmost {
+            spine.push(logical);
+            leftmost = &logical.left;
+        }
+        if spine.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ));
+        }
+
+        let mut accepted = leftmost.accept(self)?;
+        for Logical {
+            left: _,
             operator,
             right,
-        }) = expr
+        } in spine.into_iter().rev()
         {
-            let left_accepted = left.accept(self).unwrap();
-            let accepted = match operator {
-                Operator::Or => {
-                    if let Some(Expr::Literal(Literal::Bool(true))) = left_accepted {
-                        left_accepted
-                    } else {
-                        right.accept(self).unwrap()
-                    }
-                }
-                _ => {
-                    if let Some(Expr::Literal(Literal::Bool(false))) = left_accepted {
-                        left_accepted
-                    } else if Some(Expr::Literal(Literal::Nil)) == left_accepted {
-                        Some(Expr::Literal(Literal::Nil))
-                    } else {
-                        right.accept(self).unwrap()
-                    }
-                }
+            let left_truthy = accepted.as_ref().is_some_and(Expr::is_truthy);
+            let short_circuit = match operator {
+                Operator::Or => left_truthy,
+                Operator::Coalesce => !matches!(accepted, None | Some(Expr::Literal(Literal::Nil))),
+                _ => !left_truthy,
             };
+            if !short_circuit {
+                accepted = right.accept(self)?;
+            }
+        }
+        Ok(accepted)
+    }
 
-            Ok(accepted)
+    fn visit_ternary(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::Ternary(Ternary {
+            condition,
+            then_branch,
+            else_branch,
+        }) = expr
+        {
+            if condition.accept(self)?.as_ref().is_some_and(Expr::is_truthy) {
+                then_branch.accept(self)
+            } else {
+                else_branch.accept(self)
+            }
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
     fn visit_call(self: &mut Interpreter, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Call(call) = expr {
-            let callee_accepted = match call.callee.accept(self) {
-                Ok(Some(callee)) => callee,
-                Ok(None) => {
-                    return Err(Error::new(
-                        "Can only call functions and classes.".to_string(),
-                    ))
-                }
-                Err(e) => return Err(e),
-            };
-
-            let args: Vec<Expr> = call
-                .arguments
-                .iter()
-                .map(|arg| arg.accept(self).unwrap().unwrap())
-                .collect();
-
-            match callee_accepted {
-                Expr::Function(fun) => {
-                    let Function {
-                        name: _,
-                        parameters,
-                        body: _,
-                        context: _,
-                        is_initializer: _,
-                    } = &fun;
-
-                    if args.len() != parameters.len() {
-                        return Err(Error::new(format!(
-                            "Invalid number of arguments (got {}, expected {})",
-                            args.len(),
-                            parameters.len()
-                        )));
-                    } else {
-                        Ok(Some(fun.execute_call(self, args)?))
-                    }
-                }
-                Expr::Class(class) => Ok(Some(class.execute_call(self, args)?)),
-                _ => Err(Error::new(
-                    "Can only call functions and classes.".to_string(),
-                )),
-            }
+            let (callee, args) = self.callee_and_args(call)?;
+            self.call_value(callee, args)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
     fn visit_get(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
-        if let Expr::Get(Get { object, name }) = expr {
+        if let Expr::Get(Get {
+            object,
+            name,
+            optional,
+        }) = expr
+        {
             let accepted_object = object.accept(self);
             match accepted_object {
                 Ok(Some(Expr::Instance(instance))) => {
                     Ok(Some(instance.get_field(name.lexeme.as_str())?))
                 }
-                _ => Err(Error::new("Only instances have properties.".to_string())),
+                Ok(Some(Expr::Literal(Literal::Nil))) if *optional => {
+                    Ok(Some(Expr::Literal(Literal::Nil)))
+                }
+                Ok(Some(Expr::Literal(literal))) => {
+                    match natives::literal_method(&literal, &name.lexeme) {
+                        Some(method) => Ok(Some(Expr::Native(method))),
+                        None => Err(Error::new(
+                            ErrorKind::Type,
+                            "Only instances have properties.".to_string(),
+                        )),
+                    }
+                }
+                _ => Err(Error::new(
+                    ErrorKind::Type,
+                    "Only instances have properties.".to_string(),
+                )),
             }
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
@@ -632,41 +1265,62 @@ impl IVisitorExpr<Result<Option<Expr>, Error>> for Interpreter {
             value,
         }) = expr
         {
-            let accepted_object = object.accept(self)?.unwrap();
+            // Anything but an instance, including no value at all, is an
+            // error rather than a panic.
+            let accepted_object = object.accept(self)?;
             match accepted_object {
-                Expr::Instance(mut instance) => {
-                    let value = value.accept(self)?.unwrap();
+                Some(Expr::Instance(instance)) => {
+                    let value = value.accept(self)?.unwrap_or(Expr::Literal(Literal::Nil));
                     instance.set_field(&name.lexeme, value.clone());
-
-                    let distance = self.locals.get(object);
-
-                    // This doesn't scale well, but it's the simplest way I can think of now
-                    let var_name = match object.as_ref() {
-                        Expr::Var(Var::Token(t)) => t.lexeme.as_str(),
-                        Expr::This(This { keyword }) => keyword.lexeme.as_str(),
-                        _ => {
-                            println!("Invalid object: {:?}", object);
-                            panic!("Invalid object")
-                        }
-                    };
-
-                    match distance {
-                        Some(distance) => {
-                            self.assign_symbol_at(*distance, &var_name, Expr::Instance(instance))?;
-                        }
-                        None => {
-                            self.globals()
-                                .as_ref()
-                                .borrow_mut()
-                                .define(var_name, Expr::Instance(instance));
-                        }
-                    }
                     Ok(Some(value))
                 }
-                _ => Err(Error::new("Only instances have fields.".to_string())),
+                _ => Err(Error::new(
+                    ErrorKind::Type,
+                    "Only instances have fields.".to_string(),
+                )),
             }
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    fn visit_index(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::Index(Index { object, index, .. }) = expr {
+            let object = object.accept(self)?;
+            let index = index.accept(self)?;
+            let (list, index) = list_slot(object, index)?;
+            let element = list.elements.borrow()[index].clone();
+            Ok(Some(element))
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
+        }
+    }
+
+    fn visit_index_set(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::IndexSet(IndexSet {
+            object,
+            index,
+            value,
+            ..
+        }) = expr
+        {
+            let object = object.accept(self)?;
+            let index = index.accept(self)?;
+            let (list, index) = list_slot(object, index)?;
+            let value = value.accept(self)?.unwrap_or(Expr::Literal(Literal::Nil));
+            list.elements.borrow_mut()[index] = value.clone();
+            Ok(Some(value))
+        } else {
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
@@ -674,13 +1328,16 @@ impl IVisitorExpr<Result<Option<Expr>, Error>> for Interpreter {
         if let Expr::This(This { keyword }) = expr {
             self.lookup_symbol(&keyword.lexeme, expr)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
     fn visit_super(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Super(Super { keyword: _, method }) = expr {
-            let distance = self.locals.get(&expr).unwrap();
+            let distance = self.distance(expr).unwrap();
 
             let d = *distance;
             let superclass = self.get_symbol_at(d as isize, "super")?.unwrap();
@@ -692,24 +1349,66 @@ impl IVisitorExpr<Result<Option<Expr>, Error>> for Interpreter {
             let func = superclass.find_method(method.lexeme.as_str());
             match func {
                 Ok(Some(func)) => Ok(Some(Expr::Function(func.bind(&object)))),
-                _ => Err(Error::new(format!(
-                    "Undefined property '{}'.",
-                    method.lexeme
-                ))),
+                _ => Err(Error::new(
+                    ErrorKind::UndefinedProperty,
+                    format!("Undefined property '{}'.", method.lexeme),
+                )),
             }
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Runtime,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
     use ordered_float::OrderedFloat;
 
-    use crate::interpreter::ast::{Expr, Literal};
+    use crate::interpreter::ast::{
+        Error, ErrorKind, Expr, Expression, List, Literal, Location, Stmt,
+    };
+    use crate::interpreter::lexer::Lexer;
+    use crate::interpreter::parser::{Parser, MAX_NESTING_DEPTH};
+    use crate::interpreter::visitors::resolver::Resolver;
+
+    use super::{BlockContext, Interpreter};
+
+    fn interpret(source: &str) -> Result<Interpreter, Error> {
+        interpret_with_slots(source, true)
+    }
+
+    fn interpret_with_slots(source: &str, slot_lookup: bool) -> Result<Interpreter, Error> {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens()?;
+        run(Parser::new(lexer.tokens), slot_lookup)
+    }
 
-    use super::Interpreter;
+    fn run(mut parser: Parser, slot_lookup: bool) -> Result<Interpreter, Error> {
+        let ast = parser.parse().into_iter().collect::<Result<Vec<Stmt>, Error>>()?;
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_slot_lookup(slot_lookup);
+        let mut resolver = Resolver::new(&mut interpreter);
+        for stmt in &ast {
+            stmt.accept(&mut resolver)?;
+        }
+        for stmt in &ast {
+            stmt.accept(&mut interpreter)?;
+        }
+        Ok(interpreter)
+    }
+
+    fn global(interpreter: &mut Interpreter, name: &str) -> Option<Expr> {
+        interpreter.globals().borrow().retrieve(name)
+    }
 
     #[test]
     fn environment_lifecycle() {
@@ -742,6 +1441,63 @@ mod tests {
         //assert_eq!(interpreter.counter, 1);
         //Need to decide behavior here
     }
+    #[test]
+    fn runtime_error_in_a_call_restores_the_callers_scope() {
+        let mut lexer = Lexer::new(
+            "var g = \"global\";
+            fun fail() { var inner = \"callee\"; return missing; }
+            fail();
+            var after = g;",
+        );
+        lexer.scan_tokens().unwrap();
+        let ast = Parser::new(lexer.tokens)
+            .parse()
+            .into_iter()
+            .collect::<Result<Vec<Stmt>, Error>>()
+            .unwrap();
+
+        let mut interpreter = Interpreter::new();
+        let mut resolver = Resolver::new(&mut interpreter);
+        for stmt in &ast {
+            stmt.accept(&mut resolver).unwrap();
+        }
+        let errors: Vec<String> = ast
+            .iter()
+            .filter_map(|stmt| stmt.accept(&mut interpreter).err())
+            .map(|error| error.msg)
+            .collect();
+        assert_eq!(errors, vec!["Undefined variable 'missing'."]);
+
+        let globals = interpreter.globals();
+        assert!(Rc::ptr_eq(&interpreter.get_actual_env().unwrap(), &globals));
+        assert_eq!(global(&mut interpreter, "after"), Some(string("global")));
+        assert_eq!(global(&mut interpreter, "inner"), None);
+    }
+
+    #[test]
+    fn bound_method_keeps_its_instance_after_reassignment() {
+        let mut interpreter = interpret(
+            "class Named {
+              init(name) { this.name = name; }
+              who() { return this.name; }
+            }
+            var obj = Named(\"first\");
+            var who = obj.who;
+            obj = Named(\"second\");
+            var global_who = who();
+            var local_who;
+            {
+              var local = Named(\"inner\");
+              var bound = local.who;
+              local = Named(\"replaced\");
+              local_who = bound();
+            }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "global_who"), Some(string("first")));
+        assert_eq!(global(&mut interpreter, "local_who"), Some(string("inner")));
+    }
+
     #[test]
     fn environment_manipulation() {
         let mut interpreter = Interpreter::new();
@@ -802,4 +1558,1782 @@ mod tests {
         assert_eq!(interpreter.get_symbol_at(0, "a").unwrap(), None);
         assert_eq!(interpreter.get_symbol_at(2, "a").unwrap(), None);
     }
+
+    #[test]
+    fn method_reads_fields_and_calls_method_reading_fields() {
+        let mut interpreter = interpret(
+            "class Point {
+                init(x, y) { this.x = x; this.y = y; }
+                sum() { return this.x + this.y; }
+                total() { return this.x + this.sum(); }
+            }
+            var p = Point(1, 2);
+            var total = p.total();
+            var sum = p.sum();",
+        )
+        .unwrap();
+
+        assert_eq!(
+            global(&mut interpreter, "total"),
+            Some(Expr::Literal(Literal::Number(OrderedFloat(4.0))))
+        );
+        assert_eq!(
+            global(&mut interpreter, "sum"),
+            Some(Expr::Literal(Literal::Number(OrderedFloat(3.0))))
+        );
+    }
+
+    #[test]
+    fn identical_references_resolve_independently() {
+        let interpreter = interpret(
+            "{
+                var a = 1;
+                { var b = a; }
+                var c = a;
+            }",
+        )
+        .unwrap();
+
+        let mut distances: Vec<usize> = interpreter.locals.values().cloned().collect();
+        distances.sort();
+        assert_eq!(distances, vec![0, 1]);
+    }
+
+    #[test]
+    fn separately_parsed_sources_get_distinct_ids() {
+        // As between REPL lines: the last line reads a global declared by an
+        // earlier one, from where the middle line had a local resolved one
+        // scope out.
+        let middle = "{ var x = 1; { var y = x; } }";
+        let column = middle.rfind("x;").unwrap();
+        let last = format!("{}var z = x;", " ".repeat(column - 8));
+        assert_eq!(last.rfind("x;"), Some(column));
+
+        let mut interpreter = Interpreter::new();
+        for source in ["var x = 5;", middle, last.as_str()] {
+            let mut lexer = Lexer::new(source);
+            lexer.scan_tokens().unwrap();
+            let ast = Parser::new(lexer.tokens)
+                .parse()
+                .into_iter()
+                .collect::<Result<Vec<Stmt>, Error>>()
+                .unwrap();
+            let mut resolver = Resolver::new(&mut interpreter);
+            for stmt in &ast {
+                stmt.accept(&mut resolver).unwrap();
+            }
+            for stmt in &ast {
+                stmt.accept(&mut interpreter).unwrap();
+            }
+        }
+        assert_eq!(global(&mut interpreter, "z"), Some(number(5.0)));
+    }
+
+    #[test]
+    fn invalid_statements_are_skipped() {
+        let mut lexer = Lexer::new("var a = 1; var b = ; a = a + 1;");
+        lexer.scan_tokens().unwrap();
+        let (ast, errors) = Parser::new(lexer.tokens).parse_partial();
+        assert_eq!(errors.len(), 1);
+
+        let mut interpreter = Interpreter::new();
+        let mut resolver = Resolver::new(&mut interpreter);
+        for stmt in &ast {
+            stmt.accept(&mut resolver).unwrap();
+        }
+        for stmt in &ast {
+            stmt.accept(&mut interpreter).unwrap();
+        }
+
+        assert_eq!(
+            global(&mut interpreter, "a"),
+            Some(Expr::Literal(Literal::Number(OrderedFloat(2.0))))
+        );
+        assert_eq!(global(&mut interpreter, "b"), None);
+    }
+
+    #[test]
+    fn operator_overloading_methods() {
+        let mut interpreter = interpret(
+            "class Vector {
+                init(x, y) { this.x = x; this.y = y; }
+                add(other) { return Vector(this.x + other.x, this.y + other.y); }
+                equals(other) { return this.x == other.x and this.y == other.y; }
+            }
+            var v = Vector(1, 2) + Vector(3, 4);
+            var x = v.x;
+            var y = v.y;
+            var same = Vector(1, 2) == Vector(1, 2);
+            var different = Vector(1, 2) != Vector(1, 2);
+            var other = Vector(1, 2) == Vector(2, 1);",
+        )
+        .unwrap();
+
+        let number = |n: f64| Some(Expr::Literal(Literal::Number(OrderedFloat(n))));
+        assert_eq!(global(&mut interpreter, "x"), number(4.0));
+        assert_eq!(global(&mut interpreter, "y"), number(6.0));
+        assert_eq!(
+            global(&mut interpreter, "same"),
+            Some(Expr::Literal(Literal::Bool(true)))
+        );
+        assert_eq!(
+            global(&mut interpreter, "different"),
+            Some(Expr::Literal(Literal::Bool(false)))
+        );
+        assert_eq!(
+            global(&mut interpreter, "other"),
+            Some(Expr::Literal(Literal::Bool(false)))
+        );
+    }
+
+    #[test]
+    fn operator_without_overload_keeps_error() {
+        let error = interpret("class Plain {} var p = Plain() + Plain();").unwrap_err();
+        assert_eq!(error.msg, "Operands must be two numbers or two strings.");
+    }
+
+    #[test]
+    fn dynamic_field_access_by_name() {
+        let mut interpreter = interpret(
+            "class Point {}
+            var p = Point();
+            var axis = \"x\";
+            set_field(p, axis + \"_pos\", 3);
+            var direct = p.x_pos;
+            var dynamic = get_field(p, \"x\" + \"_pos\");",
+        )
+        .unwrap();
+        let three = Expr::Literal(Literal::Number(OrderedFloat(3.0)));
+        assert_eq!(global(&mut interpreter, "direct"), Some(three.clone()));
+        assert_eq!(global(&mut interpreter, "dynamic"), Some(three));
+    }
+
+    #[test]
+    fn dynamic_field_access_requires_instance() {
+        let error = interpret("var v = get_field(1, \"x\");").unwrap_err();
+        assert_eq!(error.msg, "Only instances have fields.");
+        let error = interpret("set_field(\"s\", \"x\", 1);").unwrap_err();
+        assert_eq!(error.msg, "Only instances have fields.");
+    }
+
+    #[test]
+    fn range_is_half_open_list_of_numbers() {
+        let mut interpreter =
+            interpret("var r = 0..3; var empty = 3..3; var same = deep_eq(0..3, 0..1 + 2);")
+                .unwrap();
+        let numbers = |ns: &[f64]| {
+            Some(Expr::List(List::new(
+                ns.iter()
+                    .map(|n| Expr::Literal(Literal::Number(OrderedFloat(*n))))
+                    .collect(),
+            )))
+        };
+        assert_eq!(global(&mut interpreter, "r"), numbers(&[0.0, 1.0, 2.0]));
+        assert_eq!(global(&mut interpreter, "empty"), numbers(&[]));
+        assert_eq!(
+            global(&mut interpreter, "same"),
+            Some(Expr::Literal(Literal::Bool(true)))
+        );
+    }
+
+    #[test]
+    fn lists_are_equal_only_to_themselves() {
+        let mut interpreter = interpret(
+            "var l = [1, 2]; l[1] = l;
+            var same = l == l;
+            var copy = l == [1, l];
+            var literals = [1] != [1];",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "same"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "copy"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "literals"), Some(boolean(true)));
+    }
+
+    #[test]
+    fn range_requires_integer_endpoints() {
+        let error = interpret("var r = 0..2.5;").unwrap_err();
+        assert_eq!(error.msg, "Range endpoints must be integers.");
+        let error = interpret("var r = \"a\"..2;").unwrap_err();
+        assert_eq!(error.msg, "Range endpoints must be integers.");
+    }
+
+    #[test]
+    fn ranges_are_limited_in_length() {
+        let error = interpret("var r = 0..1000000000000000;").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime);
+        assert_eq!(error.msg, "Range is longer than 1048576 elements.");
+        assert!(interpret("var r = -1000000000000000..0;").is_err());
+        assert!(interpret("var r = 1000000000000000..0;").is_ok());
+    }
+
+    #[test]
+    fn mutually_recursive_functions_in_block() {
+        let mut interpreter = interpret(
+            "var even; var odd;
+            {
+                fun is_even(n) { if (n == 0) return true; return is_odd(n - 1); }
+                fun is_odd(n) { if (n == 0) return false; return is_even(n - 1); }
+                even = is_even(4);
+                odd = is_odd(4);
+            }",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "even"),
+            Some(Expr::Literal(Literal::Bool(true)))
+        );
+        assert_eq!(
+            global(&mut interpreter, "odd"),
+            Some(Expr::Literal(Literal::Bool(false)))
+        );
+    }
+
+    #[test]
+    fn while_condition_reruns_stateful_call() {
+        let mut interpreter = interpret(
+            "fun counter(limit) {
+                var i = 0;
+                fun next() {
+                    i = i + 1;
+                    if (i > limit) return nil;
+                    return i;
+                }
+                return next;
+            }
+            var next = counter(3);
+            var v;
+            var sum = 0;
+            var loops = 0;
+            while (v = next()) {
+                sum = sum + v;
+                loops = loops + 1;
+            }",
+        )
+        .unwrap();
+        let number = |n: f64| Some(Expr::Literal(Literal::Number(OrderedFloat(n))));
+        assert_eq!(global(&mut interpreter, "sum"), number(6.0));
+        assert_eq!(global(&mut interpreter, "loops"), number(3.0));
+    }
+
+    #[test]
+    fn if_condition_error_is_propagated() {
+        let error = interpret("if (-\"a\") print 1;").unwrap_err();
+        assert_eq!(error.msg, "Operand must be a number.");
+        let error = interpret("if (missing) print 1;").unwrap_err();
+        assert_eq!(error.msg, "Undefined variable 'missing'.");
+    }
+
+    #[test]
+    fn if_condition_uses_truthiness() {
+        let mut interpreter = interpret(
+            "var a = \"no\";
+            var b = \"no\";
+            if (0) a = \"yes\";
+            if (nil) {} else b = \"yes\";",
+        )
+        .unwrap();
+        let yes = Some(Expr::Literal(Literal::Str("yes".to_string())));
+        assert_eq!(global(&mut interpreter, "a"), yes);
+        assert_eq!(global(&mut interpreter, "b"), yes);
+    }
+
+    #[test]
+    fn while_condition_uses_truthiness() {
+        let mut interpreter = interpret(
+            "var runs = 0;
+            while (\"x\") { runs = runs + 1; if (runs == 2) break; }
+            var never = true;
+            while (nil) never = false;
+            var item = 1;
+            var counted = 0;
+            while (item) { counted = counted + 1; if (item == 3) item = nil; else item = item + 1; }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "runs"), Some(number(2.0)));
+        assert_eq!(global(&mut interpreter, "never"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "counted"), Some(number(3.0)));
+    }
+
+    #[test]
+    fn while_else_runs_unless_the_loop_breaks() {
+        let mut interpreter = interpret(
+            "var i = 0;
+            var completed = false;
+            while (i < 3) { i = i + 1; } else completed = true;
+            var broken = false;
+            while (true) { break; } else { broken = true; }
+            var skipped = false;
+            while (false) {} else skipped = true;
+            fun find(n) { while (true) { return n; } else { return -1; } }
+            var found = find(7);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "i"), Some(number(3.0)));
+        assert_eq!(global(&mut interpreter, "completed"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "broken"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "skipped"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "found"), Some(number(7.0)));
+    }
+
+    #[test]
+    fn tail_calls_do_not_grow_the_stack() {
+        let mut interpreter = interpret(
+            "fun count(n, total) {
+                if (n == 0) return total;
+                return count(n - 1, total + 1);
+            }
+            fun even(n) { if (n == 0) return true; return odd(n - 1); }
+            fun odd(n) { if (n == 0) return false; return even(n - 1); }
+            class Counter {
+                down(n) { if (n == 0) return \"done\"; return this.down(n - 1); }
+            }
+            var counted = count(100000, 0);
+            var parity = even(100001);
+            var method = Counter().down(100000);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "counted"), Some(number(100000.0)));
+        assert_eq!(global(&mut interpreter, "parity"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "method"), Some(string("done")));
+    }
+
+    #[test]
+    fn errors_from_a_call_returned_inside_try_are_caught() {
+        let mut interpreter = interpret(
+            "fun fail() { panic(\"boom\"); }
+            fun guarded() {
+                try { return fail(); } catch (e) { return e; }
+            }
+            var caught = guarded();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "caught"), Some(string("boom")));
+    }
+
+    #[test]
+    fn while_without_a_block_leaves_the_else_to_the_if() {
+        let mut interpreter = interpret(
+            "var taken = \"none\";
+            if (false) while (false) taken = \"while\"; else taken = \"if\";",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "taken"), Some(string("if")));
+    }
+
+    #[test]
+    fn while_condition_error_is_propagated() {
+        let error = interpret("while (missing) {}").unwrap_err();
+        assert_eq!(error.msg, "Undefined variable 'missing'.");
+
+        // The condition only fails once it is evaluated again after the body.
+        let error = interpret("var i = 0; while (i < 1) i = \"done\";").unwrap_err();
+        assert_eq!(error.msg, "Operands must be two numbers or two strings.");
+    }
+
+    #[test]
+    fn to_json_serializes_nested_instances() {
+        let mut interpreter = interpret(
+            "class Point { norm() { return 0; } }
+            class Shape {}
+            var origin = Point();
+            origin.x = 0;
+            origin.y = -1.5;
+            var shape = Shape();
+            shape.name = \"tri\\tangle\";
+            shape.visible = true;
+            shape.parent = nil;
+            shape.origin = origin;
+            shape.sides = 0..3;
+            shape.norm = origin.norm;
+            var json = to_json(shape);",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "json"),
+            Some(Expr::Literal(Literal::Str(
+                "{\"name\":\"tri\\tangle\",\"norm\":null,\"origin\":{\"x\":0,\"y\":-1.5},\
+                 \"parent\":null,\"sides\":[0,1,2],\"visible\":true}"
+                    .to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn to_json_rejects_cycles() {
+        let error = interpret("class Node {} var n = Node(); n.next = n; to_json(n);").unwrap_err();
+        assert_eq!(error.msg, "Cannot serialize a value that contains itself.");
+    }
+
+    fn block_result(source: &str, context: BlockContext) -> Option<Stmt> {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let stmts: Vec<Stmt> = Parser::new(lexer.tokens)
+            .parse()
+            .into_iter()
+            .map(|stmt| stmt.unwrap())
+            .collect();
+        let mut interpreter = Interpreter::new();
+        let env = interpreter.get_actual_env();
+        interpreter.execute_block(&stmts, env, context).unwrap()
+    }
+
+    #[test]
+    fn statement_block_discards_final_value() {
+        assert_eq!(block_result("1 + 2; 3 * 4;", BlockContext::Statement), None);
+    }
+
+    #[test]
+    fn expression_block_yields_final_value() {
+        let result = block_result("var a = 3; 1 + 2; a * 4;", BlockContext::Expression);
+        assert_eq!(
+            result,
+            Some(Stmt::Expression(Expression {
+                expr: Box::new(Expr::Literal(Literal::Number(OrderedFloat(12.0))))
+            }))
+        );
+        // Only a trailing expression statement gives the block a value.
+        assert_eq!(block_result("1 + 2; var a = 3;", BlockContext::Expression), None);
+    }
+
+    #[test]
+    fn callables_and_instances_compare_unequal_to_nil() {
+        let mut interpreter = interpret(
+            "fun f() {}
+            class C {}
+            var i = C();
+            var eq = f == nil or nil == f or C == nil or nil == C or i == nil or nil == i
+                or get_field == nil;
+            var ne = f != nil and nil != f and C != nil and nil != C and i != nil and nil != i
+                and get_field != nil;",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "eq"),
+            Some(Expr::Literal(Literal::Bool(false)))
+        );
+        assert_eq!(
+            global(&mut interpreter, "ne"),
+            Some(Expr::Literal(Literal::Bool(true)))
+        );
+    }
+
+    #[test]
+    fn globals_lists_sorted_user_globals() {
+        let mut interpreter = interpret(
+            "var zeta = 1;
+            fun alpha() {}
+            class Mid {}
+            { var local = 2; }
+            var tick = len;
+            var join = 1;
+            var names = globals();",
+        )
+        .unwrap();
+        // `names` itself is only defined once globals() has returned.
+        assert_eq!(
+            global(&mut interpreter, "names"),
+            Some(Expr::List(List::new(
+                ["Mid", "alpha", "tick", "zeta"]
+                    .iter()
+                    .map(|n| Expr::Literal(Literal::Str(n.to_string())))
+                    .collect()
+            )))
+        );
+    }
+
+    #[test]
+    fn passing_assertions_do_nothing() {
+        let mut interpreter = interpret(
+            "var a = 1;
+            assert a == 1;
+            assert \"yes\" : \"strings are truthy\";
+            a = 2;",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "a"),
+            Some(Expr::Literal(Literal::Number(OrderedFloat(2.0))))
+        );
+    }
+
+    #[test]
+    fn failing_assertion_uses_default_message() {
+        let error = interpret("assert 1 > 2;").unwrap_err();
+        assert_eq!(error.msg, "Assertion failed.");
+    }
+
+    #[test]
+    fn failing_assertion_uses_given_message() {
+        let error = interpret("var n = nil; assert n : \"n must be set\";").unwrap_err();
+        assert_eq!(error.msg, "n must be set");
+    }
+
+    #[test]
+    fn assertion_message_is_only_evaluated_on_failure() {
+        assert!(interpret("assert true : missing;").is_ok());
+        let error = interpret("assert false : missing;").unwrap_err();
+        assert_eq!(error.msg, "Undefined variable 'missing'.");
+    }
+
+    fn evaluate(source: &str) -> Expr {
+        let mut interpreter = interpret(&format!("var result = {};", source)).unwrap();
+        global(&mut interpreter, "result").unwrap()
+    }
+
+    fn string(s: &str) -> Expr {
+        Expr::Literal(Literal::Str(s.to_string()))
+    }
+
+    fn boolean(b: bool) -> Expr {
+        Expr::Literal(Literal::Bool(b))
+    }
+
+    fn number(n: f64) -> Expr {
+        Expr::Literal(Literal::Number(OrderedFloat(n)))
+    }
+
+    #[test]
+    fn contains_native() {
+        assert_eq!(evaluate("contains(\"haystack\", \"st\")"), boolean(true));
+        assert_eq!(evaluate("contains(\"haystack\", \"needle\")"), boolean(false));
+        assert_eq!(evaluate("contains(\"haystack\", \"\")"), boolean(true));
+        assert_eq!(
+            interpret("contains(\"haystack\", 1);").unwrap_err().msg,
+            "Arguments must be strings."
+        );
+    }
+
+    #[test]
+    fn starts_with_and_ends_with_natives() {
+        assert_eq!(evaluate("starts_with(\"lox\", \"lo\")"), boolean(true));
+        assert_eq!(evaluate("starts_with(\"lox\", \"ox\")"), boolean(false));
+        assert_eq!(evaluate("starts_with(\"lox\", \"\")"), boolean(true));
+        assert_eq!(evaluate("ends_with(\"lox\", \"ox\")"), boolean(true));
+        assert_eq!(evaluate("ends_with(\"lox\", \"lo\")"), boolean(false));
+        assert_eq!(evaluate("ends_with(\"\", \"\")"), boolean(true));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn env_reads_the_injected_environment() {
+        let mut interpreter = interpret("").unwrap();
+        interpreter.set_env(HashMap::from([("LOX_MODE".to_string(), "debug".to_string())]));
+        let Some(Expr::Native(env)) = global(&mut interpreter, "env") else {
+            panic!("env is not a native");
+        };
+        let present = (env.function.0)(&mut interpreter, vec![string("LOX_MODE")]);
+        assert_eq!(present.unwrap(), string("debug"));
+        let absent = (env.function.0)(&mut interpreter, vec![string("PATH")]);
+        assert_eq!(absent.unwrap(), Expr::Literal(Literal::Nil));
+        let error = (env.function.0)(&mut interpreter, vec![number(1.0)]).unwrap_err();
+        assert_eq!(error.msg, "Arguments must be strings.");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn clock_returns_increasing_seconds() {
+        let mut interpreter = interpret(
+            "var first = clock();
+            var second = clock();
+            var ordered = first <= second;",
+        )
+        .unwrap();
+        let Some(Expr::Literal(Literal::Number(first))) = global(&mut interpreter, "first") else {
+            panic!("clock did not return a number");
+        };
+        assert!(first.0 > 0.0);
+        assert_eq!(global(&mut interpreter, "ordered"), Some(boolean(true)));
+        assert_eq!(interpret("clock(1);").unwrap_err().kind, ErrorKind::Arity);
+    }
+
+    #[test]
+    fn index_of_counts_characters() {
+        assert_eq!(evaluate("index_of(\"banana\", \"na\")"), number(2.0));
+        assert_eq!(evaluate("index_of(\"banana\", \"x\")"), number(-1.0));
+        assert_eq!(evaluate("index_of(\"banana\", \"\")"), number(0.0));
+
+        // The lexer only reads ASCII source, so call the native directly.
+        let mut interpreter = interpret("").unwrap();
+        let Some(Expr::Native(index_of)) = global(&mut interpreter, "index_of") else {
+            panic!("index_of is not a native");
+        };
+        let args = vec![string("héllo"), string("l")];
+        assert_eq!((index_of.function.0)(&mut interpreter, args).unwrap(), number(2.0));
+    }
+
+    #[test]
+    fn replace_native() {
+        assert_eq!(evaluate("replace(\"a-b-c\", \"-\", \"+\")"), string("a+b+c"));
+        assert_eq!(evaluate("replace(\"abc\", \"x\", \"y\")"), string("abc"));
+        assert_eq!(evaluate("replace(\"abc\", \"\", \"y\")"), string("abc"));
+        assert_eq!(evaluate("replace(\"abc\", \"b\", \"\")"), string("ac"));
+    }
+
+    fn strings(items: &[&str]) -> Expr {
+        Expr::List(List::new(items.iter().map(|s| string(s)).collect()))
+    }
+
+    #[test]
+    fn split_native() {
+        assert_eq!(evaluate("split(\"a,b,c\", \",\")"), strings(&["a", "b", "c"]));
+        assert_eq!(evaluate("split(\"a,,b\", \",\")"), strings(&["a", "", "b"]));
+        assert_eq!(evaluate("split(\"abc\", \"\")"), strings(&["a", "b", "c"]));
+        assert_eq!(evaluate("split(\"abc\", \";\")"), strings(&["abc"]));
+        assert_eq!(evaluate("split(\"\", \",\")"), strings(&[]));
+    }
+
+    #[test]
+    fn join_native() {
+        assert_eq!(evaluate("join(split(\"a,b,c\", \",\"), \",\")"), string("a,b,c"));
+        assert_eq!(evaluate("join(split(\"abc\", \"\"), \"-\")"), string("a-b-c"));
+        assert_eq!(evaluate("join(split(\"\", \",\"), \",\")"), string(""));
+        assert_eq!(
+            interpret("join(1..3, \",\");").unwrap_err().msg,
+            "List elements must be strings."
+        );
+        assert_eq!(
+            interpret("join(\"abc\", \",\");").unwrap_err().msg,
+            "Can only join a list."
+        );
+    }
+
+    #[test]
+    fn split_join_round_trip() {
+        let source = "var same = join(split(\"a,b,c\", \",\"), \",\") == \"a,b,c\";";
+        let mut interpreter = interpret(source).unwrap();
+        assert_eq!(global(&mut interpreter, "same"), Some(boolean(true)));
+    }
+
+    #[test]
+    fn deep_eq_compares_structure() {
+        let mut interpreter = interpret(
+            "class Point { init(x, y) { this.x = x; this.y = y; this.tags = [x, [y]]; } }
+            var a = Point(1, 2);
+            var b = Point(1, 2);
+            var c = Point(1, 3);
+            var same = deep_eq(a, b);
+            var identical = a == b;
+            var different = deep_eq(a, c);
+            b.extra = nil;
+            var extra_field = deep_eq(a, b);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "same"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "identical"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "different"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "extra_field"), Some(boolean(false)));
+
+        assert_eq!(evaluate("deep_eq([1, [\"a\"]], [1, [\"a\"]])"), boolean(true));
+        assert_eq!(evaluate("deep_eq([1, 2], [1])"), boolean(false));
+        assert_eq!(evaluate("deep_eq(1, \"1\")"), boolean(false));
+        assert_eq!(evaluate("deep_eq(nil, nil)"), boolean(true));
+    }
+
+    #[test]
+    fn deep_eq_terminates_on_cycles() {
+        let mut interpreter = interpret(
+            "class Node {}
+            var a = Node();
+            a.next = a;
+            var b = Node();
+            b.next = b;
+            var nodes = deep_eq(a, b);
+            var l = [1, nil];
+            l[1] = l;
+            var m = [1, nil];
+            m[1] = m;
+            var lists = deep_eq(l, m);
+            m[0] = 2;
+            var changed = deep_eq(l, m);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "nodes"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "lists"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "changed"), Some(boolean(false)));
+    }
+
+    #[test]
+    fn keys_values_and_has_inspect_instance_fields() {
+        let mut interpreter = interpret(
+            "class Point { norm() { return this.x + this.y; } }
+            var p = Point();
+            p.y = 2;
+            p.x = 1;
+            var names = keys(p);
+            var fields = values(p);
+            var has_x = has(p, \"x\");
+            var has_z = has(p, \"z\");
+            var has_method = has(p, \"norm\");
+            var empty = keys(Point());",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "names"), Some(strings(&["x", "y"])));
+        assert_eq!(
+            global(&mut interpreter, "fields"),
+            Some(Expr::List(List::new(vec![number(1.0), number(2.0)])))
+        );
+        assert_eq!(global(&mut interpreter, "has_x"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "has_z"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "has_method"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "empty"), Some(strings(&[])));
+
+        let error = interpret("keys([1]);").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+        assert_eq!(error.msg, "Only instances have fields.");
+    }
+
+    #[test]
+    fn len_native() {
+        assert_eq!(evaluate("len(\"hello\")"), number(5.0));
+        assert_eq!(evaluate("len(\"\")"), number(0.0));
+        assert_eq!(evaluate("len([1, [2, 3], nil])"), number(3.0));
+        assert_eq!(evaluate("len(0..4)"), number(4.0));
+
+        let error = interpret("len(5);").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+        assert_eq!(error.msg, "Can only take the length of a string or a list.");
+    }
+
+    #[test]
+    fn negative_zero_equals_zero() {
+        assert_eq!(evaluate("-0 == 0"), boolean(true));
+        assert_eq!(evaluate("0 != -0"), boolean(false));
+        assert_eq!(evaluate("-0 + 1 == 0 + 1"), boolean(true));
+    }
+
+    #[test]
+    fn programs_nested_to_the_limit_run() {
+        let depth = MAX_NESTING_DEPTH - 1;
+        let nested = |open: &str, close: &str| {
+            format!("{}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        assert_eq!(evaluate(&nested("(", ")")), number(1.0));
+        assert_eq!(evaluate(&nested("1 + (", ")")), number(depth as f64 + 1.0));
+        assert_eq!(evaluate(&nested("[", "][0]")), number(1.0));
+
+        let mut interpreter = interpret(&format!(
+            "fun id(x) {{ return x; }} var result = {};",
+            nested("id(", ")")
+        ))
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "result"), Some(number(1.0)));
+    }
+
+    #[test]
+    fn long_operator_chains_do_not_overflow() {
+        let terms = 1000;
+        let evaluate = |source: &str| {
+            let source = format!("var result = {};", source);
+            let mut interpreter = interpret(&source).unwrap();
+            global(&mut interpreter, "result").unwrap()
+        };
+        let sum = vec!["1"; terms].join(" + ");
+        assert_eq!(evaluate(&sum), number(terms as f64));
+
+        let falses = vec!["false"; terms].join(" or ");
+        assert_eq!(evaluate(&format!("{} or \"last\"", falses)), string("last"));
+        let trues = vec!["true"; terms].join(" and ");
+        assert_eq!(evaluate(&format!("{} and nil", trues)), Expr::Literal(Literal::Nil));
+    }
+
+    #[test]
+    fn type_native_names_each_kind() {
+        assert_eq!(evaluate("type(1)"), string("number"));
+        assert_eq!(evaluate("type(\"s\")"), string("string"));
+        assert_eq!(evaluate("type(true)"), string("bool"));
+        assert_eq!(evaluate("type(nil)"), string("nil"));
+        assert_eq!(evaluate("type(type)"), string("function"));
+        assert_eq!(evaluate("type(1..3)"), string("list"));
+
+        let mut interpreter = interpret(
+            "class Point {}
+            fun f() {}
+            var of_function = type(f);
+            var of_class = type(Point);
+            var of_instance = type(Point());",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "of_function"), Some(string("function")));
+        assert_eq!(global(&mut interpreter, "of_class"), Some(string("class")));
+        assert_eq!(global(&mut interpreter, "of_instance"), Some(string("instance")));
+    }
+
+    #[test]
+    fn is_native_walks_superclasses() {
+        let mut interpreter = interpret(
+            "class Shape {}
+            class Circle < Shape {}
+            class Other {}
+            var circle = Circle();
+            var direct = is(circle, Circle);
+            var inherited = is(circle, Shape);
+            var unrelated = is(circle, Other);
+            var parent = is(Shape(), Circle);
+            var number = is(1, Shape);
+            var of_class = is(Circle, Circle);",
+        )
+        .unwrap();
+        for (name, expected) in [
+            ("direct", true),
+            ("inherited", true),
+            ("unrelated", false),
+            ("parent", false),
+            ("number", false),
+            ("of_class", false),
+        ] {
+            assert_eq!(global(&mut interpreter, name), Some(boolean(expected)), "{}", name);
+        }
+        assert_eq!(
+            interpret("class A {} is(A(), 1);").unwrap_err().msg,
+            "Second argument must be a class."
+        );
+    }
+
+    #[test]
+    fn classes_with_the_same_name_are_distinct() {
+        let mut interpreter = interpret(
+            "class Shape {}
+            var outer = Shape;
+            var shape = Shape();
+            var is_local;
+            var same;
+            {
+                class Shape {}
+                is_local = is(shape, Shape);
+                same = outer == Shape;
+            }
+            var is_outer = is(shape, outer);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "is_local"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "same"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "is_outer"), Some(boolean(true)));
+    }
+
+    #[test]
+    fn functions_equal_only_themselves() {
+        let mut interpreter = interpret(
+            "fun make() { fun f() {} return f; }
+            var a = make();
+            var b = make();
+            var copy = a;
+            var distinct = a == b;
+            var same = a == copy;
+            fun g() {}
+            fun h() {}
+            var siblings = g == h;
+            class C { m() {} }
+            var c = C();
+            var bound = c.m;
+            var rebound = bound == c.m;
+            var kept = bound == bound;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "distinct"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "same"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "siblings"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "rebound"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "kept"), Some(boolean(true)));
+    }
+
+    #[test]
+    fn methods_returning_this_chain() {
+        let mut interpreter = interpret(
+            "class Builder {
+                init() {
+                    this.parts = \"\";
+                }
+                add(part) {
+                    this.parts = this.parts + part;
+                    return this;
+                }
+                build() {
+                    return this.parts;
+                }
+            }
+            var builder = Builder();
+            var built = builder.add(\"a\").add(\"b\").add(\"c\").build();
+            builder.add(\"d\");
+            var parts = builder.parts;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "built"), Some(string("abc")));
+        assert_eq!(global(&mut interpreter, "parts"), Some(string("abcd")));
+    }
+
+    #[test]
+    fn classes_are_first_class_values() {
+        let mut interpreter = interpret(
+            "class Point {
+                init(x) {
+                    this.x = x;
+                }
+            }
+            fun make(class_value, x) {
+                return class_value(x);
+            }
+            var alias = Point;
+            var direct = alias(1).x;
+            var passed = make(Point, 2).x;
+            var kind = type(make(alias, 3));",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "direct"), Some(number(1.0)));
+        assert_eq!(global(&mut interpreter, "passed"), Some(number(2.0)));
+        assert_eq!(global(&mut interpreter, "kind"), Some(string("instance")));
+    }
+
+    #[test]
+    fn super_resolves_through_two_levels() {
+        let mut interpreter = interpret(
+            "class A {
+                name() { return \"A\"; }
+            }
+            class B < A {
+                name() { return \"B>\" + super.name(); }
+            }
+            class C < B {
+                name() { return \"C>\" + super.name(); }
+                skip() { return super.name(); }
+            }
+            var full = C().name();
+            var skipped = C().skip();
+            var middle = B().name();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "full"), Some(string("C>B>A")));
+        assert_eq!(global(&mut interpreter, "skipped"), Some(string("B>A")));
+        assert_eq!(global(&mut interpreter, "middle"), Some(string("B>A")));
+    }
+
+    #[test]
+    fn optional_get_on_nil_is_nil() {
+        assert_eq!(evaluate("nil?.x == nil"), boolean(true));
+        assert_eq!(evaluate("nil?.x"), Expr::Literal(Literal::Nil));
+        let error = interpret("var n = nil; n.x;").unwrap_err();
+        assert_eq!(error.msg, "Only instances have properties.");
+    }
+
+    #[test]
+    fn optional_get_on_instance_reads_the_field() {
+        let mut interpreter = interpret(
+            "class Point {}
+            var p = Point();
+            p.x = 3;
+            var x = p?.x;
+            var missing = nil;
+            var none = missing?.x;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "x"), Some(number(3.0)));
+        assert_eq!(
+            global(&mut interpreter, "none"),
+            Some(Expr::Literal(Literal::Nil))
+        );
+        let error = interpret("var p = 1; p?.x;").unwrap_err();
+        assert_eq!(error.msg, "Only instances have properties.");
+    }
+
+    #[test]
+    fn numbers_and_strings_have_builtin_methods() {
+        assert_eq!(evaluate("\"abc\".upper() == \"ABC\""), boolean(true));
+        assert_eq!(evaluate("(3.7).floor() == 3"), boolean(true));
+        assert_eq!(evaluate("(-2.5).abs()"), number(2.5));
+        assert_eq!(evaluate("\"MiXed\".lower()"), string("mixed"));
+        assert_eq!(evaluate("\"abc\".length()"), number(3.0));
+
+        let mut interpreter = interpret(
+            "var s = \"hey\";
+            var shout = s.upper;
+            var loud = shout();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "loud"), Some(string("HEY")));
+
+        let error = interpret("\"abc\".floor();").unwrap_err();
+        assert_eq!(error.msg, "Only instances have properties.");
+        let error = interpret("(1).abs(2);").unwrap_err();
+        assert_eq!(error.msg, "'abs' expected 0 arguments but got 1.");
+    }
+
+    #[test]
+    fn modulo_takes_the_float_remainder() {
+        assert_eq!(evaluate("7 % 3"), number(1.0));
+        assert_eq!(evaluate("5.5 % 2"), number(1.5));
+        assert_eq!(evaluate("1 + 7 % 3 * 2"), number(3.0));
+        match evaluate("1 % 0") {
+            Expr::Literal(Literal::Number(n)) => assert!(n.is_nan()),
+            other => panic!("expected a number, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn try_catches_runtime_errors_and_continues() {
+        let mut interpreter = interpret(
+            "var caught = nil;
+            var after = false;
+            try {
+                var inner = 1;
+                print missing;
+                after = true;
+            } catch (e) {
+                caught = e;
+            }
+            var next = 2;",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "caught"),
+            Some(string("Undefined variable 'missing'."))
+        );
+        assert_eq!(global(&mut interpreter, "after"), Some(boolean(false)));
+        assert_eq!(global(&mut interpreter, "next"), Some(number(2.0)));
+    }
+
+    #[test]
+    fn errors_in_call_arguments_can_be_caught() {
+        let mut interpreter = interpret(
+            "fun f(x) { return x; }
+            var missing_message;
+            var panic_message;
+            try { f(missing); } catch (e) { missing_message = e; }
+            try { f(panic(\"x\")); } catch (e) { panic_message = e; }",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "missing_message"),
+            Some(string("Undefined variable 'missing'."))
+        );
+        assert_eq!(global(&mut interpreter, "panic_message"), Some(string("x")));
+    }
+
+    #[test]
+    fn try_catches_errors_raised_inside_calls() {
+        let mut interpreter = interpret(
+            "fun fail(x) { var y = x; return y - \"one\"; }
+            var message = nil;
+            var outer = 1;
+            {
+                var local = 3;
+                try {
+                    fail(1);
+                } catch (e) {
+                    message = e;
+                }
+                outer = local;
+            }",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "message"),
+            Some(string("Operands must be numbers."))
+        );
+        assert_eq!(global(&mut interpreter, "outer"), Some(number(3.0)));
+
+        let error = interpret("try { print missing; } catch (e) { 1 + nil; }").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+    }
+
+    #[test]
+    fn panic_raises_a_catchable_runtime_error() {
+        let error = interpret("panic(\"boom\"); var after = 1;").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime);
+        assert_eq!(error.msg, "boom");
+
+        let mut interpreter = interpret(
+            "fun check(n) { if (n < 0) panic(n); return n; }
+            var caught = nil;
+            try { check(-1); } catch (e) { caught = e; }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "caught"), Some(string("-1")));
+        assert_eq!(interpret("panic();").unwrap_err().kind, ErrorKind::Arity);
+    }
+
+    #[test]
+    fn strings_compare_lexicographically() {
+        assert_eq!(evaluate("\"apple\" < \"banana\""), boolean(true));
+        assert_eq!(evaluate("\"b\" > \"a\""), boolean(true));
+        assert_eq!(evaluate("\"ab\" <= \"ab\""), boolean(true));
+        assert_eq!(evaluate("\"a\" >= \"ab\""), boolean(false));
+        for source in ["\"1\" < 1;", "1 > \"1\";", "\"1\" <= 1;", "1 >= \"1\";"] {
+            assert_eq!(
+                interpret(source).unwrap_err().msg,
+                "Operands must be two numbers or two strings.",
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn lists_compare_element_by_element() {
+        assert_eq!(evaluate("[1] < [2]"), boolean(true));
+        assert_eq!(evaluate("[1, 2] < [1, 3]"), boolean(true));
+        assert_eq!(evaluate("[1, 2] < [1]"), boolean(false));
+        assert_eq!(evaluate("[1] < [1, 0]"), boolean(true));
+        assert_eq!(evaluate("[] <= []"), boolean(true));
+        assert_eq!(evaluate("[\"b\"] > [\"a\", \"z\"]"), boolean(true));
+        assert_eq!(evaluate("[[1, 2], 3] >= [[1, 1], 4]"), boolean(true));
+
+        let error = interpret("[1] < [\"1\"];").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+        assert_eq!(error.msg, "Can only compare lists of numbers, strings or lists.");
+        assert_eq!(
+            interpret("[1] < 1;").unwrap_err().msg,
+            "Operands must be two numbers or two strings."
+        );
+    }
+
+    #[test]
+    fn return_with_several_values_returns_a_list() {
+        let mut interpreter = interpret(
+            "fun pair() { var a = \"x\"; return a, a + \"y\"; }
+            fun single() { return \"only\"; }
+            var both = pair();
+            var joined = join(pair(), \"-\");
+            var one = single();
+            var kind = type(pair());",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "both"), Some(strings(&["x", "xy"])));
+        assert_eq!(global(&mut interpreter, "joined"), Some(string("x-xy")));
+        assert_eq!(global(&mut interpreter, "one"), Some(string("only")));
+        assert_eq!(global(&mut interpreter, "kind"), Some(string("list")));
+    }
+
+    #[test]
+    fn several_returned_values_destructure_at_the_call_site() {
+        let mut interpreter = interpret(
+            "fun divide(a, b) { return (a - a % b) / b, a % b; }
+            var quotient, remainder = divide(7, 2);
+            var sum;
+            {
+                var low, high = divide(9, 4);
+                fun total() { return low + high; }
+                sum = total();
+            }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "quotient"), Some(number(3.0)));
+        assert_eq!(global(&mut interpreter, "remainder"), Some(number(1.0)));
+        assert_eq!(global(&mut interpreter, "sum"), Some(number(3.0)));
+
+        let error = interpret("fun one() { return 1; } var a, b = one();").unwrap_err();
+        assert_eq!(error.msg, "Can only destructure a list.");
+        let error = interpret("var a, b = [1, 2, 3];").unwrap_err();
+        assert_eq!(error.msg, "Expected 2 values to destructure but got 3.");
+        let error = interpret("{ var a, a = [1, 2]; }").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Resolve);
+    }
+
+    #[test]
+    fn break_leaves_the_innermost_loop() {
+        let mut interpreter = interpret(
+            "var i = 0;
+            while (true) {
+                if (i == 3) break;
+                i = i + 1;
+            }
+            var pairs = 0;
+            var last = nil;
+            for (var a = 0; a < 3; a = a + 1) {
+                for (var b = 0; b < 3; b = b + 1) {
+                    if (b > a) { break; }
+                    pairs = pairs + 1;
+                    last = b;
+                }
+            }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "i"), Some(number(3.0)));
+        assert_eq!(global(&mut interpreter, "pairs"), Some(number(6.0)));
+        assert_eq!(global(&mut interpreter, "last"), Some(number(2.0)));
+    }
+
+    #[test]
+    fn properties_on_strings_and_numbers_are_errors() {
+        for source in ["\"x\".y;", "var n = 1; n.y;"] {
+            let error = interpret(source).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+            assert_eq!(error.msg, "Only instances have properties.");
+        }
+        for source in ["\"x\".y = 1;", "var n = 1; n.y = 1;", "var s = \"x\"; s.y = s.z;"] {
+            let error = interpret(source).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+            assert_eq!(error.msg, "Only instances have fields.");
+        }
+    }
+
+    #[test]
+    fn fields_can_be_set_through_any_object_expression() {
+        let mut interpreter = interpret(
+            "class Box {}
+            var outer = Box();
+            outer.inner = Box();
+            outer.inner.value = 1;
+            fun get() { return outer; }
+            get().inner.value = get().inner.value + 1;
+            var value = outer.inner.value;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "value"), Some(number(2.0)));
+    }
+
+    #[test]
+    fn list_literals_can_be_indexed_and_mutated() {
+        let mut interpreter = interpret(
+            "var list = [1, \"two\", 1 + 2];
+            var alias = list;
+            var first = list[0];
+            var last = list[1 + 1];
+            list[1] = \"2\";
+            var nested = [[1, 2], [3, 4]];
+            nested[1][0] = nested[0][1] * 10;",
+        )
+        .unwrap();
+        assert_eq!(evaluate("type([])"), string("list"));
+        assert_eq!(global(&mut interpreter, "first"), Some(number(1.0)));
+        assert_eq!(global(&mut interpreter, "last"), Some(number(3.0)));
+        assert_eq!(evaluate("[1, 2][1] = 5"), number(5.0));
+
+        let alias = global(&mut interpreter, "alias");
+        let Some(Expr::List(alias)) = alias else {
+            panic!("expected a list, got {:?}", alias)
+        };
+        assert_eq!(alias.elements.borrow()[1], string("2"));
+
+        let nested = global(&mut interpreter, "nested");
+        let Some(Expr::List(nested)) = nested else {
+            panic!("expected a list, got {:?}", nested)
+        };
+        let Expr::List(inner) = nested.elements.borrow()[1].clone() else {
+            panic!("expected a nested list")
+        };
+        assert_eq!(inner.elements.borrow()[0], number(20.0));
+    }
+
+    #[test]
+    fn list_literal_and_index_resolve_captured_locals() {
+        let mut interpreter = interpret(
+            "var i = \"global\";
+            var items = \"global\";
+            fun make() {
+              var i = 1;
+              var items = [10, 20, 30];
+              fun get() { return [i, items[i + 1]]; }
+              return get;
+            }
+            var got = make()();
+            var first = got[0];
+            var second = got[1];",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "first"), Some(number(1.0)));
+        assert_eq!(global(&mut interpreter, "second"), Some(number(30.0)));
+    }
+
+    #[test]
+    fn bad_list_indices_are_errors() {
+        let error = interpret("var l = [1, 2]; l[2];").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime);
+        assert_eq!(error.msg, "List index 2 out of bounds for length 2.");
+        let error = interpret("var l = [1, 2]; l[5] = 0;").unwrap_err();
+        assert_eq!(error.msg, "List index 5 out of bounds for length 2.");
+
+        for source in ["[1][-1];", "[1][0.5];", "[1][\"0\"];", "[1][nil] = 2;"] {
+            let error = interpret(source).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+            assert_eq!(error.msg, "List index must be a non-negative integer.");
+        }
+        let error = interpret("\"abc\"[0];").unwrap_err();
+        assert_eq!(error.msg, "Only lists can be indexed.");
+    }
+
+    #[test]
+    fn continue_skips_to_the_next_iteration() {
+        let mut interpreter = interpret(
+            "var evens = 0;
+            for (var i = 0; i <= 10; i = i + 1) {
+                if (i % 2 == 1) continue;
+                evens = evens + i;
+            }
+            var j = 0;
+            var odds = 0;
+            while (j < 10) {
+                j = j + 1;
+                if (j % 2 == 0) { continue; }
+                odds = odds + j;
+            }
+            var skipped = 0;
+            for (var a = 0; a < 3; a = a + 1) {
+                for (var b = 0; b < 3; b = b + 1) {
+                    if (a == b) continue;
+                    skipped = skipped + 1;
+                }
+            }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "evens"), Some(number(30.0)));
+        assert_eq!(global(&mut interpreter, "odds"), Some(number(25.0)));
+        assert_eq!(global(&mut interpreter, "skipped"), Some(number(6.0)));
+    }
+
+    #[test]
+    fn for_clauses_take_comma_separated_lists() {
+        let mut interpreter = interpret(
+            "var steps = 0;
+            var met = nil;
+            for (var i = 0, j = 10; i < j; i = i + 1, j = j - 1) {
+                steps = steps + 1;
+                met = i;
+            }
+            var a;
+            var b;
+            var sum = 0;
+            for (a = 1, b = 1; a < 50; a = a + b, b = a - b) {
+                if (a == 2) continue;
+                sum = sum + a;
+            }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "steps"), Some(number(5.0)));
+        assert_eq!(global(&mut interpreter, "met"), Some(number(4.0)));
+        // 1, 2, 3, 5, 8, 13, 21, 34 with 2 skipped by `continue`.
+        assert_eq!(global(&mut interpreter, "sum"), Some(number(85.0)));
+        assert_eq!(global(&mut interpreter, "a"), Some(number(55.0)));
+    }
+
+    #[test]
+    fn slot_lookup_matches_name_lookup_across_nested_scopes() {
+        let source = "var log = \"\";
+            fun counter(start) {
+                var count = start;
+                fun step(by) {
+                    var before = count;
+                    count = count + by;
+                    return before;
+                }
+                return step;
+            }
+            var next = counter(10);
+            next(1);
+            next(2);
+            var seen = next(0);
+            {
+                var a = \"outer\";
+                fun early() { return later(); }
+                fun later() { return a; }
+                {
+                    var a = \"inner\";
+                    var b = a;
+                    log = log + b + early();
+                }
+                a = \"changed\";
+                log = log + a;
+            }
+            class Box {
+                init(value) { this.value = value; }
+                get() {
+                    var value = \"local\";
+                    return this.value + value;
+                }
+            }
+            var boxed = Box(\"box\").get();
+            var caught;
+            try {
+                var x = 1;
+                panic(\"boom\");
+            } catch (e) {
+                var y = e;
+                caught = y;
+            }";
+
+        for slot_lookup in [true, false] {
+            let mut interpreter = interpret_with_slots(source, slot_lookup).unwrap();
+            assert_eq!(global(&mut interpreter, "seen"), Some(number(13.0)));
+            assert_eq!(global(&mut interpreter, "log"), Some(string("innerouterchanged")));
+            assert_eq!(global(&mut interpreter, "boxed"), Some(string("boxlocal")));
+            assert_eq!(global(&mut interpreter, "caught"), Some(string("boom")));
+        }
+    }
+
+    #[test]
+    fn hoisted_function_reading_an_undefined_local_is_an_error() {
+        let source = "{ print f(); var x = 1; fun f() { return x; } }";
+        for slot_lookup in [true, false] {
+            let error = interpret_with_slots(source, slot_lookup).unwrap_err();
+            assert_eq!(error.msg, "Undefined variable 'x'.");
+        }
+        let error = interpret_with_slots("{ f(); var x = 1; fun f() { x = 2; } }", true).unwrap_err();
+        assert_eq!(error.msg, "Undefined variable 'x'.");
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with --ignored"]
+    fn slot_lookup_benchmark() {
+        let source = "fun work() {
+                var a = 1; var b = 2; var c = 3; var d = 4; var e = 5;
+                var total = 0;
+                for (var i = 0; i < 20000; i = i + 1) {
+                    total = total + a + b + c + d + e;
+                }
+                return total;
+            }
+            var total = work();";
+
+        let mut timings = vec![];
+        for slot_lookup in [true, false] {
+            let start = std::time::Instant::now();
+            let mut interpreter = interpret_with_slots(source, slot_lookup).unwrap();
+            timings.push(start.elapsed());
+            assert_eq!(global(&mut interpreter, "total"), Some(number(300000.0)));
+            // Only the call to the global `work` is looked up by name; every
+            // reference inside it has a slot, so the timings compare the two
+            // strategies rather than a mix of both.
+            assert_eq!(interpreter.locals.len() - interpreter.slots.len(), 1);
+        }
+        println!("slots: {:?}, names: {:?}", timings[0], timings[1]);
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with --ignored"]
+    fn variable_heavy_benchmark() {
+        let source = "fun work() {
+                var total = 0;
+                for (var i = 0; i < 20000; i = i + 1) {
+                    var a = i; var b = a + 1; var c = b + a;
+                    total = total + c - b - a + 1;
+                }
+                return total;
+            }
+            var total = work();";
+
+        let start = std::time::Instant::now();
+        let mut interpreter = interpret(source).unwrap();
+        println!("variable-heavy program: {:?}", start.elapsed());
+        assert_eq!(global(&mut interpreter, "total"), Some(number(20000.0)));
+        // One entry per variable reference, however often each one runs.
+        assert!(interpreter.locals.len() < 20);
+    }
+
+    #[test]
+    fn nil_coalescing_short_circuits() {
+        assert_eq!(evaluate("(nil ?? 5) == 5"), boolean(true));
+        assert_eq!(evaluate("false ?? 5"), boolean(false));
+
+        let mut interpreter = interpret(
+            "var calls = 0;
+            fun side_effect() { calls = calls + 1; return 4; }
+            var kept = 3 ?? side_effect();
+            var a = nil;
+            var b = nil;
+            var chained = a ?? b ?? \"c\";
+            var first = a ?? \"b\" ?? side_effect();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "kept"), Some(number(3.0)));
+        assert_eq!(global(&mut interpreter, "calls"), Some(number(0.0)));
+        assert_eq!(global(&mut interpreter, "chained"), Some(string("c")));
+        assert_eq!(global(&mut interpreter, "first"), Some(string("b")));
+    }
+
+    #[test]
+    fn ternary_only_evaluates_the_taken_branch() {
+        assert_eq!(evaluate("true ? 1 : 2"), number(1.0));
+        assert_eq!(evaluate("nil ? 1 : 2"), number(2.0));
+        assert_eq!(evaluate("0 ? \"zero\" : false ? 1 : 2"), string("zero"));
+
+        let mut interpreter = interpret(
+            "var calls = 0;
+            fun bump() { calls = calls + 1; return calls; }
+            var a = true ? \"then\" : bump();
+            var b = false ? bump() : \"else\";
+            var c = bump() > 0 ? bump() : 0;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "a"), Some(string("then")));
+        assert_eq!(global(&mut interpreter, "b"), Some(string("else")));
+        assert_eq!(global(&mut interpreter, "c"), Some(number(2.0)));
+        assert_eq!(global(&mut interpreter, "calls"), Some(number(2.0)));
+
+        let error = interpret("var x = true ? 1;").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[line 1] Error at ';': Expect ':' after then branch of conditional."
+        );
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        let mut interpreter = interpret(
+            "var calls = 0;
+            fun bad() { calls = calls + 1; return \"right\"; }
+            var or_truthy = true or bad();
+            var or_value = \"left\" or bad();
+            var and_falsey = false and bad();
+            var and_nil = nil and bad();
+            var skipped = calls;
+            var or_falsey = nil or bad();
+            var and_truthy = 1 and bad();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "or_truthy"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "or_value"), Some(string("left")));
+        assert_eq!(global(&mut interpreter, "and_falsey"), Some(boolean(false)));
+        assert_eq!(
+            global(&mut interpreter, "and_nil"),
+            Some(Expr::Literal(Literal::Nil))
+        );
+        assert_eq!(global(&mut interpreter, "skipped"), Some(number(0.0)));
+        assert_eq!(global(&mut interpreter, "or_falsey"), Some(string("right")));
+        assert_eq!(global(&mut interpreter, "and_truthy"), Some(string("right")));
+        assert_eq!(global(&mut interpreter, "calls"), Some(number(2.0)));
+    }
+
+    #[test]
+    fn constants_can_be_read() {
+        let mut interpreter = interpret(
+            "const PI = 3;
+            fun area(r) { return PI * r * r; }
+            var a = area(2);
+            { var PI = 1; PI = 2; var inner = PI; }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "a"), Some(number(12.0)));
+        assert_eq!(global(&mut interpreter, "PI"), Some(number(3.0)));
+    }
+
+    #[test]
+    fn constants_cannot_be_reassigned() {
+        for source in [
+            "const PI = 3.14; PI = 3;",
+            "const PI = 3.14; fun f() { PI = 3; }",
+            "{ const PI = 3.14; { PI = 3; } }",
+            "const PI = 3; fun id(x) { return x; } id(PI = 4);",
+        ] {
+            let error = interpret(source).unwrap_err();
+            assert_eq!(error.msg, "Cannot assign to constant 'PI'.", "{}", source);
+        }
+        assert!(interpret("const PI = 3.14; var PI = 3; PI = 4;").is_ok());
+    }
+
+    #[test]
+    fn enum_members_compare_by_identity() {
+        let mut interpreter = interpret(
+            "enum Color { Red, Green, Blue }
+            var same = Color.Red == Color.Red;
+            var different = Color.Red != Color.Blue;
+            var favourite = Color.Green;
+            var kept = favourite == Color.Green;
+            enum Other { Red }
+            var across = Other.Red == Color.Red;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "same"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "different"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "kept"), Some(boolean(true)));
+        assert_eq!(global(&mut interpreter, "across"), Some(boolean(false)));
+
+        let error = interpret("enum Color { Red, } Color.Purple;").unwrap_err();
+        assert_eq!(error.msg, "Undefined property 'Purple'.");
+    }
+
+    /// A sink tests can read back after handing it to the interpreter.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn print_err_writes_to_the_stderr_sink() {
+        let mut lexer = Lexer::new("print \"out\"; print_err(\"oops\");");
+        lexer.scan_tokens().unwrap();
+        let ast = Parser::new(lexer.tokens)
+            .parse()
+            .into_iter()
+            .collect::<Result<Vec<Stmt>, Error>>()
+            .unwrap();
+
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_stdout(Box::new(stdout.clone()));
+        interpreter.set_stderr(Box::new(stderr.clone()));
+        for stmt in &ast {
+            stmt.accept(&mut interpreter).unwrap();
+        }
+
+        assert_eq!(stdout.contents(), "out\n");
+        assert_eq!(stderr.contents(), "oops\n");
+    }
+
+    #[test]
+    fn print_shows_values_as_lox_writes_them() {
+        let mut lexer = Lexer::new(
+            "print 5; print 5.5; print -0.25; print \"text\"; print true; print false; print nil;
+            print [1, \"a\", [nil]]; fun f() {} print f; class C {} print C; print C(); print len;",
+        );
+        lexer.scan_tokens().unwrap();
+        let ast = Parser::new(lexer.tokens)
+            .parse()
+            .into_iter()
+            .collect::<Result<Vec<Stmt>, Error>>()
+            .unwrap();
+
+        let stdout = SharedBuffer::default();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_stdout(Box::new(stdout.clone()));
+        for stmt in &ast {
+            stmt.accept(&mut interpreter).unwrap();
+        }
+
+        let lines = [
+            "5", "5.5", "-0.25", "text", "true", "false", "nil", "[1, a, [nil]]", "<fn f>", "C",
+            "C instance", "<native fn>",
+        ];
+        assert_eq!(stdout.contents(), lines.join("\n") + "\n");
+    }
+
+    #[test]
+    fn arity_errors_name_the_callee_and_line() {
+        let error = interpret("fun add(a, b) {}\n\nadd(1);").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Arity);
+        assert_eq!(error.msg, "'add' expected 2 arguments but got 1.");
+        assert_eq!(error.location.as_deref(), Some(&Location { line: 3, col: 6 }));
+        let error = interpret("class P { init(x) {} }\nP();").unwrap_err();
+        assert_eq!(error.to_string(), "[line 2] 'P' expected 1 arguments but got 0.");
+        let error = interpret("class A { m() {} }\nA().m(1);").unwrap_err();
+        assert_eq!(error.to_string(), "[line 2] 'm' expected 0 arguments but got 1.");
+        let error = interpret("len();").unwrap_err();
+        assert_eq!(error.to_string(), "[line 1] 'len' expected 1 arguments but got 0.");
+
+        let mut interpreter = interpret(
+            "fun f(a) {}
+            var caught;
+            try { f(); } catch (e) { caught = e; }",
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut interpreter, "caught"),
+            Some(string("'f' expected 1 arguments but got 0."))
+        );
+    }
+
+    #[test]
+    fn to_hex_and_to_bin_write_integers() {
+        assert_eq!(evaluate("to_hex(255)"), string("ff"));
+        assert_eq!(evaluate("to_hex(0)"), string("0"));
+        assert_eq!(evaluate("to_hex(-255)"), string("-ff"));
+        assert_eq!(evaluate("to_hex(9007199254740992)"), string("20000000000000"));
+        assert_eq!(evaluate("to_bin(10)"), string("1010"));
+        assert_eq!(evaluate("to_bin(0)"), string("0"));
+        assert_eq!(evaluate("to_bin(4294967296)"), string(&format!("1{}", "0".repeat(32))));
+
+        for source in ["to_hex(1.5);", "to_bin(\"10\");", "to_hex(9007199254740994);"] {
+            let error = interpret(source).unwrap_err();
+            assert_eq!(error.msg, "Argument must be an integer.", "{}", source);
+        }
+    }
+
+    #[test]
+    fn plus_concatenates_strings_with_other_values_when_enabled() {
+        let source = "var a = \"x\" + 1; var b = 1.5 + \"x\"; var c = 1 + 2; var d = \"n=\" + nil;";
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let ast = Parser::new(lexer.tokens)
+            .parse()
+            .into_iter()
+            .collect::<Result<Vec<Stmt>, Error>>()
+            .unwrap();
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_concat_strings(true);
+        for stmt in &ast {
+            stmt.accept(&mut interpreter).unwrap();
+        }
+        assert_eq!(global(&mut interpreter, "a"), Some(string("x1")));
+        assert_eq!(global(&mut interpreter, "b"), Some(string("1.5x")));
+        assert_eq!(global(&mut interpreter, "c"), Some(number(3.0)));
+        assert_eq!(global(&mut interpreter, "d"), Some(string("n=nil")));
+
+        let error = interpret("\"x\" + 1;").unwrap_err();
+        assert_eq!(error.msg, "Operands must be two numbers or two strings.");
+    }
+
+    #[test]
+    fn format_fills_placeholders_in_order() {
+        assert_eq!(
+            evaluate("format(\"{} + {} = {}\", 2, 3.5, 2 + 3.5)"),
+            string("2 + 3.5 = 5.5")
+        );
+        assert_eq!(
+            evaluate("format(\"{}, {}, {}, {}\", \"s\", true, nil, [1, \"a\"])"),
+            string("s, true, nil, [1, a]")
+        );
+        assert_eq!(evaluate("format(\"plain\")"), string("plain"));
+        assert_eq!(evaluate("format(\"{{}} {{{}}}\", 1)"), string("{} {1}"));
+
+        let error = interpret("format(\"{} {}\", 1);").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Arity);
+        assert_eq!(error.msg, "Format string has 2 placeholders but got 1 arguments.");
+        let error = interpret("format(\"{}\", 1, 2);").unwrap_err();
+        assert_eq!(error.msg, "Format string has 1 placeholders but got 2 arguments.");
+        let error = interpret("format(\"{ }\");").unwrap_err();
+        assert_eq!(error.msg, "Unmatched '{' in format string.");
+        let error = interpret("format();").unwrap_err();
+        assert_eq!(error.msg, "'format' expected 1 arguments but got 0.");
+        let error = interpret("format(1);").unwrap_err();
+        assert_eq!(error.msg, "Arguments must be strings.");
+    }
+
+    #[test]
+    fn recursive_lambdas_see_their_own_variable() {
+        let mut interpreter = interpret(
+            "var f = fun(n) { if (n <= 0) return 0; return n + f(n - 1); };
+            var global = f(4);
+            var square = fun(n) { return n * n; }(3);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut interpreter, "global"), Some(number(10.0)));
+        assert_eq!(global(&mut interpreter, "square"), Some(number(9.0)));
+
+        let error = interpret("{ var a = fun() { return 1; }(); var b = b; }").unwrap_err();
+        assert_eq!(
+            error.msg,
+            "Error at 'b': Can't read local variable in its own initializer."
+        );
+    }
+
+    #[test]
+    fn errors_report_their_kind() {
+        for (source, kind) in [
+            ("var a = @;", ErrorKind::Lex),
+            ("var a = ;", ErrorKind::Parse),
+            ("const PI = 3.14; PI = 3;", ErrorKind::Resolve),
+            ("var a = 1 + \"one\";", ErrorKind::Type),
+            ("fun f(a) {} f(1, 2);", ErrorKind::Arity),
+            ("print missing;", ErrorKind::UndefinedVariable),
+            ("class A {} A().missing;", ErrorKind::UndefinedProperty),
+            ("assert false;", ErrorKind::Runtime),
+        ] {
+            assert_eq!(interpret(source).unwrap_err().kind, kind, "{}", source);
+        }
+    }
 }
@@ -1,2 +1,3 @@
 pub mod interpreter;
+pub mod printer;
 pub mod resolver;
@@ -0,0 +1,531 @@
+use crate::interpreter::ast::{
+    Assert, Assign, Binary, Block, Call, ClassDecl, Destructure, EnumDecl, Expr, Expression,
+    FunDecl, Get, Grouping, IVisitorExpr, IVisitorStmt, If, Index, IndexSet, Invalid, InvalidExpr,
+    Lambda, ListLiteral, Literal, Logical, Print, Return, Set, Stmt, Super, Ternary, This, Try,
+    Unary, Var, VarDecl, While,
+};
+use crate::interpreter::operators::Operator;
+
+/// Renders the tree as S-expressions, e.g. `(+ 1 (* 2 3))` for `1 + 2 * 3`,
+/// which makes the parser's grouping and precedence visible.
+pub struct AstPrinter;
+
+impl AstPrinter {
+    pub fn print_expr(&mut self, expr: &Expr) -> String {
+        expr.accept(self)
+    }
+
+    pub fn print_stmt(&mut self, stmt: &Stmt) -> String {
+        stmt.accept(self)
+    }
+
+    fn parenthesize(&mut self, name: &str, exprs: &[&Expr]) -> String {
+        let mut out = format!("({}", name);
+        for expr in exprs {
+            out.push(' ');
+            out.push_str(&expr.accept(self));
+        }
+        out.push(')');
+        out
+    }
+
+    fn parenthesize_stmts(&mut self, name: &str, stmts: &[Stmt]) -> String {
+        let mut out = format!("({}", name);
+        for stmt in stmts {
+            out.push(' ');
+            out.push_str(&stmt.accept(self));
+        }
+        out.push(')');
+        out
+    }
+}
+
+fn symbol(operator: &Operator) -> &'static str {
+    match operator {
+        Operator::Bang => "!",
+        Operator::BangEqual => "!=",
+        Operator::Equal => "=",
+        Operator::EqualEqual => "==",
+        Operator::Greater => ">",
+        Operator::GreaterEqual => ">=",
+        Operator::Less => "<",
+        Operator::LessEqual => "<=",
+        Operator::Minus => "-",
+        Operator::Percent => "%",
+        Operator::Plus => "+",
+        Operator::Slash => "/",
+        Operator::Star => "*",
+        Operator::Range => "..",
+        Operator::Or => "or",
+        Operator::And => "and",
+        Operator::Coalesce => "??",
+    }
+}
+
+/// The parser fills in a missing initializer, `else` branch or return value
+/// with a bare `nil`, which is left out of the printed form.
+fn is_nil(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(Literal::Nil))
+}
+
+impl IVisitorExpr<String> for AstPrinter {
+    fn visit_var(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Var(Var::Token(token)) => token.lexeme.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_literal(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Literal(Literal::Bool(b)) => b.to_string(),
+            Expr::Literal(Literal::Number(n)) => n.to_string(),
+            Expr::Literal(Literal::Str(s)) => format!("{:?}", s),
+            Expr::Literal(Literal::Nil) => "nil".to_string(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_unary(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Unary(Unary { operator, right }) => self.parenthesize(symbol(operator), &[right]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_binary(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Binary(Binary {
+                left,
+                operator,
+                right,
+            }) => self.parenthesize(symbol(operator), &[left, right]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_grouping(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Grouping(Grouping { group }) => self.parenthesize("group", &[group]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_assign(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Assign(Assign {
+                var: Var::Token(token),
+                expr,
+            }) => format!("(= {} {})", token.lexeme, expr.accept(self)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_logical(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Logical(Logical {
+                left,
+                operator,
+                right,
+            }) => self.parenthesize(symbol(operator), &[left, right]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_ternary(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Ternary(Ternary {
+                condition,
+                then_branch,
+                else_branch,
+            }) => self.parenthesize("?:", &[condition, then_branch, else_branch]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_call(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Call(Call {
+                callee, arguments, ..
+            }) => {
+                let mut exprs: Vec<&Expr> = vec![callee];
+                exprs.extend(arguments);
+                self.parenthesize("call", &exprs)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_get(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Get(Get {
+                object,
+                name,
+                optional,
+            }) => {
+                let access = if *optional { "?." } else { "." };
+                format!("({} {} {})", access, object.accept(self), name.lexeme)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_set(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Set(Set {
+                object,
+                name,
+                value,
+            }) => format!(
+                "(= (. {} {}) {})",
+                object.accept(self),
+                name.lexeme,
+                value.accept(self)
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_index(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Index(Index { object, index, .. }) => {
+                self.parenthesize("index", &[object, index])
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_index_set(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::IndexSet(IndexSet {
+                object,
+                index,
+                value,
+                ..
+            }) => format!(
+                "(= (index {} {}) {})",
+                object.accept(self),
+                index.accept(self),
+                value.accept(self)
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_this(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::This(This { .. }) => "this".to_string(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_super(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Super(Super { method, .. }) => format!("(super {})", method.lexeme),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_list_literal(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::ListLiteral(ListLiteral { elements }) => {
+                let elements: Vec<&Expr> = elements.iter().collect();
+                self.parenthesize("list", &elements)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_lambda(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Lambda(Lambda { declaration, .. }) => declaration.accept(self),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_invalid_expr(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::InvalidExpr(InvalidExpr { msg }) => format!("(invalid {:?})", msg),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl IVisitorStmt<String> for AstPrinter {
+    fn visit_expr(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Expression(Expression { expr }) => expr.accept(self),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_print(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Print(Print { expr }) => self.parenthesize("print", &[expr]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_var_decl(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::VarDecl(VarDecl {
+                name,
+                expr,
+                constant,
+            }) => {
+                let keyword = if *constant { "const" } else { "var" };
+                if is_nil(expr) {
+                    format!("({} {})", keyword, name)
+                } else {
+                    format!("({} {} {})", keyword, name, expr.accept(self))
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_destructure(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Destructure(Destructure { names, expr }) => {
+                format!("(var ({}) {})", names.join(" "), expr.accept(self))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_block(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Block(Block { stmts }) => self.parenthesize_stmts("block", stmts),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_if(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::If(If {
+                condition,
+                branch_true,
+                branch_false,
+            }) => {
+                let condition = condition.accept(self);
+                let branch_true = branch_true.accept(self);
+                match branch_false.as_ref() {
+                    Stmt::Expression(Expression { expr }) if is_nil(expr) => {
+                        format!("(if {} {})", condition, branch_true)
+                    }
+                    branch_false => format!(
+                        "(if {} {} {})",
+                        condition,
+                        branch_true,
+                        branch_false.accept(self)
+                    ),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_while(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::While(While {
+                condition,
+                body,
+                increment,
+                else_branch,
+            }) => {
+                let mut parts = vec![condition.accept(self), body.accept(self)];
+                for increment in increment {
+                    parts.push(increment.accept(self));
+                }
+                if let Some(else_branch) = else_branch {
+                    parts.push(format!("(else {})", else_branch.accept(self)));
+                }
+                format!("(while {})", parts.join(" "))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_fun_decl(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::FunDecl(FunDecl {
+                name,
+                parameters,
+                body,
+            }) => {
+                let parameters = parameters
+                    .iter()
+                    .map(|p| p.lexeme.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+                let name = format!("fun {} ({})", name, parameters);
+                self.parenthesize_stmts(&name, body)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_return(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Return(Return { value, .. }) if is_nil(value) => "(return)".to_string(),
+            Stmt::Return(Return { value, .. }) => self.parenthesize("return", &[value]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_break(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Break(_) => "(break)".to_string(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_continue(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Continue(_) => "(continue)".to_string(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_class(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::ClassDecl(ClassDecl {
+                name,
+                methods,
+                superclass,
+            }) => {
+                let name = match superclass {
+                    Some(superclass) => {
+                        format!("class {} < {}", name.lexeme, superclass.accept(self))
+                    }
+                    None => format!("class {}", name.lexeme),
+                };
+                self.parenthesize_stmts(&name, methods)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_enum(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::EnumDecl(EnumDecl { name, members }) => {
+                let mut out = format!("(enum {}", name.lexeme);
+                for member in members {
+                    out.push(' ');
+                    out.push_str(&member.lexeme);
+                }
+                out.push(')');
+                out
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_try(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Try(Try {
+                body,
+                name,
+                handler,
+            }) => {
+                let body = self.parenthesize_stmts("try", body);
+                let handler = self.parenthesize_stmts(&format!("catch {}", name.lexeme), handler);
+                format!("{} {}", body, handler)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_assert(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Assert(Assert {
+                condition, message, ..
+            }) => match message {
+                Some(message) => self.parenthesize("assert", &[condition, message]),
+                None => self.parenthesize("assert", &[condition]),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn visit_invalid(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Invalid(Invalid { msg }) => format!("(invalid {:?})", msg),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::ast::{Error, Stmt};
+    use crate::interpreter::lexer::Lexer;
+    use crate::interpreter::parser::Parser;
+
+    use super::AstPrinter;
+
+    fn printed(source: &str) -> Vec<String> {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens().unwrap();
+        let stmts = Parser::new(lexer.tokens)
+            .parse()
+            .into_iter()
+            .collect::<Result<Vec<Stmt>, Error>>()
+            .unwrap();
+        stmts
+            .iter()
+            .map(|stmt| AstPrinter.print_stmt(stmt))
+            .collect()
+    }
+
+    #[test]
+    fn prints_expressions_with_their_precedence() {
+        assert_eq!(printed("1 + 2 * 3;"), vec!["(+ 1 (* 2 3))"]);
+        assert_eq!(printed("(1 + 2) * -3;"), vec!["(* (group (+ 1 2)) (- 3))"]);
+        assert_eq!(printed("a or b and !c;"), vec!["(or a (and b (! c)))"]);
+        assert_eq!(
+            printed("f(1, \"two\")(x);"),
+            vec!["(call (call f 1 \"two\") x)"]
+        );
+        assert_eq!(printed("x = 2.5 >= y;"), vec!["(= x (>= 2.5 y))"]);
+        assert_eq!(
+            printed("xs[0] = [1, xs[i + 1]];"),
+            vec!["(= (index xs 0) (list 1 (index xs (+ i 1))))"]
+        );
+        assert_eq!(
+            printed("x = a or b ? 1 : c ? 2 : 3;"),
+            vec!["(= x (?: (or a b) 1 (?: c 2 3)))"]
+        );
+    }
+
+    #[test]
+    fn prints_declarations_and_statements() {
+        assert_eq!(printed("var x;"), vec!["(var x)"]);
+        assert_eq!(printed("const pi = 3.14;"), vec!["(const pi 3.14)"]);
+        assert_eq!(printed("var q, r = f();"), vec!["(var (q r) (call f))"]);
+        assert_eq!(
+            printed("fun add(a, b) { return a + b; }"),
+            vec!["(fun add (a b) (return (+ a b)))"]
+        );
+        assert_eq!(
+            printed("fun pair() { return 1, 2; }"),
+            vec!["(fun pair () (return (list 1 2)))"]
+        );
+        assert_eq!(
+            printed("if (ok) print nil; else { x.y = 1; }"),
+            vec!["(if ok (print nil) (block (= (. x y) 1)))"]
+        );
+        assert_eq!(
+            printed("for (var i = 0; i < 3; i = i + 1) continue;"),
+            vec!["(block (var i 0) (while (< i 3) (continue) (= i (+ i 1))))"]
+        );
+        assert_eq!(
+            printed("while (ok) { break; } else print 1;"),
+            vec!["(while ok (block (break)) (else (print 1)))"]
+        );
+        assert_eq!(
+            printed("if (ok) while (more) print 1; else print 2;"),
+            vec!["(if ok (while more (print 1)) (print 2))"]
+        );
+    }
+}
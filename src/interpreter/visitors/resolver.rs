@@ -1,12 +1,13 @@
 use core::panic;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
     extract_enum_value,
     interpreter::ast::{
-        Assign, Binary, Block, ClassDecl, Error, Expr, Expression, FunDecl, Get, Grouping,
-        IVisitorExpr, IVisitorStmt, If, Literal, Logical, Print, Return, Set, Stmt, Super, This,
-        Unary, Var, VarDecl, While,
+        Assert, Assign, Binary, Block, Break, Call, ClassDecl, Continue, Destructure, EnumDecl,
+        Error, ErrorKind, Expr, Expression, FunDecl, Get, Grouping, IVisitorExpr, IVisitorStmt, If,
+        Index, IndexSet, Lambda, ListLiteral, Literal, Logical, Print, Return, Set, Stmt, Super,
+        Ternary, This, Try, Unary, Var, VarDecl, While,
     },
 };
 
@@ -15,17 +16,26 @@ use super::interpreter::Interpreter;
 #[derive(Debug, PartialEq, Clone)]
 pub struct Scope {
     symbol_table: HashMap<String, bool>,
+    /// Slot of each name, numbered in declaration order like the runtime
+    /// environment numbers its definitions.
+    slots: HashMap<String, usize>,
+    /// Names in `symbol_table` that were declared with `const`.
+    constants: HashSet<String>,
 }
 
 impl Scope {
     pub fn new() -> Self {
         Scope {
             symbol_table: HashMap::new(),
+            slots: HashMap::new(),
+            constants: HashSet::new(),
         }
     }
 
     pub fn define(&mut self, name: &str, value: bool) {
         self.symbol_table.insert(name.to_string(), value);
+        let next = self.slots.len();
+        self.slots.entry(name.to_string()).or_insert(next);
     }
 
     pub fn retrieve(&self, name: &str) -> Option<bool> {
@@ -42,6 +52,13 @@ pub struct Resolver<'a> {
     interpreter: &'a mut Interpreter,
     current_function: FunctionType,
     current_class: ClassType,
+    /// How many loops enclose the code being resolved, within the current
+    /// function.
+    loop_depth: usize,
+    /// How many `try` blocks enclose the code being resolved, within the
+    /// current function. A call there is not in tail position, as the
+    /// handler must still see its errors.
+    try_depth: usize,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -65,6 +82,8 @@ impl<'a> Resolver<'a> {
             interpreter,
             current_function: FunctionType::None,
             current_class: ClassType::None,
+            loop_depth: 0,
+            try_depth: 0,
         }
     }
 
@@ -84,12 +103,17 @@ impl<'a> Resolver<'a> {
         let scope = self.scopes.last_mut().unwrap();
 
         if !at_global && scope.exists(name) {
-            return Err(Error::new(format!(
-                "Error at '{}': Already a variable with this name in this scope.",
-                name
-            )));
+            return Err(Error::new(
+                ErrorKind::Resolve,
+                format!(
+                    "Error at '{}': Already a variable with this name in this scope.",
+                    name
+                ),
+            ));
         }
         scope.define(name, false);
+        // Globals may be redeclared, and a redeclaration is mutable again.
+        scope.constants.remove(name);
         Ok(None)
     }
 
@@ -110,21 +134,6 @@ impl<'a> Resolver<'a> {
         Err(Error::new(format!("Undefined variable '{:}'.", name)))
     }*/
 
-    pub fn get_non_global(&self, name: &str) -> Result<Option<bool>, Error> {
-        let mut envs = self.scopes.iter().rev().peekable();
-        while let Some(env) = envs.next() {
-            if envs.peek().is_some() {
-                let symbol = env.retrieve(name);
-                if symbol.is_some() {
-                    return Ok(symbol);
-                }
-            } else {
-                break;
-            }
-        }
-        Ok(None)
-    }
-
     pub fn contains_key(&self, name: &str) -> bool {
         for env in self.scopes.iter().rev() {
             let symbol = env.exists(name);
@@ -135,15 +144,41 @@ impl<'a> Resolver<'a> {
         false
     }
 
+    /// Whether `name` refers to a `const` in the innermost scope declaring it.
+    fn is_constant(&self, name: &str) -> bool {
+        self.scopes
+            .iter()
+            .rev()
+            .find(|scope| scope.exists(name))
+            .is_some_and(|scope| scope.constants.contains(name))
+    }
+
     pub fn resolve_local(&mut self, expr: &Expr, name: &str) {
         for i in (0..self.scopes.len()).rev() {
             if let Some(_) = self.scopes[i].symbol_table.get(name) {
                 self.interpreter.resolve(expr, self.scopes.len() - 1 - i);
+                // Globals also hold the natives, which the resolver never
+                // sees, so they stay looked up by name.
+                if i > 0 {
+                    self.interpreter.resolve_slot(expr, self.scopes[i].slots[name]);
+                }
                 return;
             }
         }
     }
 
+    /// Declares every function of a block up front so functions declared
+    /// later in the block can be referenced by earlier ones.
+    fn hoist_functions(&mut self, stmts: &[Stmt]) -> Result<(), Error> {
+        for stmt in stmts {
+            if let Stmt::FunDecl(FunDecl { name, .. }) = stmt {
+                self.declare(name)?;
+                self.define(name);
+            }
+        }
+        Ok(())
+    }
+
     pub fn resolve_function(
         &mut self,
         stmt: &Stmt,
@@ -157,6 +192,10 @@ impl<'a> Resolver<'a> {
         {
             let enclosing_function = self.current_function.clone();
             self.current_function = ftype;
+            let enclosing_loop_depth = self.loop_depth;
+            self.loop_depth = 0;
+            let enclosing_try_depth = self.try_depth;
+            self.try_depth = 0;
             self.begin_scope();
 
             for parameter in parameters {
@@ -164,14 +203,20 @@ impl<'a> Resolver<'a> {
                 self.define(&parameter.lexeme);
             }
 
+            self.hoist_functions(body)?;
             for stmt in body {
                 stmt.accept(self)?;
             }
             self.end_scope();
             self.current_function = enclosing_function;
+            self.loop_depth = enclosing_loop_depth;
+            self.try_depth = enclosing_try_depth;
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 }
@@ -184,7 +229,10 @@ impl<'a> IVisitorStmt<Result<Option<Stmt>, Error>> for Resolver<'a> {
                 Err(e) => Err(e),
             }
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
@@ -194,32 +242,69 @@ impl<'a> IVisitorStmt<Result<Option<Stmt>, Error>> for Resolver<'a> {
                 Ok(_) => Ok(None),
                 Err(e) => Err(e),
             },
-            _ => Err(Error::new("Invalid statement".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
         }
     }
 
     fn visit_var_decl(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
         match stmt {
-            Stmt::VarDecl(VarDecl { name, expr }) => {
+            Stmt::VarDecl(VarDecl {
+                name,
+                expr,
+                constant,
+            }) => {
                 self.declare(name.as_str())?;
                 expr.accept(self)?;
                 self.define(name.as_str());
+                if *constant {
+                    if let Some(scope) = self.scopes.last_mut() {
+                        scope.constants.insert(name.clone());
+                    }
+                }
                 Ok(None)
             }
-            _ => Err(Error::new("Invalid statement".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
+        }
+    }
+
+    fn visit_destructure(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        if let Stmt::Destructure(Destructure { names, expr }) = stmt {
+            for name in names {
+                self.declare(name.as_str())?;
+            }
+            expr.accept(self)?;
+            for name in names {
+                self.define(name.as_str());
+            }
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
     fn visit_block(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
         if let Stmt::Block(Block { stmts }) = stmt {
             self.begin_scope();
+            self.hoist_functions(stmts)?;
             for stmt in stmts {
                 stmt.accept(self)?;
             }
             self.end_scope();
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
@@ -235,14 +320,34 @@ impl<'a> IVisitorStmt<Result<Option<Stmt>, Error>> for Resolver<'a> {
                 branch_false.accept(self)?;
                 Ok(None)
             }
-            _ => Err(Error::new("Invalid statement".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
         }
     }
 
     fn visit_while(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
-        if let Stmt::While(While { condition, body }) = stmt {
-            condition.accept(self).unwrap();
-            body.accept(self).unwrap();
+        if let Stmt::While(While {
+            condition,
+            body,
+            increment,
+            else_branch,
+        }) = stmt
+        {
+            condition.accept(self)?;
+            let enclosing_loop_depth = self.loop_depth;
+            self.loop_depth += 1;
+            let res = body.accept(self);
+            self.loop_depth = enclosing_loop_depth;
+            res?;
+            for increment in increment {
+                increment.accept(self)?;
+            }
+            // The loop is over by then, so `break` there leaves an outer loop.
+            if let Some(else_branch) = else_branch {
+                else_branch.accept(self)?;
+            }
         }
         Ok(None)
     }
@@ -254,13 +359,20 @@ impl<'a> IVisitorStmt<Result<Option<Stmt>, Error>> for Resolver<'a> {
                 parameters: _,
                 body: _,
             }) => {
-                self.declare(name)?;
-                self.define(name);
+                let hoisted = self.scopes.len() > 1
+                    && self.scopes.last().unwrap().retrieve(name) == Some(true);
+                if !hoisted {
+                    self.declare(name)?;
+                    self.define(name);
+                }
 
                 self.resolve_function(stmt, FunctionType::Function)?;
                 Ok(None)
             }
-            _ => Err(Error::new("Invalid statement".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
         }
     }
 
@@ -268,23 +380,77 @@ impl<'a> IVisitorStmt<Result<Option<Stmt>, Error>> for Resolver<'a> {
         match stmt {
             Stmt::Return(Return { keyword, value }) => {
                 if self.current_function == FunctionType::None {
-                    return Err(Error::new(format!(
-                        "Error at '{}': Can't return from top-level code.",
-                        keyword.lexeme
-                    )));
+                    return Err(Error::new(
+                        ErrorKind::Resolve,
+                        format!(
+                            "Error at '{}': Can't return from top-level code.",
+                            keyword.lexeme
+                        ),
+                    ));
                 }
                 if *value != Expr::Literal(Literal::Nil) {
                     if self.current_function == FunctionType::Initializer {
-                        return Err(Error::new(format!(
-                            "Error at '{:}': Can't return a value from an initializer.",
-                            keyword.lexeme
-                        )));
+                        return Err(Error::new(
+                            ErrorKind::Resolve,
+                            format!(
+                                "Error at '{:}': Can't return a value from an initializer.",
+                                keyword.lexeme
+                            ),
+                        ));
+                    }
+                    if let Expr::Call(Call { tail, .. }) = value {
+                        tail.set(self.try_depth == 0);
                     }
                     value.accept(self)?;
                 }
                 Ok(None)
             }
-            _ => Err(Error::new("Invalid statement".to_string())),
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
+        }
+    }
+
+    fn visit_break(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        match stmt {
+            Stmt::Break(Break { keyword }) => {
+                if self.loop_depth == 0 {
+                    return Err(Error::new(
+                        ErrorKind::Resolve,
+                        format!(
+                            "Error at '{}': Can't break outside of a loop.",
+                            keyword.lexeme
+                        ),
+                    ));
+                }
+                Ok(None)
+            }
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
+        }
+    }
+
+    fn visit_continue(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        match stmt {
+            Stmt::Continue(Continue { keyword }) => {
+                if self.loop_depth == 0 {
+                    return Err(Error::new(
+                        ErrorKind::Resolve,
+                        format!(
+                            "Error at '{}': Can't continue outside of a loop.",
+                            keyword.lexeme
+                        ),
+                    ));
+                }
+                Ok(None)
+            }
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
         }
     }
 
@@ -305,10 +471,13 @@ impl<'a> IVisitorStmt<Result<Option<Stmt>, Error>> for Resolver<'a> {
                     let token =
                         extract_enum_value!(*boxed_expr.clone(), Expr::Var(Var::Token(c)) => c);
                     if token.lexeme == name.lexeme {
-                        return Err(Error::new(format!(
-                            "Error at '{:}': A class can't inherit from itself.",
-                            name.lexeme
-                        )));
+                        return Err(Error::new(
+                            ErrorKind::Resolve,
+                            format!(
+                                "Error at '{:}': A class can't inherit from itself.",
+                                name.lexeme
+                            ),
+                        ));
                     }
                     self.current_class = ClassType::SubClass;
                     superclass.as_ref().unwrap().accept(self).unwrap();
@@ -339,22 +508,100 @@ impl<'a> IVisitorStmt<Result<Option<Stmt>, Error>> for Resolver<'a> {
             self.current_class = enclosing_class;
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    fn visit_enum(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        if let Stmt::EnumDecl(EnumDecl { name, .. }) = stmt {
+            self.declare(&name.lexeme)?;
+            self.define(&name.lexeme);
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    fn visit_try(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        if let Stmt::Try(Try {
+            body,
+            name,
+            handler,
+        }) = stmt
+        {
+            self.begin_scope();
+            self.try_depth += 1;
+            self.hoist_functions(body)?;
+            for stmt in body {
+                stmt.accept(self)?;
+            }
+            self.try_depth -= 1;
+            self.end_scope();
+
+            self.begin_scope();
+            self.declare(&name.lexeme)?;
+            self.define(&name.lexeme);
+            self.hoist_functions(handler)?;
+            for stmt in handler {
+                stmt.accept(self)?;
+            }
+            self.end_scope();
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    fn visit_assert(&mut self, stmt: &Stmt) -> Result<Option<Stmt>, Error> {
+        match stmt {
+            Stmt::Assert(Assert {
+                keyword: _,
+                condition,
+                message,
+            }) => {
+                condition.accept(self)?;
+                if let Some(message) = message {
+                    message.accept(self)?;
+                }
+                Ok(None)
+            }
+            _ => Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            )),
         }
     }
+
+    fn visit_invalid(&mut self, _: &Stmt) -> Result<Option<Stmt>, Error> {
+        Ok(None)
+    }
 }
 
 impl<'a> IVisitorExpr<Result<Option<Expr>, Error>> for Resolver<'a> {
     fn visit_var(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Var(Var::Token(token)) = expr {
-            if self.scopes.len() != 0
-                && self.contains_key(&token.lexeme)
-                && (self.get_non_global(&token.lexeme)?.ok_or(true) == Ok(false))
+            // Only the innermost scope can hold a variable still being
+            // initialized; an enclosing one means a function body refers
+            // back to it, which is fine by the time the body runs.
+            if self.scopes.len() > 1
+                && self.scopes.last().unwrap().retrieve(&token.lexeme) == Some(false)
             {
-                return Err(Error::new(format!(
-                    "Error at '{}': Can't read local variable in its own initializer.",
-                    &token.lexeme
-                )));
+                return Err(Error::new(
+                    ErrorKind::Resolve,
+                    format!(
+                        "Error at '{}': Can't read local variable in its own initializer.",
+                        &token.lexeme
+                    ),
+                ));
             } else {
                 self.resolve_local(&mut expr.clone(), &token.lexeme);
                 Ok(None)
@@ -374,39 +621,87 @@ impl<'a> IVisitorExpr<Result<Option<Expr>, Error>> for Resolver<'a> {
             right,
         }) = expr
         {
-            right.accept(self).unwrap();
+            right.accept(self)?;
             Ok(None)
         } else {
-            Err(Error::new("Invalid expression".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
     fn visit_binary(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
-        if let Expr::Binary(Binary {
-            left,
-            operator: _,
-            right,
-        }) = expr
-        {
-            left.accept(self).unwrap();
-            right.accept(self).unwrap();
-            Ok(None)
-        } else {
-            Err(Error::new("Invalid expression".to_string()))
+        let mut rights = vec![];
+        let mut leftmost = expr;
+        while let Expr::Binary(Binary { left, right, .. }) = leftmost {
+            rights.push(right);
+            leftmost = left;
+        }
+        if rights.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ));
         }
+
+        leftmost.accept(self)?;
+        for right in rights.into_iter().rev() {
+            right.accept(self)?;
+        }
+        Ok(None)
     }
 
     fn visit_grouping(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Grouping(Grouping { group }) = expr {
             group.accept(self)
         } else {
-            Err(Error::new("Invalid expression".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
+    fn visit_list_literal(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::ListLiteral(ListLiteral { elements }) = expr {
+            for element in elements {
+                element.accept(self)?;
+            }
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ))
+        }
+    }
+
+    fn visit_lambda(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::Lambda(Lambda { declaration, .. }) = expr {
+            self.resolve_function(declaration, FunctionType::Function)?;
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ))
+        }
+    }
+
+    fn visit_invalid_expr(&mut self, _: &Expr) -> Result<Option<Expr>, Error> {
+        Ok(None)
+    }
+
     fn visit_assign(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Assign(Assign { var, expr: value }) = expr {
             let Var::Token(token) = var;
+            if self.is_constant(&token.lexeme) {
+                return Err(Error::new(
+                    ErrorKind::Resolve,
+                    format!("Cannot assign to constant '{}'.", token.lexeme),
+                ));
+            }
             value.accept(self)?;
             self.resolve_local(expr, &token.lexeme);
             Ok(None)
@@ -416,39 +711,69 @@ impl<'a> IVisitorExpr<Result<Option<Expr>, Error>> for Resolver<'a> {
     }
 
     fn visit_logical(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
-        if let Expr::Logical(Logical {
-            left,
-            operator: _,
-            right,
+        let mut rights = vec![];
+        let mut leftmost = expr;
+        while let Expr::Logical(Logical { left, right, .. }) = leftmost {
+            rights.push(right);
+            leftmost = left;
+        }
+        if rights.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ));
+        }
+
+        leftmost.accept(self)?;
+        for right in rights.into_iter().rev() {
+            right.accept(self)?;
+        }
+        Ok(None)
+    }
+
+    fn visit_ternary(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::Ternary(Ternary {
+            condition,
+            then_branch,
+            else_branch,
         }) = expr
         {
-            left.accept(self).unwrap();
-            right.accept(self).unwrap();
+            condition.accept(self)?;
+            then_branch.accept(self)?;
+            else_branch.accept(self)?;
             Ok(None)
         } else {
-            Err(Error::new("Invalid expression".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
     fn visit_call(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Call(call) = expr {
             call.callee.accept(self)?;
-            let _: Vec<_> = call
-                .arguments
+            call.arguments
                 .iter()
-                .map(|arg| arg.accept(self)) // ?
-                .collect();
+                .map(|arg| arg.accept(self))
+                .collect::<Result<Vec<_>, _>>()?;
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
     fn visit_get(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
-        if let Expr::Get(Get { object, name: _ }) = expr {
+        if let Expr::Get(Get { object, .. }) = expr {
             object.accept(self)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
@@ -463,42 +788,250 @@ impl<'a> IVisitorExpr<Result<Option<Expr>, Error>> for Resolver<'a> {
             value.accept(self)?;
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+
+    fn visit_index(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::Index(Index { object, index, .. }) = expr {
+            object.accept(self)?;
+            index.accept(self)?;
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ))
+        }
+    }
+
+    fn visit_index_set(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
+        if let Expr::IndexSet(IndexSet {
+            object,
+            index,
+            value,
+            ..
+        }) = expr
+        {
+            object.accept(self)?;
+            index.accept(self)?;
+            value.accept(self)?;
+            Ok(None)
+        } else {
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid expression".to_string(),
+            ))
         }
     }
 
     fn visit_this(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::This(This { keyword }) = expr {
             if self.current_class == ClassType::None {
-                return Err(Error::new(format!(
-                    "Error at '{}': Can't use 'this' outside of a class.",
-                    keyword.lexeme
-                )));
+                return Err(Error::new(
+                    ErrorKind::Resolve,
+                    format!(
+                        "Error at '{}': Can't use 'this' outside of a class.",
+                        keyword.lexeme
+                    ),
+                ));
             }
             self.resolve_local(expr, keyword.lexeme.as_str());
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
         }
     }
 
     fn visit_super(&mut self, expr: &Expr) -> Result<Option<Expr>, Error> {
         if let Expr::Super(Super { keyword, method: _ }) = expr {
             if self.current_class == ClassType::None {
-                return Err(Error::new(format!(
-                    "Error at '{}': Can't use 'super' outside of a class.",
-                    keyword.lexeme
-                )));
+                return Err(Error::new(
+                    ErrorKind::Resolve,
+                    format!(
+                        "Error at '{}': Can't use 'super' outside of a class.",
+                        keyword.lexeme
+                    ),
+                ));
             } else if self.current_class != ClassType::SubClass {
-                return Err(Error::new(format!(
-                    "Error at '{}': Can't use 'super' in a class with no superclass.",
-                    keyword.lexeme
-                )));
+                return Err(Error::new(
+                    ErrorKind::Resolve,
+                    format!(
+                        "Error at '{}': Can't use 'super' in a class with no superclass.",
+                        keyword.lexeme
+                    ),
+                ));
             }
             self.resolve_local(expr, keyword.lexeme.as_str());
             Ok(None)
         } else {
-            Err(Error::new("Invalid statement".to_string()))
+            Err(Error::new(
+                ErrorKind::Resolve,
+                "Invalid statement".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::ast::{Binary, Call, Error, Expr, FunDecl, Return, Stmt, Try};
+    use crate::interpreter::lexer::Lexer;
+    use crate::interpreter::parser::Parser;
+    use crate::interpreter::visitors::interpreter::Interpreter;
+
+    use super::Resolver;
+
+    fn resolve(source: &str) -> Result<Vec<Stmt>, Error> {
+        let mut lexer = Lexer::new(source);
+        lexer.scan_tokens()?;
+        let stmts = Parser::new(lexer.tokens)
+            .parse()
+            .into_iter()
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut interpreter = Interpreter::new();
+        let mut resolver = Resolver::new(&mut interpreter);
+        for stmt in &stmts {
+            stmt.accept(&mut resolver)?;
+        }
+        Ok(stmts)
+    }
+
+    /// The call expression returned by the first statement of function `f`.
+    fn returned_call(stmts: &[Stmt]) -> Option<&Call> {
+        match &stmts[0] {
+            Stmt::FunDecl(FunDecl { body, .. }) => match &body[0] {
+                Stmt::Return(Return {
+                    value: Expr::Call(call),
+                    ..
+                }) => Some(call),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn local_self_reference_in_initializer() {
+        let message = "Error at 'a': Can't read local variable in its own initializer.";
+        assert_eq!(resolve("{ var a = a; }").unwrap_err().msg, message);
+        assert_eq!(resolve("var a = 1; { var a = a; }").unwrap_err().msg, message);
+        assert_eq!(resolve("fun f() { var a = a; }").unwrap_err().msg, message);
+        assert_eq!(resolve("{ var a = 1; { var a = a + 1; } }").unwrap_err().msg, message);
+        assert_eq!(resolve("{ var a = -a; }").unwrap_err().msg, message);
+        assert_eq!(resolve("{ var a = true and a; }").unwrap_err().msg, message);
+    }
+
+    #[test]
+    fn function_body_can_refer_to_the_variable_being_initialized() {
+        assert!(resolve("{ var g = fun(n) { return g(n - 1); }; }").is_ok());
+        assert!(resolve("fun f() { var g = fun() { return g; }; }").is_ok());
+        assert!(resolve("{ var g = fun() { var h = h; }; }").is_err());
+    }
+
+    #[test]
+    fn global_self_reference_in_initializer() {
+        assert!(resolve("var a = a;").is_ok());
+        assert!(resolve("var a = 1; var a = a + 1;").is_ok());
+        assert!(resolve("var a = 1; { var b = a; }").is_ok());
+        assert!(resolve("{ var a = 1; fun f() { var b = a; } }").is_ok());
+    }
+
+    #[test]
+    fn super_outside_class() {
+        let error = resolve("super.foo();").unwrap_err();
+        assert_eq!(error.msg, "Error at 'super': Can't use 'super' outside of a class.");
+
+        let error = resolve("fun f() { super.foo(); }").unwrap_err();
+        assert_eq!(error.msg, "Error at 'super': Can't use 'super' outside of a class.");
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let error = resolve("break;").unwrap_err();
+        assert_eq!(error.msg, "Error at 'break': Can't break outside of a loop.");
+
+        let error = resolve("while (true) { fun f() { break; } }").unwrap_err();
+        assert_eq!(error.msg, "Error at 'break': Can't break outside of a loop.");
+
+        assert!(resolve("while (true) { if (true) { break; } }").is_ok());
+    }
+
+    #[test]
+    fn continue_outside_loop() {
+        let error = resolve("continue;").unwrap_err();
+        assert_eq!(
+            error.msg,
+            "Error at 'continue': Can't continue outside of a loop."
+        );
+
+        let error = resolve("for (;;) { fun f() { continue; } }").unwrap_err();
+        assert_eq!(
+            error.msg,
+            "Error at 'continue': Can't continue outside of a loop."
+        );
+
+        assert!(resolve("for (var i = 0; i < 1; i = i + 1) { continue; }").is_ok());
+    }
+
+    #[test]
+    fn super_without_superclass() {
+        let error = resolve("class Base { foo() { super.foo(); } }").unwrap_err();
+        assert_eq!(
+            error.msg,
+            "Error at 'super': Can't use 'super' in a class with no superclass."
+        );
+    }
+
+    #[test]
+    fn return_of_call_is_tail_position() {
+        let stmts = resolve("fun f(x) { return f(x); }").unwrap();
+        assert!(returned_call(&stmts).unwrap().tail.get());
+    }
+
+    #[test]
+    fn call_inside_returned_expression_is_not_tail_position() {
+        let stmts = resolve("fun f(x) { return f(x) + 1; }").unwrap();
+        assert!(returned_call(&stmts).is_none());
+        match &stmts[0] {
+            Stmt::FunDecl(FunDecl { body, .. }) => match &body[0] {
+                Stmt::Return(Return {
+                    value: Expr::Binary(Binary { left, .. }),
+                    ..
+                }) => assert!(matches!(left.as_ref(), Expr::Call(call) if !call.tail.get())),
+                _ => panic!("Expected a returned binary expression"),
+            },
+            _ => panic!("Expected a function"),
+        }
+    }
+
+    #[test]
+    fn return_inside_try_is_not_tail_position() {
+        let stmts = resolve("fun f(x) { try { return f(x); } catch (e) { return f(e); } }").unwrap();
+        match &stmts[0] {
+            Stmt::FunDecl(FunDecl { body, .. }) => match &body[0] {
+                Stmt::Try(Try { body, handler, .. }) => {
+                    let tails: Vec<bool> = [body, handler]
+                        .iter()
+                        .map(|block| match &block[0] {
+                            Stmt::Return(Return {
+                                value: Expr::Call(call),
+                                ..
+                            }) => call.tail.get(),
+                            _ => panic!("Expected a returned call"),
+                        })
+                        .collect();
+                    assert_eq!(tails, [false, true]);
+                }
+                _ => panic!("Expected a try statement"),
+            },
+            _ => panic!("Expected a function"),
         }
     }
 }
@@ -7,18 +7,27 @@ extern crate num_derive;
 extern crate num_traits;
 
 use compiler::vm::{VM, InterpretResult};
-use interpreter::ast::{Error, Expr, IVisitorExpr, IVisitorStmt, Stmt};
+use interpreter::ast::{
+    ClassDecl, Destructure, EnumDecl, Error, Expr, FunDecl, IVisitorExpr, IVisitorStmt, Stmt,
+    VarDecl,
+};
 use interpreter::lexer::Lexer;
 use interpreter::parser::Parser;
-use interpreter::visitors::{interpreter::Interpreter, resolver::Resolver};
-use std::{env, fs};
+use interpreter::visitors::{interpreter::Interpreter, printer::AstPrinter, resolver::Resolver};
+use std::io::{self, Read};
+use std::{env, fs, process};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     match args.len() {
         2 => run_file(&args[1]),
-        3 => run_test(&args[1]),
-        _ => panic!("Usage: loxc [script]"),
+        3 => match args[2].as_str() {
+            "--tree" => run_tree(&args[1]),
+            "--outline" => run_outline(&args[1]),
+            "--ast" => run_ast(&args[1]),
+            _ => run_test(&args[1]),
+        },
+        _ => panic!("Usage: loxc [script | -] [--test | --tree | --outline | --ast]"),
     }
 }
 
@@ -30,9 +39,82 @@ fn run_file(path: &String) {
     run_test(path);
 }
 
+/// Reads a program from `path`, or from stdin when the path is `-`.
+fn read_source(path: &str) -> String {
+    if path == "-" {
+        let mut source = String::new();
+        io::stdin()
+            .read_to_string(&mut source)
+            .expect("Error reading stdin");
+        source
+    } else {
+        fs::read_to_string(path).expect("Error reading file")
+    }
+}
+
+/// Runs a program with the tree-walking interpreter instead of the VM.
+fn run_tree(path: &str) {
+    let source = read_source(path);
+    if run(&source) {
+        process::exit(65);
+    }
+}
+
+/// Lists the top-level declarations of a program the way an editor's
+/// outline would, even when parts of it don't parse.
+fn run_outline(path: &str) {
+    let source = read_source(path);
+    let mut lexer = Lexer::new(&source);
+    if let Err(error) = lexer.scan_tokens() {
+        println!("{}", error);
+        process::exit(65);
+    }
+    let (program, errors) = Parser::new(lexer.tokens).parse_partial();
+    for stmt in &program {
+        match stmt {
+            Stmt::VarDecl(VarDecl { name, constant, .. }) => {
+                println!("{} {}", if *constant { "const" } else { "var" }, name)
+            }
+            Stmt::Destructure(Destructure { names, .. }) => println!("var {}", names.join(", ")),
+            Stmt::FunDecl(FunDecl { name, .. }) => println!("fun {}", name),
+            Stmt::ClassDecl(ClassDecl { name, .. }) => println!("class {}", name.lexeme),
+            Stmt::EnumDecl(EnumDecl { name, .. }) => println!("enum {}", name.lexeme),
+            Stmt::Invalid(_) => println!("<invalid>"),
+            _ => {}
+        }
+    }
+    for error in &errors {
+        println!("{}", error);
+    }
+    if !errors.is_empty() {
+        process::exit(65);
+    }
+}
+
+/// Prints each statement as an S-expression, showing how it was parsed.
+fn run_ast(path: &str) {
+    let source = read_source(path);
+    let mut lexer = Lexer::new(&source);
+    if let Err(error) = lexer.scan_tokens() {
+        println!("{}", error);
+        process::exit(65);
+    }
+    let (program, errors) = Parser::new(lexer.tokens).parse_partial();
+    let mut printer = AstPrinter;
+    for stmt in &program {
+        println!("{}", printer.print_stmt(stmt));
+    }
+    for error in &errors {
+        println!("{}", error);
+    }
+    if !errors.is_empty() {
+        process::exit(65);
+    }
+}
+
 fn run_test(path: &String) {
     println!("Running test: {}", path);
-    let source = fs::read_to_string(path).expect("Error reading file");
+    let source = read_source(path);
 
     let mut vm = VM::init_vm();
     println!("Source: {}", source);
@@ -61,8 +143,8 @@ where
     for stmt in ast {
         let value = stmt.as_ref().unwrap().accept(visitor);
         match value {
-            Err(Error { msg }) => {
-                println!("{:?}", msg);
+            Err(error) => {
+                println!("{}", error);
                 clean = false;
             }
             Ok(Some(v)) => println!("{:?}", v),
@@ -78,8 +160,8 @@ fn run(source: &String) -> bool {
     match res {
         Ok(_) => {}
         Err(e) => {
-            println!("{:?}", e.msg);
-            return false;
+            println!("{}", e);
+            return true;
         }
     }
     let mut parser: Parser = Parser::new(lexer.tokens);
@@ -91,9 +173,9 @@ fn run(source: &String) -> bool {
     if only_ok.count() != ast.len() {
         let only_err = ast.iter().filter(|result| result.is_err());
         for err in only_err {
-            println!("{:?}", err.as_ref().unwrap_err().msg);
-            return true;
+            println!("{}", err.as_ref().unwrap_err());
         }
+        return true;
     }
     let mut resolver: Resolver = Resolver::new(&mut interpreter);
     let res = apply_visitor(&mut resolver, &ast);
@@ -102,10 +184,7 @@ fn run(source: &String) -> bool {
         return true;
     }
 
-    apply_visitor(&mut interpreter, &ast);
-    let _ = ast.iter().filter(|result| result.is_err());
-
-    false
+    !apply_visitor(&mut interpreter, &ast)
 }
 
 #[cfg(test)]
@@ -1,3 +1,13 @@
+use crate::compiler::{
+    value::Value,
+    vm::{InterpretResult, VM},
+};
+use crate::interpreter::{
+    ast::{Expr, Literal},
+    lexer::Lexer,
+    parser::Parser,
+    visitors::{interpreter::Interpreter, resolver::Resolver},
+};
 use crate::tools::TestReader;
 
 const TESTS_FOLDER: &str = "./tests";
@@ -261,3 +271,117 @@ fn test_while() {
         assert_eq!(expected, result)
     }
 }
+
+fn tree_walker_global(source: &str, name: &str) -> Expr {
+    let mut lexer = Lexer::new(source);
+    lexer.scan_tokens().unwrap();
+    let ast = Parser::new(lexer.tokens).parse();
+
+    let mut interpreter = Interpreter::new();
+    let mut resolver = Resolver::new(&mut interpreter);
+    for stmt in &ast {
+        stmt.as_ref().unwrap().accept(&mut resolver).unwrap();
+    }
+    for stmt in &ast {
+        stmt.as_ref().unwrap().accept(&mut interpreter).unwrap();
+    }
+    let value = interpreter.globals().borrow().retrieve(name);
+    value.unwrap()
+}
+
+fn vm_global(source: &str, name: &str) -> Expr {
+    let mut vm = VM::init_vm();
+    let res = vm.interpret(&source.to_string());
+    assert!(matches!(res, InterpretResult::Ok));
+    match vm.globals[name].clone() {
+        Value::Bool(b) => Expr::Literal(Literal::Bool(b)),
+        Value::Nil => Expr::Literal(Literal::Nil),
+        Value::Number(n) => Expr::Literal(Literal::Number(n)),
+        Value::String(s) => Expr::Literal(Literal::Str(s)),
+        v => panic!("No tree-walker equivalent for {:?}", v),
+    }
+}
+
+fn assert_backends_agree(source: &str, name: &str, expected: Expr) {
+    assert_eq!(tree_walker_global(source, name), expected, "tree-walker: {}", source);
+    assert_eq!(vm_global(source, name), expected, "vm: {}", source);
+}
+
+#[test]
+fn test_logical_operators_across_backends() {
+    use ordered_float::OrderedFloat;
+    let number = |n: f64| Expr::Literal(Literal::Number(OrderedFloat(n)));
+
+    assert_backends_agree("var r = nil and 1;", "r", Expr::Literal(Literal::Nil));
+    assert_backends_agree("var r = false and 1;", "r", Expr::Literal(Literal::Bool(false)));
+    assert_backends_agree("var r = 1 and 2;", "r", number(2.0));
+    assert_backends_agree("var r = 0 or 2;", "r", number(0.0));
+    assert_backends_agree("var r = false or 2;", "r", number(2.0));
+    assert_backends_agree("var r = nil or false or 3;", "r", number(3.0));
+    assert_backends_agree("var r = nil or 1 or 2;", "r", number(1.0));
+    assert_backends_agree("var r = 1 and 2 and nil;", "r", Expr::Literal(Literal::Nil));
+
+    // The right operand only runs when the left one doesn't decide the result.
+    assert_backends_agree("var x = 0; var r = true or (x = 1);", "x", number(0.0));
+    assert_backends_agree("var x = 0; var r = false and (x = 1);", "x", number(0.0));
+    assert_backends_agree("var x = 0; var r = false or (x = 1);", "x", number(1.0));
+    assert_backends_agree("var x = 0; var r = true and (x = 1);", "x", number(1.0));
+}
+
+#[test]
+fn test_string_escapes_across_backends() {
+    let expected = Expr::Literal(Literal::Str("a\tb\nc\\d".to_string()));
+    assert_backends_agree("var s = \"a\\tb\\nc\\\\d\";", "s", expected);
+}
+
+
+/// Corpus files the VM cannot pass yet: compile errors are reported in a
+/// different format, the tree-walker's mixed-type equality errors, number
+/// literal edge cases, `NaN` equal to itself (the VM follows IEEE) and
+/// classes. Categories with no VM suite below are
+/// skipped entirely. Drop entries as the VM catches up.
+const VM_SKIPPED: &[&str] = &[
+    "print/missing_argument.lox",
+    "number/decimal_point_at_eof.lox",
+    "number/leading_dot.lox",
+    "number/trailing_dot.lox",
+    "number/nan_equality.lox",
+    "bool/equality.lox",
+    "operator/equals_class.lox",
+    "operator/equals_method.lox",
+    "operator/not_class.lox",
+];
+
+/// Runs every file of `category` through the VM in-process, apart from the
+/// ones listed in `VM_SKIPPED`.
+fn run_vm_suite(category: &str) {
+    let tr = TestReader::new(&(TESTS_FOLDER.to_string() + "/" + category + "/*.lox"));
+    for key in tr.iter() {
+        if VM_SKIPPED.contains(&key.as_str()) {
+            continue;
+        }
+        println!("key: {}", key);
+        let (expected, result) = tr.run_vm_test(&(TESTS_FOLDER.to_string() + "/" + key));
+        assert_eq!(expected, result, "vm: {}", key)
+    }
+}
+
+#[test]
+fn test_vm_print() {
+    run_vm_suite("print");
+}
+
+#[test]
+fn test_vm_number() {
+    run_vm_suite("number");
+}
+
+#[test]
+fn test_vm_bool() {
+    run_vm_suite("bool");
+}
+
+#[test]
+fn test_vm_operator() {
+    run_vm_suite("operator");
+}
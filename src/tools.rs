@@ -6,6 +6,7 @@ use std::{
     process::{Command, Stdio},
 };
 
+use crate::compiler::vm::VM;
 use crate::interpreter::ast::{Expr, Literal};
 
 pub struct TestReader {
@@ -39,15 +40,35 @@ impl TestReader {
     }
 
     pub fn run_test(&self, test_path: &str) -> (Vec<String>, Vec<String>) {
-        let results = self.run_source(test_path);
-        let expected = self
-            .get_expected_result(&test_path)
+        let output = strip_banner(
+            self.run_source(test_path),
+            &self.get_test_source(test_path),
+        );
+        let expectations = self.get_expected_result(&test_path);
+        let expected = expectations
             .iter()
-            .map(|x| match x {
-                Ok(v) => format!("{:?}", v.clone()),
-                Err(e) => format!("{:?}", e.trim().clone()),
-            })
+            .map(|x| x.formatted())
             .collect::<Vec<String>>();
+        let results = align(&expectations, &output);
+        println!("expected: {:?}", expected);
+        println!("results: {:?}", results);
+
+        (expected, results)
+    }
+
+    /// Like `run_test`, but runs the source in-process on the bytecode VM,
+    /// matching what it printed followed by the runtime errors it reported.
+    pub fn run_vm_test(&self, test_path: &str) -> (Vec<String>, Vec<String>) {
+        let mut vm = VM::init_vm();
+        vm.interpret(&self.get_test_source(test_path));
+        let output = vm.output.into_iter().chain(vm.errors).collect::<Vec<String>>();
+
+        let expectations = self.get_expected_result(test_path);
+        let expected = expectations
+            .iter()
+            .map(|x| x.formatted())
+            .collect::<Vec<String>>();
+        let results = align(&expectations, &output);
         println!("expected: {:?}", expected);
         println!("results: {:?}", results);
 
@@ -57,48 +78,45 @@ impl TestReader {
     fn get_test_source(&self, test_path: &str) -> String {
         let path = test_path
             .split('/')
-            .into_iter()
             .skip(2)
             .collect::<Vec<&str>>()
             .join("/");
         self.test_source[path.as_str()].clone()
     }
 
-    fn get_expected_result(&self, test_path: &str) -> Vec<Result<Expr, String>> {
+    fn get_expected_result(&self, test_path: &str) -> Vec<Expectation> {
         let source = self.get_test_source(test_path);
 
         let mut comments = Vec::new();
-        let lines = source.lines();
-        for line in lines {
+        for (i, line) in source.lines().enumerate() {
             let comment = line.trim().split("//").nth(1);
 
             if let Some(comment) = comment {
-                if comment.trim().starts_with("expect:") {
-                    let splitted = comment.split(" ").collect::<Vec<&str>>();
-                    let expected = splitted.last().unwrap().to_string();
-                    if expected.chars().nth(0).unwrap().is_numeric() {
-                        comments.push(Ok(Expr::Literal(Literal::Number(
-                            expected.parse().unwrap(),
-                        ))));
-                    } else if expected.chars().nth(0).unwrap() == '-' {
-                        comments.push(Ok(Expr::Literal(Literal::Number(
-                            expected.parse().unwrap(),
-                        ))));
-                    } else if expected.as_str() == "true" {
-                        comments.push(Ok(Expr::Literal(Literal::Bool(true))));
-                    } else if expected.as_str() == "false" {
-                        comments.push(Ok(Expr::Literal(Literal::Bool(false))));
-                    } else if expected.as_str() == "nil" {
-                        comments.push(Ok(Expr::Literal(Literal::Nil)));
-                    } else {
-                        comments.push(Ok(Expr::Literal(Literal::Str(expected))));
-                    }
-                } else if comment.trim().starts_with("expect runtime error: ") {
-                    let splitted = comment.split(":").collect::<Vec<&str>>();
-                    let expected = splitted.last().unwrap().to_string();
-                    comments.push(Err(expected));
+                let line = i + 1;
+                let comment = comment.trim();
+                if let Some(expected) = comment.strip_prefix("expect:") {
+                    let expected = expected.trim().to_string();
+                    comments.push(Expectation {
+                        line,
+                        value: Ok(parse_expected_value(&expected)),
+                        text: expected,
+                        runtime_error: false,
+                    });
+                } else if let Some(expected) = comment.strip_prefix("expect runtime error:") {
+                    let expected = expected.trim().to_string();
+                    comments.push(Expectation {
+                        line,
+                        value: Err(expected.clone()),
+                        text: expected,
+                        runtime_error: true,
+                    });
                 } else {
-                    comments.push(Err(comment.trim().to_string()));
+                    comments.push(Expectation {
+                        line,
+                        value: Err(comment.to_string()),
+                        text: comment.to_string(),
+                        runtime_error: false,
+                    });
                 }
             }
         }
@@ -136,3 +154,223 @@ impl TestReader {
         self.test_source.keys().collect()
     }
 }
+
+/// One expectation comment from a test source and the line it sits on.
+struct Expectation {
+    line: usize,
+    text: String,
+    value: Result<Expr, String>,
+    runtime_error: bool,
+}
+
+impl Expectation {
+    /// The expectation as the tree-walker prints it, which is what tests compare against.
+    fn formatted(&self) -> String {
+        match &self.value {
+            Ok(v) => format!("{:?}", v),
+            Err(e) => format!("{:?}", e),
+        }
+    }
+
+    /// Whether an output line satisfies this expectation, accepting both the
+    /// tree-walker's debug format and the canonical text the VM prints.
+    /// Runtime errors reported with a `[line N]` prefix must come from the
+    /// line the expectation was written on.
+    fn matches(&self, output: &str) -> bool {
+        if output == self.formatted() || output == self.text {
+            return true;
+        }
+        if !self.runtime_error {
+            return false;
+        }
+
+        let message = output.trim_matches('"');
+        match message.strip_prefix("[line ") {
+            Some(rest) => match rest.split_once(']') {
+                Some((line, message)) => {
+                    line.parse() == Ok(self.line) && message.trim() == self.text
+                }
+                None => false,
+            },
+            None => message == self.text,
+        }
+    }
+}
+
+/// Drops what the CLI's test mode prints around the program's own output:
+/// the `Running test:` line, the echoed source and the closing status line.
+fn strip_banner(mut output: Vec<String>, source: &str) -> Vec<String> {
+    if output
+        .first()
+        .is_some_and(|line| line.starts_with("Running test:"))
+    {
+        let echoed = 1 + source.split('\n').count();
+        output.drain(..echoed.min(output.len()));
+    }
+    if let Some("Result OK" | "Compile error" | "Runtime error") =
+        output.last().map(String::as_str)
+    {
+        output.pop();
+    }
+    output
+}
+
+fn parse_expected_value(expected: &str) -> Expr {
+    let first = expected.chars().next();
+    if let (Some('0'..='9' | '-'), Ok(n)) = (first, expected.parse()) {
+        Expr::Literal(Literal::Number(n))
+    } else if expected == "true" {
+        Expr::Literal(Literal::Bool(true))
+    } else if expected == "false" {
+        Expr::Literal(Literal::Bool(false))
+    } else if expected == "nil" {
+        Expr::Literal(Literal::Nil)
+    } else {
+        Expr::Literal(Literal::Str(expected.to_string()))
+    }
+}
+
+/// Lines up expectations with program output in order. A string printed over
+/// several lines by the tree-walker can satisfy a run of consecutive
+/// expectations. Matched expectations are reported as their own formatted
+/// value; the first one that cannot be matched gets the next unconsumed output
+/// line instead so the assertion diff shows what actually came out. Output
+/// lines no expectation accounts for are reported where they occur, so stray
+/// output fails the comparison.
+fn align(expectations: &[Expectation], output: &[String]) -> Vec<String> {
+    let mut results = Vec::new();
+    let mut cursor = 0;
+    let mut i = 0;
+
+    while i < expectations.len() {
+        let expectation = &expectations[i];
+        let single = output[cursor..]
+            .iter()
+            .position(|line| expectation.matches(line))
+            .map(|pos| (pos, 1));
+        let found = single.or_else(|| multiline_match(&expectations[i..], &output[cursor..]));
+
+        match found {
+            Some((pos, consumed)) => {
+                results.extend_from_slice(&output[cursor..cursor + pos]);
+                for expectation in &expectations[i..i + consumed] {
+                    results.push(expectation.formatted());
+                }
+                cursor += pos + 1;
+                i += consumed;
+            }
+            None => {
+                results.push(output.get(cursor).cloned().unwrap_or_default());
+                cursor = (cursor + 1).min(output.len());
+                i += 1;
+            }
+        }
+    }
+    results.extend_from_slice(&output[cursor..]);
+
+    results
+}
+
+/// Finds an output line holding a string value that spans several consecutive
+/// expectations, returning its position and how many expectations it covers.
+fn multiline_match(expectations: &[Expectation], output: &[String]) -> Option<(usize, usize)> {
+    for consumed in 2..=expectations.len() {
+        let joined = expectations[..consumed]
+            .iter()
+            .map(|e| e.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let formatted = format!("{:?}", Expr::Literal(Literal::Str(joined)));
+        if let Some(pos) = output.iter().position(|line| *line == formatted) {
+            return Some((pos, consumed));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{align, strip_banner, TestReader};
+
+    const FIXTURE: &str = "./tests/test_reader/interleaved.lox";
+
+    fn aligned(output: &[&str]) -> (Vec<String>, Vec<String>) {
+        let tr = TestReader::new("./tests/test_reader/*.lox");
+        let expectations = tr.get_expected_result(FIXTURE);
+        let expected = expectations.iter().map(|x| x.formatted()).collect();
+        let output: Vec<String> = output.iter().map(|x| x.to_string()).collect();
+        (expected, align(&expectations, &output))
+    }
+
+    #[test]
+    fn aligns_tree_walker_output() {
+        let (expected, results) = aligned(&[
+            "Literal(Number(OrderedFloat(1.0)))",
+            "Literal(Str(\"two words\"))",
+            "Literal(Nil)",
+            "\"Only instances have properties.\"",
+        ]);
+        assert_eq!(expected, results);
+    }
+
+    #[test]
+    fn aligns_canonical_output_and_error_line() {
+        let (expected, results) = aligned(&[
+            "1",
+            "two words",
+            "nil",
+            "[line 6] Only instances have properties.",
+        ]);
+        assert_eq!(expected, results);
+    }
+
+    #[test]
+    fn unaccounted_output_is_reported() {
+        for output in [
+            &["1", "debug", "two words", "nil", "[line 6] Only instances have properties."][..],
+            &["1", "two words", "nil", "[line 6] Only instances have properties.", "extra"][..],
+        ] {
+            let (expected, results) = aligned(output);
+            assert_ne!(expected, results, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn test_mode_banner_is_stripped() {
+        let source = "print 1;\nprint 2;\n";
+        let output = [
+            "Running test: t.lox",
+            "Source: print 1;",
+            "print 2;",
+            "",
+            "1",
+            "2",
+            "Result OK",
+        ];
+        let output = output.iter().map(|x| x.to_string()).collect();
+        assert_eq!(strip_banner(output, source), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn runtime_error_on_wrong_line_does_not_match() {
+        let (expected, results) =
+            aligned(&["1", "two words", "nil", "[line 2] Only instances have properties."]);
+        assert_ne!(expected, results);
+    }
+
+    #[test]
+    fn missing_output_is_reported() {
+        let (expected, results) = aligned(&["1", "nil"]);
+        assert_eq!(expected[0], results[0]);
+        assert_ne!(expected, results);
+    }
+
+    #[test]
+    fn multiline_string_spans_expectations() {
+        let tr = TestReader::new("./tests/string/multiline.lox");
+        let expectations = tr.get_expected_result("./tests/string/multiline.lox");
+        let expected: Vec<String> = expectations.iter().map(|x| x.formatted()).collect();
+        let output = vec!["Literal(Str(\"1\\n2\\n3\"))".to_string()];
+        assert_eq!(expected, align(&expectations, &output));
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn runs_program_read_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lors"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"var a = 1; print a + 2;\nprint \"piped\";")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert!(lines.contains(&"3"), "{}", stdout);
+    assert!(lines.contains(&"piped"), "{}", stdout);
+    assert!(lines.contains(&"Result OK"), "{}", stdout);
+}
+
+#[test]
+fn tree_walker_reports_every_syntax_error_with_its_line() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lors"))
+        .args(["-", "--tree"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"print 1;\nvar a = 1\nprint a;\nprint (;\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(65));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "[line 3] Error at 'print': Expect ';' after variable declaration.",
+            "[line 4] Error at ';': Expect expression.",
+        ]
+    );
+}
+
+#[test]
+fn outline_lists_declarations_around_a_broken_one() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lors"))
+        .args(["-", "--outline"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"var a = 1;\nfun f( { }\nclass C { m() {} }\nprint a;\nconst K = 2;\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(65));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "var a",
+            "<invalid>",
+            "class C",
+            "const K",
+            "[line 2] Error at '{': Expect parameter name.",
+        ]
+    );
+}
+
+#[test]
+fn ast_prints_each_statement_as_an_s_expression() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lors"))
+        .args(["-", "--ast"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"var x = 1 + 2 * 3;\nprint -x;\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines, ["(var x (+ 1 (* 2 3)))", "(print (- x))"]);
+}